@@ -0,0 +1,171 @@
+//! A tiny language server for testing Kokona's LSP client. It keeps the
+//! text of open documents in sync the way a real server would and answers
+//! with canned results:
+//!
+//! - every `bad` in a document gets an error diagnostic,
+//! - completion offers `alpha` and `beta`,
+//! - hover answers with the server's copy of the whole document, so tests
+//!   can check that incremental sync arrived intact,
+//! - definition points at the start of the document,
+//! - rename replaces the word under the cursor.
+//!
+//! `cargo test` builds it along with the other examples.
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok()?;
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn write_message(message: Value) {
+    let body = message.to_string();
+    let mut stdout = io::stdout().lock();
+    write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    stdout.flush().unwrap();
+}
+
+/// Byte offset of an LSP position, counting UTF-16 code units.
+fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap() as usize;
+    let character = position["character"].as_u64().unwrap() as usize;
+    let line_start: usize = text.split_inclusive('\n').take(line).map(str::len).sum();
+    let mut units = 0;
+    for (idx, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + idx;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    let character: usize = before[line_start..].chars().map(char::len_utf16).sum();
+    json!({ "line": line, "character": character })
+}
+
+fn publish_diagnostics(uri: &str, text: &str) {
+    let diagnostics: Vec<Value> = text
+        .match_indices("bad")
+        .map(|(idx, word)| {
+            json!({
+                "range": { "start": position(text, idx), "end": position(text, idx + word.len()) },
+                "severity": 1,
+                "source": "fake",
+                "message": "bad word",
+            })
+        })
+        .collect();
+    write_message(json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    }));
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+fn main() {
+    let mut documents: HashMap<String, String> = HashMap::new();
+    let mut stdin = io::stdin().lock();
+    while let Some(message) = read_message(&mut stdin) {
+        let params = &message["params"];
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let result = match message["method"].as_str().unwrap_or_default() {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": { "openClose": true, "change": 2 },
+                    "completionProvider": { "triggerCharacters": ["."] },
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "renameProvider": true,
+                },
+            }),
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap().to_string();
+                publish_diagnostics(&uri, &text);
+                documents.insert(uri, text);
+                continue;
+            }
+            "textDocument/didChange" => {
+                let text = documents.get_mut(&uri).unwrap();
+                for change in params["contentChanges"].as_array().unwrap() {
+                    let new_text = change["text"].as_str().unwrap();
+                    match change.get("range") {
+                        Some(range) => {
+                            let start = offset(text, &range["start"]);
+                            let end = offset(text, &range["end"]);
+                            text.replace_range(start..end, new_text);
+                        }
+                        None => *text = new_text.to_string(),
+                    }
+                }
+                publish_diagnostics(&uri, text);
+                continue;
+            }
+            "textDocument/didClose" => {
+                documents.remove(&uri);
+                continue;
+            }
+            "textDocument/completion" => json!([
+                { "label": "alpha", "detail": "fn alpha()" },
+                { "label": "beta", "insertText": "beta()" },
+            ]),
+            "textDocument/hover" => json!({
+                "contents": { "kind": "plaintext", "value": documents[&uri] },
+            }),
+            "textDocument/definition" => json!({
+                "uri": uri,
+                "range": {
+                    "start": { "line": 0, "character": 0 },
+                    "end": { "line": 0, "character": 0 },
+                },
+            }),
+            "textDocument/rename" => {
+                let text = &documents[&uri];
+                let at = offset(text, &params["position"]);
+                let bytes = text.as_bytes();
+                let start = (0..at).rev().take_while(|&i| is_word_byte(bytes[i])).last();
+                let end = (at..bytes.len()).find(|&i| !is_word_byte(bytes[i]));
+                let start = start.unwrap_or(at);
+                let end = end.unwrap_or(bytes.len());
+                json!({ "changes": { uri.clone(): [{
+                    "range": { "start": position(text, start), "end": position(text, end) },
+                    "newText": params["newName"],
+                }] } })
+            }
+            "shutdown" => Value::Null,
+            "exit" => return,
+            _ => continue,
+        };
+        if let Some(id) = message.get("id") {
+            write_message(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Replaces the file at `path` with `contents` so that it is never left
+/// half written: the new contents go to a temp file next to it, which is
+/// synced to disk and then renamed over the original. The original's
+/// permissions and (where allowed) owner carry over. With `backup`, the old
+/// contents are kept as `path~`.
+pub fn write(path: &Path, contents: &[u8], backup: bool) -> io::Result<()> {
+    // Saving through a symlink updates the file it points to, not the link
+    let path = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_symlink() => fs::canonicalize(path)?,
+        _ => path.to_path_buf(),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let original = fs::metadata(&path).ok();
+
+    let temp = temp_path(&dir, &path);
+    let result = (|| {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        file.write_all(contents)?;
+        if let Some(meta) = &original {
+            file.set_permissions(meta.permissions())?;
+            copy_owner(&file, meta);
+        }
+        file.sync_all()?;
+        drop(file);
+
+        if backup && original.is_some() {
+            let mut backup_path = path.clone().into_os_string();
+            backup_path.push("~");
+            fs::copy(&path, backup_path)?;
+        }
+        fs::rename(&temp, &path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+        return result;
+    }
+
+    // Make the rename itself durable; not every platform can open folders
+    if let Ok(dir) = File::open(&dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// A name in `dir` that nothing else uses, hidden on Unix.
+fn temp_path(dir: &Path, path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    dir.join(format!(
+        ".{}.{}-{}.kokona-tmp",
+        name,
+        std::process::id(),
+        NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+#[cfg(unix)]
+fn copy_owner(file: &File, meta: &fs::Metadata) {
+    use std::os::unix::fs::{fchown, MetadataExt};
+    // Only root may give a file away, so this can fail for other users'
+    // files; the group alone may still go through
+    if fchown(file, Some(meta.uid()), Some(meta.gid())).is_err() {
+        let _ = fchown(file, None, Some(meta.gid()));
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_owner(_file: &File, _meta: &fs::Metadata) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh folder for one test, for the test to remove.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kokona-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn replaces_the_contents_and_leaves_nothing_behind() {
+        let dir = temp_dir("atomic-replace");
+        let path = dir.join("a.txt");
+        write(&path, b"first", false).unwrap();
+        write(&path, b"second", false).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(entries(&dir), ["a.txt"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_backup_holds_the_old_contents() {
+        let dir = temp_dir("atomic-backup");
+        let path = dir.join("a.txt");
+        // Nothing to back up the first time
+        write(&path, b"old", true).unwrap();
+        assert_eq!(entries(&dir), ["a.txt"]);
+        write(&path, b"new", true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read(dir.join("a.txt~")).unwrap(), b"old");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn permissions_are_kept() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = temp_dir("atomic-permissions");
+        let path = dir.join("run.sh");
+        fs::write(&path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+        write(&path, b"#!/bin/sh\necho hi\n", false).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_stay_links_to_the_saved_file() {
+        let dir = temp_dir("atomic-symlink");
+        let target = dir.join("target.txt");
+        let link = dir.join("link.txt");
+        fs::write(&target, "old").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        write(&link, b"new", false).unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_failed_write_removes_its_temp_file() {
+        let dir = temp_dir("atomic-failure");
+        // A file can't be renamed over a folder
+        let path = dir.join("folder");
+        fs::create_dir(&path).unwrap();
+        assert!(write(&path, b"contents", false).is_err());
+        assert!(path.is_dir());
+        assert_eq!(entries(&dir), ["folder"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+use crate::document::{Document, Tabs};
+use crate::history::Edit;
+use directories_next::ProjectDirs;
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often dirty documents are copied to the recovery folder.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+pub enum AutosaveMode {
+    #[default]
+    Off,
+    /// Save every modified file when the window loses focus.
+    OnFocusLoss,
+    /// Save a modified file once it has gone unedited for a while.
+    AfterDelay,
+}
+
+impl AutosaveMode {
+    pub const ALL: [Self; 3] = [Self::Off, Self::OnFocusLoss, Self::AfterDelay];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Off => "Off",
+            Self::OnFocusLoss => "When the window loses focus",
+            Self::AfterDelay => "After a delay",
+        }
+    }
+}
+
+/// Unsaved text left in the recovery folder by a session that didn't exit
+/// cleanly.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The file it belongs to, `None` for untitled buffers.
+    pub path: Option<PathBuf>,
+    pub text: String,
+    /// Seconds since the Unix epoch.
+    pub taken_at: u64,
+    #[serde(skip)]
+    file: PathBuf,
+}
+
+impl Snapshot {
+    pub fn title(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => crate::document::UNTITLED.to_string(),
+        }
+    }
+
+    /// Reopens the snapshot as a modified document: its file with the
+    /// recovered text, so saving puts the text back where it belongs.
+    pub fn into_document(self) -> Document {
+        let mut doc = match &self.path {
+            Some(path) => Document::open(path).unwrap_or_else(|_| {
+                let mut doc = Document::new_untitled();
+                doc.path = Some(path.clone());
+                doc
+            }),
+            None => Document::new_untitled(),
+        };
+        let current = doc.text.to_string();
+        if current != self.text {
+            doc.edit(vec![Edit {
+                at: 0,
+                removed: current,
+                inserted: self.text.clone(),
+            }]);
+        }
+        doc.modified = true;
+        self.discard();
+        doc
+    }
+
+    pub fn discard(&self) {
+        let _ = fs::remove_file(&self.file);
+    }
+}
+
+fn recovery_dir() -> Option<PathBuf> {
+    let proj_dirs = ProjectDirs::from("dev", "nijika", "kokona")?;
+    Some(proj_dirs.data_dir().join("recovery"))
+}
+
+/// Snapshot files are named after the process and document that wrote them.
+fn snapshot_name(doc_id: u64) -> String {
+    format!("{}-{}.json", std::process::id(), doc_id)
+}
+
+/// Snapshots from earlier sessions, newest first.
+pub fn find_snapshots() -> Vec<Snapshot> {
+    let Some(dir) = recovery_dir() else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let own = format!("{}-", std::process::id());
+    let mut snapshots: Vec<Snapshot> = entries
+        .filter_map(|entry| {
+            let file = entry.ok()?.path();
+            let name = file.file_name()?.to_str()?;
+            if name.starts_with(&own) || !name.ends_with(".json") {
+                return None;
+            }
+            let mut snapshot: Snapshot =
+                serde_json::from_str(&fs::read_to_string(&file).ok()?).ok()?;
+            snapshot.file = file;
+            Some(snapshot)
+        })
+        .collect();
+    snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.taken_at));
+    snapshots
+}
+
+/// Keeps a copy of every dirty document in the recovery folder, and removes
+/// it again once the document is saved or closed.
+#[derive(Default)]
+pub struct Snapshots {
+    last_run: Option<Instant>,
+    /// Revision each document's snapshot was taken at.
+    written: HashMap<u64, u64>,
+}
+
+impl Snapshots {
+    pub fn update(&mut self, ctx: &egui::Context, tabs: &Tabs) {
+        let Some(dir) = recovery_dir() else {
+            return;
+        };
+        // Stale snapshots go right away, so a clean exit leaves nothing behind
+        self.written.retain(|&id, _| {
+            let dirty = tabs
+                .index_of(id)
+                .is_some_and(|idx| tabs.documents[idx].modified);
+            if !dirty {
+                let _ = fs::remove_file(dir.join(snapshot_name(id)));
+            }
+            dirty
+        });
+
+        if self
+            .last_run
+            .is_some_and(|last| last.elapsed() < SNAPSHOT_INTERVAL)
+        {
+            return;
+        }
+        self.last_run = Some(Instant::now());
+        ctx.request_repaint_after(SNAPSHOT_INTERVAL);
+
+        for doc in tabs.documents.iter().filter(|doc| doc.modified) {
+            let revision = doc.text.revision();
+            if self.written.get(&doc.id) == Some(&revision) {
+                continue;
+            }
+            let snapshot = Snapshot {
+                path: doc.path.clone(),
+                text: doc.text.to_string(),
+                taken_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+                file: PathBuf::new(),
+            };
+            let result = fs::create_dir_all(&dir)
+                .map_err(|e| e.to_string())
+                .and_then(|()| serde_json::to_vec(&snapshot).map_err(|e| e.to_string()))
+                .and_then(|json| {
+                    crate::atomic::write(&dir.join(snapshot_name(doc.id)), &json, false)
+                        .map_err(|e| e.to_string())
+                });
+            match result {
+                Ok(()) => {
+                    self.written.insert(doc.id, revision);
+                }
+                Err(e) => {
+                    crate::views::report_error(format!("Failed to write recovery snapshot: {}", e))
+                }
+            }
+        }
+    }
+
+    /// Removes every snapshot this session wrote, for when it exits cleanly.
+    pub fn clear(&mut self) {
+        if let Some(dir) = recovery_dir() {
+            for id in self.written.keys() {
+                let _ = fs::remove_file(dir.join(snapshot_name(*id)));
+            }
+        }
+        self.written.clear();
+    }
+}
+
+/// Saves modified files on its own, as `AutosaveMode` says.
+#[derive(Default)]
+pub struct Autosave {
+    window_focused: bool,
+    /// Revision of each document and when it was first seen, to tell how
+    /// long it has gone unedited.
+    last_edit: HashMap<u64, (u64, Instant)>,
+}
+
+impl Autosave {
+    pub fn update(
+        &mut self,
+        ctx: &egui::Context,
+        tabs: &mut Tabs,
+        mode: AutosaveMode,
+        delay: Duration,
+    ) {
+        let focused = ctx.input(|i| i.focused);
+        let lost_focus = self.window_focused && !focused;
+        self.window_focused = focused;
+        self.last_edit.retain(|&id, _| tabs.index_of(id).is_some());
+
+        let mut saved_any = false;
+        for doc in &mut tabs.documents {
+            let revision = doc.text.revision();
+            let last_edit = self
+                .last_edit
+                .entry(doc.id)
+                .or_insert((revision, Instant::now()));
+            if last_edit.0 != revision {
+                *last_edit = (revision, Instant::now());
+            }
+            if !doc.modified || doc.is_untitled() {
+                continue;
+            }
+            let due = match mode {
+                AutosaveMode::Off => false,
+                AutosaveMode::OnFocusLoss => lost_focus,
+                AutosaveMode::AfterDelay => {
+                    let idle = last_edit.1.elapsed();
+                    if idle < delay {
+                        ctx.request_repaint_after(delay - idle);
+                    }
+                    idle >= delay
+                }
+            };
+            if due {
+                match doc.autosave() {
+                    Ok(()) => saved_any = true,
+                    Err(e) => {
+                        crate::views::report_error(format!(
+                            "Autosave of {} failed: {}",
+                            doc.filename(),
+                            e
+                        ));
+                        // Try again after another delay, not every frame
+                        last_edit.1 = Instant::now();
+                    }
+                }
+            }
+        }
+        if saved_any && tabs.active().is_some_and(|doc| !doc.modified) {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
+        }
+    }
+}
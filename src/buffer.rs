@@ -0,0 +1,271 @@
+use ropey::Rope;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Range;
+
+/// How many edits are remembered for consumers that fall behind.
+const MAX_DELTAS: usize = 128;
+
+/// A replaced byte span, in the coordinates of the text after the change.
+/// `start..start + new_len` is what is there now, `old_len` is how long
+/// the span was before.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delta {
+    pub start: usize,
+    pub old_len: usize,
+    pub new_len: usize,
+}
+
+impl Delta {
+    /// Folds `next`, made after `self`, into one span covering both.
+    fn then(self, next: Delta) -> Delta {
+        let start = self.start.min(next.start);
+        // End of the combined span in the coordinates between the two edits
+        let mid_end = (self.start + self.new_len).max(next.start + next.old_len);
+        let old_end = mid_end + self.old_len - self.new_len;
+        let new_end = mid_end + next.new_len - next.old_len;
+        Delta {
+            start,
+            old_len: old_end - start,
+            new_len: new_end - start,
+        }
+    }
+
+    /// Byte offset shift for anything after the span.
+    pub fn shift(&self, offset: usize) -> usize {
+        offset + self.new_len - self.old_len
+    }
+}
+
+/// What happened to the buffer since a consumer last looked at it.
+pub enum Change {
+    Unchanged,
+    Edited(Delta),
+    /// Too much happened (or the buffer was reloaded); start over.
+    Replaced,
+}
+
+/// Document text, kept in a rope so edits and lookups cost the same however
+/// long the file is. The editor only ever copies out the lines it shows.
+/// Only `\n` counts as a line break, the same as in egui's layout.
+pub struct Buffer {
+    rope: Rope,
+    revision: u64,
+    /// Edits by the revision they produced, oldest first.
+    deltas: VecDeque<(u64, Delta)>,
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Self::from(String::new())
+    }
+}
+
+impl From<String> for Buffer {
+    fn from(text: String) -> Self {
+        Self {
+            rope: Rope::from_str(&text),
+            revision: 0,
+            deltas: VecDeque::new(),
+        }
+    }
+}
+
+/// The whole text, for the rare callers that need it in one piece.
+impl fmt::Display for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.rope, f)
+    }
+}
+
+impl Buffer {
+    pub fn rope(&self) -> &Rope {
+        &self.rope
+    }
+
+    /// Bumped on every edit; cheap to compare instead of the text itself.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rope.len_bytes() == 0
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    pub fn len_lines(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    pub fn char_to_byte(&self, char_idx: usize) -> usize {
+        self.rope.char_to_byte(char_idx.min(self.rope.len_chars()))
+    }
+
+    pub fn byte_to_char(&self, byte_idx: usize) -> usize {
+        self.rope.byte_to_char(byte_idx.min(self.rope.len_bytes()))
+    }
+
+    pub fn byte_to_line(&self, byte_idx: usize) -> usize {
+        self.rope.byte_to_line(byte_idx.min(self.rope.len_bytes()))
+    }
+
+    pub fn line_to_byte(&self, line: usize) -> usize {
+        self.rope.line_to_byte(line.min(self.rope.len_lines()))
+    }
+
+    pub fn line_to_char(&self, line: usize) -> usize {
+        self.rope.line_to_char(line.min(self.rope.len_lines()))
+    }
+
+    /// Byte span of the lines touching `bytes`, line breaks included.
+    pub fn line_span(&self, bytes: Range<usize>) -> Range<usize> {
+        let first = self.byte_to_line(bytes.start);
+        let last = self.byte_to_line(bytes.end);
+        self.line_to_byte(first)..self.line_to_byte(last + 1)
+    }
+
+    /// Borrowed when the span sits in one chunk of the rope, as short spans
+    /// mostly do.
+    pub fn char_slice(&self, chars: Range<usize>) -> Cow<'_, str> {
+        let end = chars.end.min(self.rope.len_chars());
+        self.rope.slice(chars.start.min(end)..end).into()
+    }
+
+    pub fn byte_slice(&self, bytes: Range<usize>) -> Cow<'_, str> {
+        let end = bytes.end.min(self.rope.len_bytes());
+        self.rope.byte_slice(bytes.start.min(end)..end).into()
+    }
+
+    /// Replaces a char range, the only way the text changes.
+    pub fn replace(&mut self, chars: Range<usize>, text: &str) {
+        let end = chars.end.min(self.rope.len_chars());
+        let chars = chars.start.min(end)..end;
+        let bytes = self.char_to_byte(chars.start)..self.char_to_byte(chars.end);
+        if bytes.is_empty() && text.is_empty() {
+            return;
+        }
+        self.rope.remove(chars.clone());
+        self.rope.insert(chars.start, text);
+
+        self.revision += 1;
+        self.deltas.push_back((
+            self.revision,
+            Delta {
+                start: bytes.start,
+                old_len: bytes.len(),
+                new_len: text.len(),
+            },
+        ));
+        if self.deltas.len() > MAX_DELTAS {
+            self.deltas.pop_front();
+        }
+    }
+
+    /// Everything that changed after `revision`, merged into one span.
+    pub fn changes_since(&self, revision: u64) -> Change {
+        if revision == self.revision {
+            return Change::Unchanged;
+        }
+        let mut pending = self.deltas.iter().skip_while(|(rev, _)| *rev <= revision);
+        match pending.next() {
+            // Only usable if nothing between `revision` and the log was dropped
+            Some(&(rev, first)) if rev == revision + 1 => {
+                Change::Edited(pending.fold(first, |acc, &(_, delta)| acc.then(delta)))
+            }
+            _ => Change::Replaced,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The merged edit since `revision`, `None` if the consumer must start over.
+    fn edited(buffer: &Buffer, revision: u64) -> Option<Delta> {
+        match buffer.changes_since(revision) {
+            Change::Edited(delta) => Some(delta),
+            Change::Unchanged => panic!("nothing changed since {}", revision),
+            Change::Replaced => None,
+        }
+    }
+
+    fn delta(start: usize, old_len: usize, new_len: usize) -> Delta {
+        Delta {
+            start,
+            old_len,
+            new_len,
+        }
+    }
+
+    #[test]
+    fn typing_merges_into_one_insert() {
+        let mut buffer = Buffer::from("abc".to_string());
+        buffer.replace(3..3, "x");
+        buffer.replace(4..4, "y");
+        assert_eq!(buffer.to_string(), "abcxy");
+        assert_eq!(edited(&buffer, 0), Some(delta(3, 0, 2)));
+        assert_eq!(edited(&buffer, 1), Some(delta(4, 0, 1)));
+        assert!(matches!(buffer.changes_since(2), Change::Unchanged));
+    }
+
+    #[test]
+    fn deleting_merges_into_one_removal() {
+        let mut buffer = Buffer::from("abcdef".to_string());
+        // Backspace twice from the end
+        buffer.replace(5..6, "");
+        buffer.replace(4..5, "");
+        assert_eq!(edited(&buffer, 0), Some(delta(4, 2, 0)));
+
+        // Typing two chars and taking one back leaves one inserted
+        let mut buffer = Buffer::from("hello world".to_string());
+        buffer.replace(5..5, "XY");
+        buffer.replace(6..7, "");
+        assert_eq!(buffer.to_string(), "helloX world");
+        assert_eq!(edited(&buffer, 0), Some(delta(5, 0, 1)));
+    }
+
+    #[test]
+    fn far_apart_edits_merge_into_the_span_between() {
+        let mut buffer = Buffer::from("aaaa bbbb".to_string());
+        buffer.replace(0..0, "X");
+        buffer.replace(9..10, "");
+        assert_eq!(buffer.to_string(), "Xaaaa bbb");
+        assert_eq!(edited(&buffer, 0), Some(delta(0, 9, 9)));
+    }
+
+    #[test]
+    fn spans_are_in_bytes() {
+        let mut buffer = Buffer::from("añb".to_string());
+        buffer.replace(1..2, "日本");
+        assert_eq!(edited(&buffer, 0), Some(delta(1, 2, 6)));
+    }
+
+    #[test]
+    fn edits_at_the_end_are_clamped_to_it() {
+        let mut buffer = Buffer::from("ab\n".to_string());
+        buffer.replace(3..10, "c");
+        buffer.replace(10..12, "d");
+        assert_eq!(buffer.to_string(), "ab\ncd");
+        assert_eq!(edited(&buffer, 0), Some(delta(3, 0, 2)));
+        // Nothing to replace and nothing to insert isn't an edit
+        buffer.replace(20..30, "");
+        assert_eq!(buffer.revision(), 2);
+    }
+
+    #[test]
+    fn falling_behind_the_log_means_starting_over() {
+        let mut buffer = Buffer::default();
+        for i in 0..=MAX_DELTAS {
+            buffer.replace(i..i, "x");
+        }
+        assert_eq!(buffer.revision(), MAX_DELTAS as u64 + 1);
+        // The first edit was dropped, the rest still merge
+        assert_eq!(edited(&buffer, 0), None);
+        assert_eq!(edited(&buffer, 1), Some(delta(1, 0, MAX_DELTAS)));
+    }
+}
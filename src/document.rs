@@ -0,0 +1,461 @@
+use crate::buffer::Buffer;
+use crate::editorconfig::EditorConfig;
+use crate::encoding::TextEncoding;
+use crate::history::{Edit, History};
+use crate::indent::{self, IndentSettings};
+use crate::line_ending::{self, LineEnding};
+use crate::watcher::{DiskChange, DiskStamp};
+use eframe::egui;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub const UNTITLED: &str = "untitled.txt";
+
+static NEXT_DOCUMENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A single open file (or untitled buffer) shown as a tab in the editor.
+pub struct Document {
+    /// Stable id, used to salt egui widget ids so every tab keeps its own
+    /// cursor and scroll state.
+    pub id: u64,
+    pub text: Buffer,
+    pub path: Option<PathBuf>,
+    pub modified: bool,
+    /// Name of the syntect syntax, resolved lazily by the editor.
+    pub syntax: Option<String>,
+    pub scroll_offset: egui::Vec2,
+    /// Scroll position the editor jumps to the next time it is shown.
+    pub scroll_to: Option<egui::Vec2>,
+    pub cursor: Option<egui::text::CCursorRange>,
+    pub history: History,
+    /// What `.editorconfig` files say about this file.
+    pub editorconfig: EditorConfig,
+    /// Indentation the file used when it was opened, if it could be told.
+    pub detected_indent: Option<IndentSettings>,
+    /// How the file's bytes are decoded, and encoded again on save.
+    pub encoding: TextEncoding,
+    /// Line break written on save; the buffer itself only has `\n`.
+    pub line_ending: LineEnding,
+    /// The file had more than one kind of line ending when opened. Saving
+    /// writes them all as `line_ending`.
+    pub mixed_line_endings: bool,
+    /// The file as last read or written, to tell our own saves from
+    /// changes made by others.
+    pub disk_stamp: Option<DiskStamp>,
+    /// A change on disk waiting for the user to decide about it.
+    pub disk_change: Option<DiskChange>,
+}
+
+impl Document {
+    pub fn new_untitled() -> Self {
+        Self {
+            id: NEXT_DOCUMENT_ID.fetch_add(1, Ordering::Relaxed),
+            text: Buffer::default(),
+            path: None,
+            modified: false,
+            syntax: None,
+            scroll_offset: egui::Vec2::ZERO,
+            scroll_to: None,
+            cursor: None,
+            history: History::default(),
+            editorconfig: EditorConfig::default(),
+            detected_indent: None,
+            encoding: TextEncoding::default(),
+            line_ending: LineEnding::default(),
+            mixed_line_endings: false,
+            disk_stamp: None,
+            disk_change: None,
+        }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let disk_stamp = DiskStamp::of(path);
+        let editorconfig = EditorConfig::for_path(path);
+        let (text, encoding) =
+            TextEncoding::detect_with(&std::fs::read(path)?, editorconfig.encoding());
+        let (line_ending, mixed) = LineEnding::detect(&text);
+        let text = line_ending::normalize(&text).into_owned();
+        let mut doc = Self::new_untitled();
+        if crate::views::current_settings().persistent_undo {
+            doc.history = History::load(path, &text);
+        }
+        doc.editorconfig = editorconfig;
+        doc.encoding = encoding;
+        doc.line_ending = doc.editorconfig.end_of_line.unwrap_or(line_ending);
+        doc.mixed_line_endings = mixed;
+        doc.disk_stamp = disk_stamp;
+        doc.detected_indent = indent::detect(&text, IndentSettings::default().tab_width);
+        doc.text = Buffer::from(text);
+        doc.path = Some(path.to_path_buf());
+        Ok(doc)
+    }
+
+    pub fn is_untitled(&self) -> bool {
+        self.path.is_none()
+    }
+
+    /// Full path for display, or `untitled.txt` for new buffers.
+    pub fn filename(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => UNTITLED.to_string(),
+        }
+    }
+
+    /// Short name shown on the tab.
+    pub fn title(&self) -> String {
+        self.path
+            .as_ref()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .unwrap_or(UNTITLED)
+            .to_string()
+    }
+
+    /// Indentation to use and where it came from, for the status bar:
+    /// `.editorconfig` first, then what the file already uses, then
+    /// `default` from the settings.
+    pub fn indent(&self, default: IndentSettings) -> (IndentSettings, Option<&'static str>) {
+        let config = &self.editorconfig;
+        if config.has_indent() {
+            let use_tabs = config.use_tabs.unwrap_or(default.use_tabs);
+            let tab_width = config
+                .tab_width
+                .or(config.indent_size)
+                .unwrap_or(default.tab_width);
+            let width = if use_tabs {
+                tab_width
+            } else {
+                config.indent_size.unwrap_or(tab_width)
+            };
+            let indent = IndentSettings {
+                tab_width: width.max(1),
+                use_tabs,
+            };
+            return (indent, Some(".editorconfig"));
+        }
+        match self.detected_indent {
+            Some(detected) if detected.use_tabs => (
+                IndentSettings {
+                    tab_width: default.tab_width,
+                    use_tabs: true,
+                },
+                Some("detected"),
+            ),
+            Some(detected) => (detected, Some("detected")),
+            None => (default, None),
+        }
+    }
+
+    /// Edits `.editorconfig` asks for before saving: trailing whitespace
+    /// and the final newline. Bottom up, so they apply one after another.
+    fn save_fixes(&self) -> Vec<Edit> {
+        let config = &self.editorconfig;
+        let text = self.text.to_string();
+        let mut edits = Vec::new();
+
+        let trailing = text.len() - text.trim_end_matches('\n').len();
+        match config.insert_final_newline {
+            Some(true) if trailing == 0 && !text.is_empty() => {
+                edits.push(Edit::insert(self.text.len_chars(), "\n"));
+            }
+            Some(false) if trailing > 0 => {
+                let start = self.text.len_chars() - trailing;
+                edits.push(Edit {
+                    at: start,
+                    removed: text[text.len() - trailing..].to_string(),
+                    inserted: String::new(),
+                });
+            }
+            _ => {}
+        }
+
+        if config.trim_trailing_whitespace == Some(true) {
+            let rope = self.text.rope();
+            for line in (0..rope.len_lines()).rev() {
+                let content = rope.line(line).to_string();
+                let content = content.trim_end_matches('\n');
+                let kept = content.trim_end_matches([' ', '\t']);
+                if kept.len() < content.len() {
+                    let at = rope.line_to_char(line) + kept.chars().count();
+                    edits.push(Edit {
+                        at,
+                        removed: content[kept.len()..].to_string(),
+                        inserted: String::new(),
+                    });
+                }
+            }
+        }
+        edits
+    }
+
+    /// Decodes the file again with `encoding`, for when detection guessed
+    /// wrong. The new text replaces the old as one undo step.
+    pub fn reopen_with(&mut self, encoding: TextEncoding) -> std::io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let disk_stamp = DiskStamp::of(path);
+        let (text, _) = encoding.decode(&std::fs::read(path)?);
+        let text = line_ending::normalize(&text).into_owned();
+        self.encoding = encoding;
+        self.disk_stamp = disk_stamp;
+        self.disk_change = None;
+        let current = self.text.to_string();
+        if text != current {
+            self.edit(vec![Edit {
+                at: 0,
+                removed: current,
+                inserted: text,
+            }]);
+        }
+        self.modified = false;
+        self.history.mark_saved();
+        Ok(())
+    }
+
+    /// The bytes written on save, with the document's line endings and
+    /// encoding.
+    fn contents_for_save(&self) -> std::io::Result<Vec<u8>> {
+        self.encoding
+            .encode(&self.line_ending.apply(&self.text.to_string()))
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save_to(&mut self, path: &Path) -> std::io::Result<()> {
+        self.write_to(path, true)
+    }
+
+    /// Saves to the document's own file without the `.editorconfig` fixes,
+    /// which would otherwise trim whitespace out from under the cursor.
+    pub fn autosave(&mut self) -> std::io::Result<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        self.write_to(&path, false)
+    }
+
+    fn write_to(&mut self, path: &Path, apply_fixes: bool) -> std::io::Result<()> {
+        if self.path.as_deref() != Some(path) {
+            self.editorconfig = EditorConfig::for_path(path);
+            if let Some(encoding) = self.editorconfig.encoding() {
+                self.encoding = encoding;
+            }
+            if let Some(line_ending) = self.editorconfig.end_of_line {
+                self.line_ending = line_ending;
+            }
+        }
+        let fixes = if apply_fixes {
+            self.save_fixes()
+        } else {
+            Vec::new()
+        };
+        if !fixes.is_empty() {
+            self.edit(fixes);
+        }
+        crate::atomic::write(
+            path,
+            &self.contents_for_save()?,
+            crate::views::current_settings().backup_on_save,
+        )?;
+        if self.path.as_deref() != Some(path) {
+            self.path = Some(path.to_path_buf());
+            self.syntax = None;
+        }
+        self.modified = false;
+        self.mixed_line_endings = false;
+        self.disk_stamp = DiskStamp::of(path);
+        self.disk_change = None;
+        self.history.mark_saved();
+        if crate::views::current_settings().persistent_undo {
+            if let Err(e) = self.history.save(path, &self.text.to_string()) {
+                crate::views::report_error(format!("Failed to save undo history: {}", e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks at the file again after something changed it on disk. Reloads
+    /// a clean buffer and returns true; a dirty one (or a deleted file) is
+    /// left alone with `disk_change` set.
+    pub fn check_disk(&mut self) -> bool {
+        let Some(path) = self.path.clone() else {
+            return false;
+        };
+        let disk_stamp = DiskStamp::of(&path);
+        if disk_stamp == self.disk_stamp {
+            return false;
+        }
+        let Some(bytes) = disk_stamp.and_then(|_| std::fs::read(&path).ok()) else {
+            self.disk_change = Some(DiskChange::Deleted);
+            return false;
+        };
+        let (text, _) = self.encoding.decode(&bytes);
+        if line_ending::normalize(&text) == self.text.to_string() {
+            // Touched, or written with what we already have
+            self.disk_stamp = disk_stamp;
+            self.disk_change = None;
+            return false;
+        }
+        if self.modified {
+            self.disk_change = Some(DiskChange::Modified);
+            return false;
+        }
+        match self.reopen_with(self.encoding) {
+            Ok(()) => true,
+            Err(e) => {
+                crate::views::report_error(format!("Error reloading {}: {}", path.display(), e));
+                false
+            }
+        }
+    }
+
+    /// Keeps the buffer over a change on disk; saving will overwrite it.
+    pub fn keep_mine(&mut self) {
+        if let Some(path) = &self.path {
+            self.disk_stamp = DiskStamp::of(path);
+        }
+        self.disk_change = None;
+        self.modified = true;
+    }
+
+    /// Applies a programmatic edit as a single undo step.
+    pub fn edit(&mut self, edits: Vec<Edit>) {
+        for edit in &edits {
+            edit.apply(&mut self.text);
+        }
+        self.history.record_step(edits);
+        self.modified = true;
+    }
+
+    /// Returns the char offset the cursor should move to.
+    pub fn undo(&mut self) -> Option<usize> {
+        let cursor = self.history.undo(&mut self.text)?;
+        self.modified = !self.history.is_at_saved();
+        Some(cursor)
+    }
+
+    pub fn redo(&mut self) -> Option<usize> {
+        let cursor = self.history.redo(&mut self.text)?;
+        self.modified = !self.history.is_at_saved();
+        Some(cursor)
+    }
+
+    /// An untitled buffer nobody has typed into yet; opening a file replaces it.
+    fn is_pristine(&self) -> bool {
+        self.is_untitled() && !self.modified && self.text.is_empty()
+    }
+}
+
+#[derive(Default)]
+pub struct Tabs {
+    pub documents: Vec<Document>,
+    pub active: usize,
+    /// Ids of dirty documents waiting for the "Unsaved Changes" dialog.
+    pub close_queue: VecDeque<u64>,
+}
+
+impl Tabs {
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    pub fn active(&self) -> Option<&Document> {
+        self.documents.get(self.active)
+    }
+
+    pub fn active_mut(&mut self) -> Option<&mut Document> {
+        self.documents.get_mut(self.active)
+    }
+
+    pub fn index_of(&self, id: u64) -> Option<usize> {
+        self.documents.iter().position(|d| d.id == id)
+    }
+
+    pub fn any_modified(&self) -> bool {
+        self.documents.iter().any(|d| d.modified)
+    }
+
+    pub fn push(&mut self, doc: Document) {
+        if self.active().is_some_and(Document::is_pristine) {
+            self.documents[self.active] = doc;
+        } else {
+            self.documents.push(doc);
+            self.active = self.documents.len() - 1;
+        }
+    }
+
+    pub fn new_document(&mut self) {
+        self.documents.push(Document::new_untitled());
+        self.active = self.documents.len() - 1;
+    }
+
+    /// Opens `path` in a new tab, or focuses the tab that already has it.
+    pub fn open(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        if let Some(idx) = self
+            .documents
+            .iter()
+            .position(|d| d.path.as_deref() == Some(path))
+        {
+            self.active = idx;
+            return Ok(());
+        }
+        let doc = Document::open(path)?;
+        self.push(doc);
+        Ok(())
+    }
+
+    pub fn select_next(&mut self) {
+        if !self.documents.is_empty() {
+            self.active = (self.active + 1) % self.documents.len();
+        }
+    }
+
+    pub fn select_prev(&mut self) {
+        if !self.documents.is_empty() {
+            self.active = if self.active == 0 {
+                self.documents.len() - 1
+            } else {
+                self.active - 1
+            };
+        }
+    }
+
+    /// Closes the tab at `idx` without asking, discarding any changes.
+    pub fn close(&mut self, idx: usize) {
+        if idx >= self.documents.len() {
+            return;
+        }
+        let id = self.documents.remove(idx).id;
+        self.close_queue.retain(|&queued| queued != id);
+        if self.active > idx || self.active >= self.documents.len() {
+            self.active = self.active.saturating_sub(1);
+        }
+    }
+
+    /// Closes clean tabs right away; dirty ones are queued for confirmation.
+    pub fn request_close(&mut self, idx: usize) {
+        let Some(doc) = self.documents.get(idx) else {
+            return;
+        };
+        if doc.modified {
+            if !self.close_queue.contains(&doc.id) {
+                self.close_queue.push_back(doc.id);
+            }
+        } else {
+            self.close(idx);
+        }
+    }
+
+    /// Queues every dirty tab for confirmation, used when quitting.
+    pub fn request_close_all_modified(&mut self) {
+        for doc in &self.documents {
+            if doc.modified && !self.close_queue.contains(&doc.id) {
+                self.close_queue.push_back(doc.id);
+            }
+        }
+    }
+}
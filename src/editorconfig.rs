@@ -0,0 +1,382 @@
+use crate::encoding::TextEncoding;
+use crate::line_ending::LineEnding;
+use regex::Regex;
+use std::path::Path;
+
+/// The `.editorconfig` properties Kokona understands, resolved for one file.
+/// `None` means no file said anything (or it was `unset`).
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct EditorConfig {
+    /// `indent_style`: tabs when true, spaces when false.
+    pub use_tabs: Option<bool>,
+    /// `indent_size`; `tab` means the same as `tab_width`.
+    pub indent_size: Option<usize>,
+    pub tab_width: Option<usize>,
+    pub end_of_line: Option<LineEnding>,
+    /// `charset`, lowercased, e.g. `utf-8` or `utf-8-bom`.
+    pub charset: Option<String>,
+    pub trim_trailing_whitespace: Option<bool>,
+    pub insert_final_newline: Option<bool>,
+}
+
+impl EditorConfig {
+    /// Reads every `.editorconfig` from the file's folder up to the one
+    /// marked `root = true`, closer files and later sections winning.
+    pub fn for_path(path: &Path) -> Self {
+        let mut files = Vec::new();
+        let mut dir = path.parent();
+        while let Some(current) = dir {
+            if let Ok(contents) = std::fs::read_to_string(current.join(".editorconfig")) {
+                let file = ConfigFile::parse(&contents);
+                let root = file.root;
+                files.push((current, file));
+                if root {
+                    break;
+                }
+            }
+            dir = current.parent();
+        }
+
+        let mut config = Self::default();
+        for (dir, file) in files.iter().rev() {
+            let Ok(relative) = path.strip_prefix(dir) else {
+                continue;
+            };
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            for section in &file.sections {
+                if section.pattern.is_match(&relative) {
+                    for (key, value) in &section.properties {
+                        config.set(key, value);
+                    }
+                }
+            }
+        }
+        config
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        let unset = value == "unset";
+        let flag = match value {
+            "true" => Some(true),
+            "false" => Some(false),
+            _ => None,
+        };
+        match key {
+            "indent_style" => {
+                self.use_tabs = match value {
+                    "tab" => Some(true),
+                    "space" => Some(false),
+                    _ if unset => None,
+                    _ => self.use_tabs,
+                }
+            }
+            "indent_size" => {
+                self.indent_size = if value == "tab" || unset {
+                    None
+                } else {
+                    value.parse().ok().or(self.indent_size)
+                }
+            }
+            "tab_width" => {
+                self.tab_width = if unset {
+                    None
+                } else {
+                    value.parse().ok().or(self.tab_width)
+                }
+            }
+            "end_of_line" => {
+                self.end_of_line = match value {
+                    "lf" => Some(LineEnding::Lf),
+                    "crlf" => Some(LineEnding::CrLf),
+                    "cr" => Some(LineEnding::Cr),
+                    _ if unset => None,
+                    _ => self.end_of_line,
+                }
+            }
+            "charset" => self.charset = if unset { None } else { Some(value.to_string()) },
+            "trim_trailing_whitespace" => {
+                self.trim_trailing_whitespace = if unset {
+                    None
+                } else {
+                    flag.or(self.trim_trailing_whitespace)
+                }
+            }
+            "insert_final_newline" => {
+                self.insert_final_newline = if unset {
+                    None
+                } else {
+                    flag.or(self.insert_final_newline)
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The encoding `charset` asks for, if Kokona knows it.
+    pub fn encoding(&self) -> Option<TextEncoding> {
+        TextEncoding::from_charset(self.charset.as_deref()?)
+    }
+
+    /// Whether the file says anything about indentation.
+    pub fn has_indent(&self) -> bool {
+        self.use_tabs.is_some() || self.indent_size.is_some() || self.tab_width.is_some()
+    }
+}
+
+struct Section {
+    pattern: Regex,
+    properties: Vec<(String, String)>,
+}
+
+struct ConfigFile {
+    root: bool,
+    sections: Vec<Section>,
+}
+
+impl ConfigFile {
+    fn parse(contents: &str) -> Self {
+        let mut file = ConfigFile {
+            root: false,
+            sections: Vec::new(),
+        };
+        // Properties under a section whose glob didn't compile are dropped
+        let mut in_section = false;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(glob) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                in_section = match glob_to_regex(glob) {
+                    Some(pattern) => {
+                        file.sections.push(Section {
+                            pattern,
+                            properties: Vec::new(),
+                        });
+                        true
+                    }
+                    None => false,
+                };
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let key = key.trim().to_lowercase();
+            let value = value.trim().to_lowercase();
+            if !in_section {
+                // Only `root` is allowed before the first section
+                if key == "root" && file.sections.is_empty() {
+                    file.root = value == "true";
+                }
+                continue;
+            }
+            if let Some(section) = file.sections.last_mut() {
+                section.properties.push((key, value));
+            }
+        }
+        file
+    }
+}
+
+/// Compiles an EditorConfig glob into a regex over paths relative to the
+/// `.editorconfig`'s folder. Globs without a `/` match the file name in any
+/// subfolder.
+pub fn glob_to_regex(glob: &str) -> Option<Regex> {
+    let anywhere = !glob.contains('/');
+    let glob = glob.strip_prefix('/').unwrap_or(glob);
+    let chars: Vec<char> = glob.chars().collect();
+    let mut pattern = String::from(if anywhere { "^(?:.*/)?" } else { "^" });
+    let mut i = 0;
+    let mut braces = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '\\' if i + 1 < chars.len() => {
+                i += 1;
+                pattern.push_str(&regex::escape(&chars[i].to_string()));
+            }
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                i += 1;
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            '[' => match chars[i + 1..].iter().position(|&c| c == ']') {
+                Some(len) => {
+                    let class: String = chars[i + 1..i + 1 + len].iter().collect();
+                    let (negated, class) = match class.strip_prefix('!') {
+                        Some(rest) => (true, rest.to_string()),
+                        None => (false, class),
+                    };
+                    pattern.push('[');
+                    if negated {
+                        pattern.push('^');
+                    }
+                    for c in class.chars() {
+                        // Keep ranges like `a-z`, escape everything else
+                        if c == '-' {
+                            pattern.push(c);
+                        } else {
+                            pattern.push_str(&regex::escape(&c.to_string()));
+                        }
+                    }
+                    pattern.push(']');
+                    i += len + 1;
+                }
+                None => pattern.push_str("\\["),
+            },
+            '{' => {
+                let close = chars[i + 1..].iter().position(|&c| c == '}');
+                let inner: Option<String> =
+                    close.map(|len| chars[i + 1..i + 1 + len].iter().collect());
+                if let Some(range) = inner.as_deref().and_then(number_range) {
+                    pattern.push_str(&range);
+                    i += close.unwrap_or(0) + 1;
+                } else if inner.as_deref().is_some_and(|inner| inner.contains(',')) {
+                    braces += 1;
+                    pattern.push_str("(?:");
+                } else {
+                    pattern.push_str("\\{");
+                }
+            }
+            ',' if braces > 0 => pattern.push('|'),
+            '}' if braces > 0 => {
+                braces -= 1;
+                pattern.push(')');
+            }
+            _ => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+        i += 1;
+    }
+    pattern.push('$');
+    Regex::new(&pattern).ok()
+}
+
+/// `{n1..n2}`: any whole number between the two, inclusive.
+fn number_range(inner: &str) -> Option<String> {
+    let (from, to) = inner.split_once("..")?;
+    let (from, to): (i64, i64) = (from.parse().ok()?, to.parse().ok()?);
+    let (from, to) = (from.min(to), from.max(to));
+    // Large ranges would make a huge regex; match any number instead
+    if to - from > 1000 {
+        return Some(r"(?:[+-]?\d+)".to_string());
+    }
+    let numbers: Vec<String> = (from..=to).map(|n| n.to_string()).collect();
+    Some(format!("(?:{})", numbers.join("|")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn matches(glob: &str, path: &str) -> bool {
+        glob_to_regex(glob).unwrap().is_match(path)
+    }
+
+    /// A fresh folder for one test, for the test to remove.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kokona-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn stars_stop_at_slashes_unless_doubled() {
+        // Without a slash the glob matches in any subfolder
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", "src/main.rs"));
+        assert!(!matches("*.rs", "main_rs"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/bin/main.rs"));
+        assert!(!matches("src/*.rs", "lib/src/main.rs"));
+        assert!(matches("src/**.rs", "src/bin/main.rs"));
+        assert!(matches("/src/**/*.rs", "src/a/b/main.rs"));
+        assert!(matches("?.c", "a.c"));
+        assert!(!matches("?.c", "ab.c"));
+    }
+
+    #[test]
+    fn braces_give_alternatives_or_number_ranges() {
+        assert!(matches("*.{js,ts}", "a.ts"));
+        assert!(!matches("*.{js,ts}", "a.rs"));
+        assert!(matches("v{1..3}.txt", "v2.txt"));
+        assert!(!matches("v{1..3}.txt", "v4.txt"));
+        assert!(matches("v{-2..1}.txt", "v-2.txt"));
+        assert!(matches("v{-2..1}.txt", "v0.txt"));
+        assert!(!matches("v{-2..1}.txt", "v-3.txt"));
+        // Either order, and a lone word in braces is taken literally
+        assert!(matches("v{3..1}.txt", "v2.txt"));
+        assert!(matches("{a}.txt", "{a}.txt"));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(matches("[ab].txt", "a.txt"));
+        assert!(!matches("[ab].txt", "c.txt"));
+        assert!(matches("[!ab].txt", "c.txt"));
+        assert!(!matches("[!ab].txt", "a.txt"));
+        assert!(matches("[a-c].txt", "b.txt"));
+        assert!(matches("[a.txt", "[a.txt"));
+        assert!(matches(r"\*.txt", "*.txt"));
+        assert!(!matches(r"\*.txt", "a.txt"));
+        assert!(matches(r"a\{b,c}.txt", "a{b,c}.txt"));
+        assert!(!matches(r"a\{b,c}.txt", "ab.txt"));
+    }
+
+    #[test]
+    fn closer_files_and_later_sections_win_up_to_the_root() {
+        let dir = temp_dir("editorconfig-sections");
+        fs::create_dir_all(dir.join("project/src")).unwrap();
+        // Above the root, so never read
+        fs::write(dir.join(".editorconfig"), "[*]\ncharset = latin1\n").unwrap();
+        fs::write(
+            dir.join("project/.editorconfig"),
+            "root = true\n\n[*]\nindent_style = space\nindent_size = 4\nend_of_line = crlf\n\n[*.rs]\nindent_size = 2\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("project/src/.editorconfig"),
+            "[*.rs]\ntab_width = 8\nend_of_line = unset\n",
+        )
+        .unwrap();
+        let rust = EditorConfig::for_path(&dir.join("project/src/main.rs"));
+        let other = EditorConfig::for_path(&dir.join("project/src/notes.txt"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            rust,
+            EditorConfig {
+                use_tabs: Some(false),
+                indent_size: Some(2),
+                tab_width: Some(8),
+                ..EditorConfig::default()
+            }
+        );
+        assert_eq!(other.indent_size, Some(4));
+        assert_eq!(other.end_of_line, Some(LineEnding::CrLf));
+        assert_eq!(other.charset, None);
+    }
+
+    #[test]
+    fn keys_and_values_are_case_insensitive() {
+        let dir = temp_dir("editorconfig-case");
+        fs::write(
+            dir.join(".editorconfig"),
+            "ROOT = True\n; a comment\n[*]\nIndent_Style = TAB\nCharset = UTF-8\nInsert_Final_Newline = FALSE\nindent_size = tab\n",
+        )
+        .unwrap();
+        let config = EditorConfig::for_path(&dir.join("a.txt"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(config.use_tabs, Some(true));
+        assert_eq!(config.charset.as_deref(), Some("utf-8"));
+        assert_eq!(config.insert_final_newline, Some(false));
+        assert_eq!(config.indent_size, None);
+        assert!(config.has_indent());
+        assert_eq!(config.encoding(), Some(TextEncoding::UTF_8));
+    }
+}
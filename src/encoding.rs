@@ -0,0 +1,302 @@
+use encoding_rs::Encoding;
+
+/// Encodings offered in "Reopen with Encoding…" and "Save with Encoding…".
+pub const CHOICES: &[TextEncoding] = &[
+    TextEncoding::UTF_8,
+    TextEncoding {
+        encoding: encoding_rs::UTF_8,
+        bom: true,
+    },
+    TextEncoding {
+        encoding: encoding_rs::UTF_16LE,
+        bom: true,
+    },
+    TextEncoding {
+        encoding: encoding_rs::UTF_16BE,
+        bom: true,
+    },
+    TextEncoding::new(encoding_rs::WINDOWS_1252),
+    TextEncoding::new(encoding_rs::ISO_8859_15),
+    TextEncoding::new(encoding_rs::WINDOWS_1250),
+    TextEncoding::new(encoding_rs::ISO_8859_2),
+    TextEncoding::new(encoding_rs::WINDOWS_1251),
+    TextEncoding::new(encoding_rs::KOI8_R),
+    TextEncoding::new(encoding_rs::WINDOWS_1253),
+    TextEncoding::new(encoding_rs::WINDOWS_1254),
+    TextEncoding::new(encoding_rs::WINDOWS_1255),
+    TextEncoding::new(encoding_rs::WINDOWS_1256),
+    TextEncoding::new(encoding_rs::WINDOWS_874),
+    TextEncoding::new(encoding_rs::SHIFT_JIS),
+    TextEncoding::new(encoding_rs::EUC_JP),
+    TextEncoding::new(encoding_rs::ISO_2022_JP),
+    TextEncoding::new(encoding_rs::GBK),
+    TextEncoding::new(encoding_rs::GB18030),
+    TextEncoding::new(encoding_rs::BIG5),
+    TextEncoding::new(encoding_rs::EUC_KR),
+];
+
+/// How a file's bytes map to text, kept with the document so saving writes
+/// it back the way it was read.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TextEncoding {
+    pub encoding: &'static Encoding,
+    /// Whether the file starts with a byte order mark.
+    pub bom: bool,
+}
+
+impl Default for TextEncoding {
+    fn default() -> Self {
+        Self::UTF_8
+    }
+}
+
+impl TextEncoding {
+    pub const UTF_8: Self = Self::new(encoding_rs::UTF_8);
+
+    pub const fn new(encoding: &'static Encoding) -> Self {
+        Self {
+            encoding,
+            bom: false,
+        }
+    }
+
+    /// The `.editorconfig` `charset` values.
+    pub fn from_charset(charset: &str) -> Option<Self> {
+        let (encoding, bom) = match charset {
+            "utf-8" => (encoding_rs::UTF_8, false),
+            "utf-8-bom" => (encoding_rs::UTF_8, true),
+            "utf-16le" => (encoding_rs::UTF_16LE, true),
+            "utf-16be" => (encoding_rs::UTF_16BE, true),
+            // What every legacy decoder actually treats Latin-1 as
+            "latin1" => (encoding_rs::WINDOWS_1252, false),
+            _ => return None,
+        };
+        Some(Self { encoding, bom })
+    }
+
+    /// Shown in the status bar and the encoding menus.
+    pub fn name(&self) -> String {
+        let name = match self.encoding.name() {
+            "UTF-8" => "UTF-8",
+            "UTF-16LE" => "UTF-16 LE",
+            "UTF-16BE" => "UTF-16 BE",
+            other => other,
+        };
+        if self.bom && self.encoding == encoding_rs::UTF_8 {
+            format!("{} with BOM", name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// Detects the encoding of `bytes` and decodes them. A byte order mark
+    /// wins, then UTF-16 or valid UTF-8, then the best guess for legacy
+    /// encodings.
+    pub fn detect(bytes: &[u8]) -> (String, Self) {
+        if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+            let encoding = Self {
+                encoding,
+                bom: true,
+            };
+            return (encoding.decode(bytes).0, encoding);
+        }
+        // Before UTF-8, which ASCII in UTF-16 (with all its NULs) also is
+        if let Some(encoding) = utf16_without_bom(bytes) {
+            let encoding = Self::new(encoding);
+            return (encoding.decode(bytes).0, encoding);
+        }
+        if let Ok(text) = std::str::from_utf8(bytes) {
+            return (text.to_string(), Self::UTF_8);
+        }
+        let mut detector = chardetng::EncodingDetector::new(chardetng::Iso2022JpDetection::Allow);
+        detector.feed(bytes, true);
+        let encoding = Self::new(detector.guess(None, chardetng::Utf8Detection::Allow));
+        (encoding.decode(bytes).0, encoding)
+    }
+
+    /// Like `detect`, but decodes with `preferred`, such as the
+    /// `.editorconfig` charset, when no byte order mark says otherwise and
+    /// the bytes are valid in it. Anything else would change the file on
+    /// the next save.
+    pub fn detect_with(bytes: &[u8], preferred: Option<Self>) -> (String, Self) {
+        if let Some(preferred) = preferred {
+            let bom = Encoding::for_bom(bytes).map(|(encoding, _)| encoding);
+            if bom.is_none_or(|encoding| encoding == preferred.encoding) {
+                let (text, had_errors) = preferred.decode(bytes);
+                if !had_errors {
+                    return (text, preferred);
+                }
+            }
+        }
+        Self::detect(bytes)
+    }
+
+    /// Decodes `bytes`, skipping the byte order mark. Also says whether
+    /// some bytes weren't valid and became U+FFFD.
+    pub fn decode(&self, bytes: &[u8]) -> (String, bool) {
+        let bytes = match Encoding::for_bom(bytes) {
+            Some((encoding, len)) if encoding == self.encoding => &bytes[len..],
+            _ => bytes,
+        };
+        let (text, had_errors) = self.encoding.decode_without_bom_handling(bytes);
+        (text.into_owned(), had_errors)
+    }
+
+    /// Encodes `text` for writing. Fails on the first character the
+    /// encoding has no way to represent, instead of writing something else.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::with_capacity(text.len() + 3);
+        let utf16 = |big_endian: bool| {
+            text.encode_utf16()
+                .flat_map(move |unit| {
+                    if big_endian {
+                        unit.to_be_bytes()
+                    } else {
+                        unit.to_le_bytes()
+                    }
+                })
+                .collect::<Vec<u8>>()
+        };
+        // encoding_rs only decodes UTF-16, as the web never writes it
+        if self.encoding == encoding_rs::UTF_16LE {
+            if self.bom {
+                bytes.extend([0xFF, 0xFE]);
+            }
+            bytes.extend(utf16(false));
+            return Ok(bytes);
+        }
+        if self.encoding == encoding_rs::UTF_16BE {
+            if self.bom {
+                bytes.extend([0xFE, 0xFF]);
+            }
+            bytes.extend(utf16(true));
+            return Ok(bytes);
+        }
+
+        if self.bom && self.encoding == encoding_rs::UTF_8 {
+            bytes.extend([0xEF, 0xBB, 0xBF]);
+        }
+        let mut encoder = self.encoding.new_encoder();
+        let mut rest = text;
+        let mut buffer = [0u8; 4096];
+        loop {
+            let (result, read, written) =
+                encoder.encode_from_utf8_without_replacement(rest, &mut buffer, true);
+            bytes.extend_from_slice(&buffer[..written]);
+            rest = &rest[read..];
+            match result {
+                encoding_rs::EncoderResult::InputEmpty => return Ok(bytes),
+                encoding_rs::EncoderResult::OutputFull => {}
+                encoding_rs::EncoderResult::Unmappable(c) => {
+                    return Err(format!("'{}' can't be written as {}", c, self.name()));
+                }
+            }
+        }
+    }
+}
+
+/// Whether `bytes` look like something other than text: NULs near the
+/// start, which UTF-16 text only has as half of its characters.
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes[..bytes.len().min(8192)].contains(&0)
+        && Encoding::for_bom(bytes).is_none()
+        && utf16_without_bom(bytes).is_none()
+}
+
+/// UTF-16 without a byte order mark shows up as every other byte being
+/// zero for mostly-ASCII text.
+fn utf16_without_bom(bytes: &[u8]) -> Option<&'static Encoding> {
+    let sample = &bytes[..bytes.len().min(4096) & !1];
+    if sample.len() < 4 {
+        return None;
+    }
+    let zeros = |offset: usize| {
+        sample
+            .iter()
+            .skip(offset)
+            .step_by(2)
+            .filter(|&&b| b == 0)
+            .count()
+    };
+    let half = sample.len() / 2;
+    if zeros(1) * 10 > half * 7 && zeros(0) * 10 < half {
+        Some(encoding_rs::UTF_16LE)
+    } else if zeros(0) * 10 > half * 7 && zeros(1) * 10 < half {
+        Some(encoding_rs::UTF_16BE)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_and_boms_are_detected() {
+        assert_eq!(
+            TextEncoding::detect("héllo".as_bytes()),
+            ("héllo".to_string(), TextEncoding::UTF_8)
+        );
+        let (text, encoding) = TextEncoding::detect(b"\xEF\xBB\xBFhi");
+        assert_eq!(text, "hi");
+        assert_eq!(encoding.encoding, encoding_rs::UTF_8);
+        assert!(encoding.bom);
+        let (text, encoding) = TextEncoding::detect(b"\xFF\xFEh\0i\0");
+        assert_eq!(text, "hi");
+        assert_eq!(encoding.encoding, encoding_rs::UTF_16LE);
+    }
+
+    #[test]
+    fn utf16_without_bom_is_detected_before_utf8() {
+        let (text, encoding) = TextEncoding::detect(b"\0h\0e\0l\0l\0o");
+        assert_eq!(text, "hello");
+        assert_eq!(encoding, TextEncoding::new(encoding_rs::UTF_16BE));
+        assert!(!is_binary(b"\0h\0e\0l\0l\0o"));
+        assert!(is_binary(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x03\0"));
+    }
+
+    #[test]
+    fn invalid_utf8_falls_back_to_a_legacy_guess() {
+        let (text, encoding) = TextEncoding::detect(b"caf\xE9 cr\xE8me br\xFBl\xE9e");
+        assert_eq!(text, "café crème brûlée");
+        assert_ne!(encoding.encoding, encoding_rs::UTF_8);
+    }
+
+    #[test]
+    fn every_choice_round_trips_with_its_bom() {
+        for &encoding in CHOICES {
+            let bytes = encoding.encode("line\n").unwrap();
+            assert_eq!(encoding.decode(&bytes), ("line\n".to_string(), false));
+            if encoding.bom {
+                assert_eq!(
+                    TextEncoding::detect(&bytes).1,
+                    encoding,
+                    "{}",
+                    encoding.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unmappable_characters_fail_to_encode() {
+        assert!(TextEncoding::new(encoding_rs::WINDOWS_1252)
+            .encode("日本")
+            .is_err());
+    }
+
+    #[test]
+    fn the_preferred_encoding_wins_when_it_fits() {
+        let latin1 = TextEncoding::from_charset("latin1");
+        // Plain ASCII is valid UTF-8 too, but the charset says otherwise
+        assert_eq!(TextEncoding::detect_with(b"abc", latin1).1, latin1.unwrap());
+        // A BOM for another encoding beats it
+        let (_, encoding) = TextEncoding::detect_with(b"\xEF\xBB\xBFabc", latin1);
+        assert_eq!(encoding.encoding, encoding_rs::UTF_8);
+        // So do bytes that aren't valid in it
+        let utf8 = TextEncoding::from_charset("utf-8");
+        let (_, encoding) = TextEncoding::detect_with(b"caf\xE9", utf8);
+        assert_ne!(encoding.encoding, encoding_rs::UTF_8);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::editorconfig::EditorConfig;
+use crate::encoding::{self, TextEncoding};
+use crate::line_ending;
+use eframe::egui;
+use regex::{NoExpand, Regex};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Stop collecting past this many hits; the list is unusable by then anyway.
+const MAX_HITS: usize = 10_000;
+/// Longer lines are shortened in the result list.
+const MAX_PREVIEW_CHARS: usize = 200;
+
+/// Builds the regex behind both search panels. Plain queries are escaped,
+/// whole-word wraps the pattern in `\b`.
+pub fn compile_query(
+    query: &str,
+    regex: bool,
+    whole_word: bool,
+    case_sensitive: bool,
+) -> Result<Regex, String> {
+    let mut pattern = if regex {
+        query.to_string()
+    } else {
+        regex::escape(query)
+    };
+    if whole_word {
+        pattern = format!(r"\b(?:{})\b", pattern);
+    }
+    regex::RegexBuilder::new(&pattern)
+        .case_insensitive(!case_sensitive)
+        .multi_line(true)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+pub struct Hit {
+    /// Zero-based line number.
+    pub line: usize,
+    /// The whole line, without its line break.
+    pub text: String,
+}
+
+impl Hit {
+    /// `text` shortened for display.
+    pub fn preview(&self) -> String {
+        shorten(self.text.trim_start())
+    }
+}
+
+fn shorten(text: &str) -> String {
+    match text.char_indices().nth(MAX_PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+pub struct FileHits {
+    pub path: PathBuf,
+    pub hits: Vec<Hit>,
+}
+
+/// A search running (or finished) on a background thread. Results are
+/// appended file by file as they are found.
+struct Search {
+    pattern: Regex,
+    results: Arc<Mutex<Vec<FileHits>>>,
+    cancel: Arc<AtomicBool>,
+    done: Arc<AtomicBool>,
+}
+
+impl Drop for Search {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub struct FindInFiles {
+    pub open: bool,
+    pub query: String,
+    pub replacement: String,
+    pub case_sensitive: bool,
+    pub whole_word: bool,
+    pub regex: bool,
+    /// Shows what every hit turns into, and the button that writes it.
+    pub replace_mode: bool,
+    /// Folder to search; the current file's folder when not chosen.
+    pub root: Option<PathBuf>,
+    pub error: Option<String>,
+    search: Option<Search>,
+}
+
+impl FindInFiles {
+    /// Starts searching `root`, dropping (and stopping) any earlier search.
+    pub fn start(&mut self, ctx: &egui::Context, root: &Path) {
+        self.search = None;
+        self.error = None;
+        if self.query.is_empty() {
+            return;
+        }
+        let pattern = match compile_query(
+            &self.query,
+            self.regex,
+            self.whole_word,
+            self.case_sensitive,
+        ) {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                self.error = Some(e);
+                return;
+            }
+        };
+
+        let search = Search {
+            pattern: pattern.clone(),
+            results: Arc::new(Mutex::new(Vec::new())),
+            cancel: Arc::new(AtomicBool::new(false)),
+            done: Arc::new(AtomicBool::new(false)),
+        };
+        let results = search.results.clone();
+        let cancel = search.cancel.clone();
+        let done = search.done.clone();
+        let root = root.to_path_buf();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            let mut total = 0;
+            // Honours .gitignore, .ignore and hidden files like git does
+            for entry in ignore::WalkBuilder::new(&root).require_git(false).build() {
+                if cancel.load(Ordering::Relaxed) || total >= MAX_HITS {
+                    break;
+                }
+                let Ok(entry) = entry else {
+                    continue;
+                };
+                if !entry.file_type().is_some_and(|t| t.is_file()) {
+                    continue;
+                }
+                let hits = search_file(&pattern, entry.path());
+                if !hits.is_empty() {
+                    total += hits.len();
+                    results.lock().unwrap().push(FileHits {
+                        path: entry.into_path(),
+                        hits,
+                    });
+                    ctx.request_repaint();
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+            ctx.request_repaint();
+        });
+        self.search = Some(search);
+    }
+
+    pub fn cancel(&mut self) {
+        if let Some(search) = &self.search {
+            search.cancel.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.search
+            .as_ref()
+            .is_some_and(|s| !s.done.load(Ordering::Relaxed))
+    }
+
+    pub fn was_cancelled(&self) -> bool {
+        self.search
+            .as_ref()
+            .is_some_and(|s| s.cancel.load(Ordering::Relaxed))
+    }
+
+    /// Results so far, grouped by file in the order they were found.
+    pub fn results(&self) -> Option<MutexGuard<'_, Vec<FileHits>>> {
+        self.search.as_ref().map(|s| s.results.lock().unwrap())
+    }
+
+    /// What a hit line becomes after replacing, with capture groups
+    /// expanded in regex mode.
+    pub fn replaced_line(&self, line: &str) -> String {
+        let Some(search) = &self.search else {
+            return line.to_string();
+        };
+        if self.regex {
+            search
+                .pattern
+                .replace_all(line, self.replacement.as_str())
+                .into_owned()
+        } else {
+            search
+                .pattern
+                .replace_all(line, NoExpand(&self.replacement))
+                .into_owned()
+        }
+    }
+
+    /// Line replacements for `file` in `text`: the byte span of each hit
+    /// line and its new contents, last line first. `None` if the text no
+    /// longer has the lines that were found, i.e. it changed since.
+    pub fn replacements(&self, file: &FileHits, text: &str) -> Option<Vec<(Range<usize>, String)>> {
+        let mut starts = vec![0];
+        starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        let mut replacements = Vec::new();
+        for hit in file.hits.iter().rev() {
+            let start = *starts.get(hit.line)?;
+            let end = start + hit.text.len();
+            if text.get(start..end) != Some(hit.text.as_str()) {
+                return None;
+            }
+            replacements.push((start..end, self.replaced_line(&hit.text)));
+        }
+        Some(replacements)
+    }
+}
+
+/// Matching lines of one file, decoded and with line breaks normalized the
+/// way the editor opens it, `.editorconfig` charset included. Binary files
+/// are skipped.
+fn search_file(pattern: &Regex, path: &Path) -> Vec<Hit> {
+    let Ok(bytes) = std::fs::read(path) else {
+        return Vec::new();
+    };
+    if encoding::is_binary(&bytes) {
+        return Vec::new();
+    }
+    let (text, _) = TextEncoding::detect_with(&bytes, EditorConfig::for_path(path).encoding());
+    line_ending::normalize(&text)
+        .split('\n')
+        .enumerate()
+        .filter(|(_, text)| pattern.find_iter(text).any(|m| !m.is_empty()))
+        .map(|(line, text)| Hit {
+            line,
+            text: text.to_string(),
+        })
+        .collect()
+}
@@ -0,0 +1,418 @@
+use crate::buffer::{Buffer, Change};
+use eframe::egui;
+use ropey::Rope;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use syntect::highlighting::{
+    HighlightIterator, HighlightState, Highlighter as ThemeHighlighter, Style, Theme,
+};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxSet};
+
+/// Lines highlighted per pass before the results are handed to the editor.
+const CHUNK_LINES: usize = 256;
+/// How often the editor is repainted while a long file is still colouring in.
+const REPAINT_DELAY: Duration = Duration::from_millis(100);
+
+/// Styled byte ranges within one line, in order. May be stale (or empty)
+/// while the worker catches up, so callers have to check they still fit.
+pub type Line = Vec<(Range<usize>, Style)>;
+
+/// Parser and highlighter state at the start of a line. Not `Send`, so these
+/// only ever live on the worker thread.
+type LineState = (ParseState, HighlightState);
+
+/// What the editor sees of a document: its lines, kept in step with the
+/// buffer on the UI thread, and filled in by the worker.
+struct Published {
+    syntax: String,
+    revision: u64,
+    lines: Vec<Line>,
+    /// Bumped on every message sent to the worker, so it can tell whether
+    /// its results are for the text the editor has now.
+    epoch: u64,
+    /// Bumped whenever `lines` gets new highlights.
+    generation: u64,
+}
+
+struct Shared {
+    docs: HashMap<u64, Published>,
+    /// Document shown in the editor, highlighted before the others.
+    active: Option<u64>,
+}
+
+enum Message {
+    Reset {
+        doc_id: u64,
+        epoch: u64,
+        rope: Rope,
+        syntax: String,
+    },
+    /// Lines `first..=old_last` became `first..=last`.
+    Edit {
+        doc_id: u64,
+        epoch: u64,
+        rope: Rope,
+        first: usize,
+        old_last: usize,
+        last: usize,
+    },
+    Close(u64),
+    /// Every document is highlighted again with the new theme.
+    Theme(Theme),
+}
+
+/// Syntax highlighting kept per line and updated on a background thread.
+/// Edits only re-highlight from the edited line until the parser ends up in
+/// the same state it was in before, so typing stays cheap in large files.
+pub struct Highlighter {
+    shared: Arc<Mutex<Shared>>,
+    sender: Sender<Message>,
+}
+
+impl Highlighter {
+    pub fn new(ctx: &egui::Context, ps: Arc<SyntaxSet>, theme: Theme) -> Self {
+        let shared = Arc::new(Mutex::new(Shared {
+            docs: HashMap::new(),
+            active: None,
+        }));
+        let (sender, receiver) = mpsc::channel();
+
+        let worker_shared = shared.clone();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            // Built on the thread itself, parser states can't cross threads
+            let worker = Worker {
+                ps,
+                theme,
+                shared: worker_shared,
+                docs: HashMap::new(),
+            };
+            worker.run(&receiver, &ctx);
+        });
+
+        Self { shared, sender }
+    }
+
+    /// Brings the document's highlights in line with `text`. Cheap when
+    /// nothing changed, so it is fine to call every frame.
+    pub fn update(&self, doc_id: u64, text: &Buffer, syntax: &str) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.active = Some(doc_id);
+        let doc = shared.docs.entry(doc_id).or_insert_with(|| Published {
+            syntax: String::new(),
+            revision: 0,
+            lines: Vec::new(),
+            epoch: 0,
+            generation: 0,
+        });
+
+        let change = if doc.syntax != syntax {
+            Change::Replaced
+        } else {
+            text.changes_since(doc.revision)
+        };
+        doc.revision = text.revision();
+        doc.epoch += 1;
+        let message = match change {
+            Change::Unchanged => {
+                doc.epoch -= 1;
+                return;
+            }
+            Change::Replaced => {
+                doc.syntax = syntax.to_string();
+                // Old spans stay up until their lines are redone
+                doc.lines.resize_with(text.len_lines(), Vec::new);
+                Message::Reset {
+                    doc_id,
+                    epoch: doc.epoch,
+                    rope: text.rope().clone(),
+                    syntax: syntax.to_string(),
+                }
+            }
+            Change::Edited(delta) => {
+                let first = text.byte_to_line(delta.start);
+                let last = text.byte_to_line(delta.start + delta.new_len);
+                let added = text.len_lines() as isize - doc.lines.len() as isize;
+                let old_last = (last as isize - added) as usize;
+                doc.lines
+                    .splice(first + 1..=old_last, (first + 1..=last).map(|_| Vec::new()));
+                Message::Edit {
+                    doc_id,
+                    epoch: doc.epoch,
+                    rope: text.rope().clone(),
+                    first,
+                    old_last,
+                    last,
+                }
+            }
+        };
+        let _ = self.sender.send(message);
+    }
+
+    /// Bumped whenever new highlights for the document come in.
+    pub fn generation(&self, doc_id: u64) -> u64 {
+        let shared = self.shared.lock().unwrap();
+        shared.docs.get(&doc_id).map_or(0, |doc| doc.generation)
+    }
+
+    /// Runs `f` on the highlighted lines of a document, one per text line.
+    pub fn with_lines<R>(&self, doc_id: u64, f: impl FnOnce(&[Line]) -> R) -> R {
+        let shared = self.shared.lock().unwrap();
+        f(shared.docs.get(&doc_id).map_or(&[], |doc| &doc.lines))
+    }
+
+    pub fn set_theme(&self, theme: Theme) {
+        let _ = self.sender.send(Message::Theme(theme));
+    }
+
+    /// Forgets documents that are no longer open.
+    pub fn retain(&self, is_open: impl Fn(u64) -> bool) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.docs.retain(|&doc_id, _| {
+            let open = is_open(doc_id);
+            if !open {
+                let _ = self.sender.send(Message::Close(doc_id));
+            }
+            open
+        });
+    }
+}
+
+/// The worker's side of a document.
+struct WorkDoc {
+    syntax: String,
+    rope: Rope,
+    epoch: u64,
+    /// Start state of every line; `None` until the worker gets there.
+    states: Vec<Option<LineState>>,
+    /// First line whose highlighting is out of date.
+    dirty: Option<usize>,
+    /// Last line touched by an edit. Past it the worker stops as soon as a
+    /// line starts in the same state as before, since nothing below changes.
+    edited_until: usize,
+}
+
+struct Highlighted {
+    first: usize,
+    spans: Vec<Line>,
+    /// Start state of each line after a highlighted one.
+    next_states: Vec<LineState>,
+    converged: bool,
+}
+
+impl WorkDoc {
+    fn reset(&mut self, initial: LineState) {
+        self.states.clear();
+        self.states.resize_with(self.rope.len_lines(), || None);
+        self.states[0] = Some(initial);
+        self.dirty = Some(0);
+        self.edited_until = usize::MAX;
+    }
+
+    /// Shifts the states after the edit into place. The first edited line
+    /// keeps its start state, the edit begins inside it.
+    fn splice(&mut self, first: usize, old_last: usize, last: usize) {
+        self.states
+            .splice(first + 1..=old_last, (first + 1..=last).map(|_| None));
+        self.edited_until = match self.dirty {
+            Some(_) if self.edited_until == usize::MAX => usize::MAX,
+            Some(_) if self.edited_until > old_last => (self.edited_until + last)
+                .saturating_sub(old_last)
+                .max(last),
+            Some(_) => self.edited_until.max(last),
+            None => last,
+        };
+        self.dirty = Some(self.dirty.map_or(first, |dirty| dirty.min(first)));
+    }
+
+    /// Highlights up to a chunk of lines from the first dirty one.
+    fn highlight(&self, ps: &SyntaxSet, highlighter: &ThemeHighlighter) -> Option<Highlighted> {
+        let first = self.dirty?;
+        let (mut parse, mut highlight) = self.states.get(first)?.clone()?;
+        let mut result = Highlighted {
+            first,
+            spans: Vec::new(),
+            next_states: Vec::new(),
+            converged: false,
+        };
+
+        let end = (first + CHUNK_LINES).min(self.rope.len_lines());
+        for i in first..end {
+            let line: Cow<str> = self.rope.line(i).into();
+            let ops = parse.parse_line(&line, ps).unwrap_or_default();
+            let mut spans = Vec::new();
+            let mut offset = 0;
+            for (style, piece) in HighlightIterator::new(&mut highlight, &ops, &line, highlighter) {
+                spans.push((offset..offset + piece.len(), style));
+                offset += piece.len();
+            }
+            result.spans.push(spans);
+
+            let converged = i >= self.edited_until
+                && matches!(self.states.get(i + 1), Some(Some((p, h))) if *p == parse && *h == highlight);
+            if converged {
+                result.converged = true;
+                break;
+            }
+            result.next_states.push((parse.clone(), highlight.clone()));
+        }
+        Some(result)
+    }
+
+    fn commit(&mut self, result: Highlighted) {
+        let next = result.first + result.spans.len();
+        for (state, next_state) in self.states[result.first + 1..]
+            .iter_mut()
+            .zip(result.next_states)
+        {
+            *state = Some(next_state);
+        }
+        self.dirty = (!result.converged && next < self.states.len()).then_some(next);
+    }
+}
+
+struct Worker {
+    ps: Arc<SyntaxSet>,
+    theme: Theme,
+    shared: Arc<Mutex<Shared>>,
+    docs: HashMap<u64, WorkDoc>,
+}
+
+impl Worker {
+    fn run(mut self, receiver: &Receiver<Message>, ctx: &egui::Context) {
+        loop {
+            let busy = self.docs.values().any(|doc| doc.dirty.is_some());
+            let message = if busy {
+                receiver.try_recv().ok()
+            } else {
+                match receiver.recv() {
+                    Ok(message) => Some(message),
+                    // The editor is gone
+                    Err(_) => return,
+                }
+            };
+            // Catch up with every edit before highlighting anything
+            if let Some(message) = message {
+                self.apply(message);
+                continue;
+            }
+            self.highlight_next(ctx);
+        }
+    }
+
+    fn initial_state(&self, syntax: &str) -> LineState {
+        let syntax = self
+            .ps
+            .find_syntax_by_name(syntax)
+            .unwrap_or_else(|| self.ps.find_syntax_plain_text());
+        let highlighter = ThemeHighlighter::new(&self.theme);
+        (
+            ParseState::new(syntax),
+            HighlightState::new(&highlighter, ScopeStack::new()),
+        )
+    }
+
+    fn apply(&mut self, message: Message) {
+        match message {
+            Message::Reset {
+                doc_id,
+                epoch,
+                rope,
+                syntax,
+            } => {
+                let initial = self.initial_state(&syntax);
+                let doc = self.docs.entry(doc_id).or_insert_with(|| WorkDoc {
+                    syntax: String::new(),
+                    rope: Rope::new(),
+                    epoch: 0,
+                    states: Vec::new(),
+                    dirty: None,
+                    edited_until: 0,
+                });
+                doc.syntax = syntax;
+                doc.rope = rope;
+                doc.epoch = epoch;
+                doc.reset(initial);
+            }
+            Message::Edit {
+                doc_id,
+                epoch,
+                rope,
+                first,
+                old_last,
+                last,
+            } => {
+                if let Some(doc) = self.docs.get_mut(&doc_id) {
+                    doc.rope = rope;
+                    doc.epoch = epoch;
+                    doc.splice(first, old_last, last);
+                }
+            }
+            Message::Close(doc_id) => {
+                self.docs.remove(&doc_id);
+            }
+            Message::Theme(theme) => {
+                self.theme = theme;
+                let initial: Vec<_> = self
+                    .docs
+                    .values()
+                    .map(|doc| self.initial_state(&doc.syntax))
+                    .collect();
+                for (doc, initial) in self.docs.values_mut().zip(initial) {
+                    doc.reset(initial);
+                }
+            }
+        }
+    }
+
+    fn highlight_next(&mut self, ctx: &egui::Context) {
+        let active = self.shared.lock().unwrap().active;
+        let Some(doc_id) = active
+            .filter(|id| self.docs.get(id).is_some_and(|doc| doc.dirty.is_some()))
+            .or_else(|| {
+                self.docs
+                    .iter()
+                    .find(|(_, doc)| doc.dirty.is_some())
+                    .map(|(&id, _)| id)
+            })
+        else {
+            return;
+        };
+        let Some(doc) = self.docs.get_mut(&doc_id) else {
+            return;
+        };
+        let highlighter = ThemeHighlighter::new(&self.theme);
+        let Some(result) = doc.highlight(&self.ps, &highlighter) else {
+            doc.dirty = None;
+            return;
+        };
+
+        let mut shared = self.shared.lock().unwrap();
+        let Some(published) = shared.docs.get_mut(&doc_id) else {
+            return;
+        };
+        // The text changed while we were busy; the edit is already queued
+        if published.epoch != doc.epoch {
+            return;
+        }
+        for (line, spans) in published.lines[result.first..]
+            .iter_mut()
+            .zip(&result.spans)
+        {
+            line.clone_from(spans);
+        }
+        published.generation += 1;
+        drop(shared);
+
+        doc.commit(result);
+        if doc.dirty.is_some() {
+            ctx.request_repaint_after(REPAINT_DELAY);
+        } else {
+            ctx.request_repaint();
+        }
+    }
+}
@@ -0,0 +1,501 @@
+use crate::buffer::Buffer;
+use directories_next::ProjectDirs;
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Typing pauses longer than this start a new undo group.
+const GROUP_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Oldest groups are dropped past this, both in memory and in the journal.
+const MAX_GROUPS: usize = 1000;
+
+/// One replacement in the buffer. Offsets are in characters, like egui's
+/// `TextBuffer`, so they stay valid for non-ASCII text.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Edit {
+    pub at: usize,
+    pub removed: String,
+    pub inserted: String,
+}
+
+impl Edit {
+    pub fn insert(at: usize, text: &str) -> Self {
+        Self {
+            at,
+            removed: String::new(),
+            inserted: text.to_string(),
+        }
+    }
+
+    /// Applies the edit to `text`, returning the char offset after it.
+    pub fn apply(&self, text: &mut Buffer) -> usize {
+        text.replace(
+            self.at..self.at + self.removed.chars().count(),
+            &self.inserted,
+        );
+        self.at + self.inserted.chars().count()
+    }
+
+    /// Reverts the edit on `text`, returning the char offset after it.
+    fn revert(&self, text: &mut Buffer) -> usize {
+        text.replace(
+            self.at..self.at + self.inserted.chars().count(),
+            &self.removed,
+        );
+        self.at + self.removed.chars().count()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Word,
+    Space,
+    Backspace,
+    Delete,
+    Other,
+}
+
+fn classify(edits: &[Edit]) -> Kind {
+    let [edit] = edits else {
+        return Kind::Other;
+    };
+    let mut inserted = edit.inserted.chars();
+    match (
+        inserted.next(),
+        inserted.next(),
+        edit.removed.chars().count(),
+    ) {
+        (Some(c), None, 0) if c.is_alphanumeric() || c == '_' => Kind::Word,
+        (Some(c), None, 0) if c == ' ' || c == '\t' => Kind::Space,
+        (None, None, 1) => Kind::Delete,
+        _ => Kind::Other,
+    }
+}
+
+/// Editor-owned undo/redo stack. Typing is coalesced into word-sized groups,
+/// everything else is recorded as one group per call.
+pub struct History {
+    undo: Vec<Vec<Edit>>,
+    redo: Vec<Vec<Edit>>,
+    /// Kind and end offset of the group that typing may still extend.
+    open: Option<(Kind, usize, Instant)>,
+    /// Length of the undo stack when the document was last saved.
+    saved_at: Option<usize>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            open: None,
+            // A fresh buffer starts out matching what is on disk (or nothing)
+            saved_at: Some(0),
+        }
+    }
+}
+
+impl History {
+    /// Records edits made by the user through the text widget, where
+    /// `cursor` is the char offset the cursor was at before them.
+    pub fn record_typing(&mut self, edits: Vec<Edit>, cursor: Option<usize>) {
+        if edits.is_empty() {
+            return;
+        }
+        let mut kind = classify(&edits);
+        // Backspace deletes before the cursor, Delete at it
+        let (start, end) = (edits[0].at, edits[edits.len() - 1].at);
+        if kind == Kind::Delete && cursor == Some(start + 1) {
+            kind = Kind::Backspace;
+        }
+
+        let merges = match self.open {
+            Some((open, cursor, when)) if when.elapsed() < GROUP_TIMEOUT => match (open, kind) {
+                (Kind::Word, Kind::Word) | (Kind::Word | Kind::Space, Kind::Space) => {
+                    start == cursor
+                }
+                (Kind::Backspace, Kind::Backspace) => start + 1 == cursor,
+                (Kind::Delete, Kind::Delete) => start == cursor,
+                _ => false,
+            },
+            _ => false,
+        };
+
+        let cursor = match kind {
+            Kind::Word | Kind::Space => end + 1,
+            _ => start,
+        };
+        if merges {
+            if let Some(group) = self.undo.last_mut() {
+                group.extend(edits);
+            }
+        } else {
+            self.push(edits);
+        }
+        self.open = (kind != Kind::Other).then(|| (kind, cursor, Instant::now()));
+    }
+
+    /// Records a programmatic edit (indentation, insert character, replace)
+    /// as a single step of its own.
+    pub fn record_step(&mut self, edits: Vec<Edit>) {
+        if !edits.is_empty() {
+            self.push(edits);
+            self.open = None;
+        }
+    }
+
+    fn push(&mut self, edits: Vec<Edit>) {
+        self.redo.clear();
+        if self.saved_at.is_some_and(|saved| saved > self.undo.len()) {
+            self.saved_at = None;
+        }
+        self.undo.push(edits);
+        if self.undo.len() > MAX_GROUPS {
+            self.undo.remove(0);
+            self.saved_at = self.saved_at.and_then(|saved| saved.checked_sub(1));
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Reverts the last group on `text`, returning where the cursor should go.
+    pub fn undo(&mut self, text: &mut Buffer) -> Option<usize> {
+        let group = self.undo.pop()?;
+        self.open = None;
+        let mut cursor = 0;
+        for edit in group.iter().rev() {
+            cursor = edit.revert(text);
+        }
+        self.redo.push(group);
+        Some(cursor)
+    }
+
+    /// Re-applies the last undone group on `text`.
+    pub fn redo(&mut self, text: &mut Buffer) -> Option<usize> {
+        let group = self.redo.pop()?;
+        self.open = None;
+        let mut cursor = 0;
+        for edit in &group {
+            cursor = edit.apply(text);
+        }
+        self.undo.push(group);
+        Some(cursor)
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.open = None;
+        self.saved_at = Some(self.undo.len());
+    }
+
+    /// True when undo/redo brought the buffer back to its saved contents.
+    pub fn is_at_saved(&self) -> bool {
+        self.saved_at == Some(self.undo.len())
+    }
+}
+
+/// `TextBuffer` handed to the editor's `TextEdit` so every change the widget
+/// makes ends up in `edits`. The widget only sees a window of whole lines;
+/// its offsets are relative to the window, `edits` are in document offsets.
+pub struct RecordingBuffer<'a> {
+    text: &'a mut Buffer,
+    /// Kept in step with the buffer's revision for code that can't borrow it.
+    revision: &'a Cell<u64>,
+    window: String,
+    /// Char offset of the window in the document.
+    start: usize,
+    pub edits: Vec<Edit>,
+}
+
+impl<'a> RecordingBuffer<'a> {
+    pub fn new(text: &'a mut Buffer, lines: Range<usize>, revision: &'a Cell<u64>) -> Self {
+        let start = text.line_to_char(lines.start);
+        let window = text
+            .char_slice(start..text.line_to_char(lines.end))
+            .into_owned();
+        Self {
+            text,
+            revision,
+            window,
+            start,
+            edits: Vec::new(),
+        }
+    }
+
+    /// The document chars the widget currently shows.
+    pub fn window(&self) -> Range<usize> {
+        self.start..self.start + self.window.chars().count()
+    }
+
+    /// Applies `edit`, given in window offsets.
+    fn replace(&mut self, mut edit: Edit) {
+        let removed = edit.removed.chars().count();
+        let bytes = byte_index(&self.window, edit.at)..byte_index(&self.window, edit.at + removed);
+        self.window.replace_range(bytes, &edit.inserted);
+        edit.at += self.start;
+        self.text
+            .replace(edit.at..edit.at + removed, &edit.inserted);
+        self.revision.set(self.text.revision());
+        self.edits.push(edit);
+    }
+}
+
+fn byte_index(text: &str, char_index: usize) -> usize {
+    text.char_indices()
+        .nth(char_index)
+        .map_or(text.len(), |(i, _)| i)
+}
+
+impl egui::TextBuffer for RecordingBuffer<'_> {
+    fn is_mutable(&self) -> bool {
+        true
+    }
+
+    fn as_str(&self) -> &str {
+        &self.window
+    }
+
+    fn insert_text(&mut self, text: &str, char_index: usize) -> usize {
+        if text.is_empty() {
+            return 0;
+        }
+        // Pasted CRLFs become plain line breaks like the rest of the buffer
+        let text = &*crate::line_ending::normalize(text);
+        self.replace(Edit::insert(char_index, text));
+        text.chars().count()
+    }
+
+    fn delete_char_range(&mut self, char_range: Range<usize>) {
+        if char_range.is_empty() {
+            return;
+        }
+        let removed = self.char_range(char_range.clone()).to_string();
+        self.replace(Edit {
+            at: char_range.start,
+            removed,
+            inserted: String::new(),
+        });
+    }
+
+    fn clear(&mut self) {
+        self.delete_char_range(0..self.window.chars().count());
+    }
+
+    fn replace_with(&mut self, text: &str) {
+        self.clear();
+        self.insert_text(text, 0);
+    }
+}
+
+/// On-disk copy of a file's history, only valid while the file still has
+/// the contents it had when the journal was written.
+#[derive(Serialize, Deserialize)]
+struct Journal {
+    content_len: usize,
+    content_hash: u64,
+    undo: Vec<Vec<Edit>>,
+    redo: Vec<Vec<Edit>>,
+}
+
+/// 64-bit FNV-1a. Journals outlive the build that wrote them, so this has
+/// to stay the same across Rust releases, unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn journal_path(file: &Path) -> Option<PathBuf> {
+    let proj_dirs = ProjectDirs::from("dev", "nijika", "kokona")?;
+    let file = fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+    Some(proj_dirs.data_dir().join("history").join(format!(
+        "{:016x}.json",
+        fnv1a(file.as_os_str().as_encoded_bytes())
+    )))
+}
+
+impl History {
+    /// Loads the journal for `file` if it was written for `text`.
+    pub fn load(file: &Path, text: &str) -> Self {
+        let journal = journal_path(file)
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|contents| serde_json::from_str::<Journal>(&contents).ok())
+            .filter(|journal| {
+                journal.content_len == text.len() && journal.content_hash == fnv1a(text.as_bytes())
+            });
+        match journal {
+            Some(journal) => Self {
+                saved_at: Some(journal.undo.len()),
+                undo: journal.undo,
+                redo: journal.redo,
+                open: None,
+            },
+            None => Self::default(),
+        }
+    }
+
+    /// Writes the journal for `file`, whose contents on disk are now `text`.
+    pub fn save(&self, file: &Path, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(path) = journal_path(file) else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let journal = Journal {
+            content_len: text.len(),
+            content_hash: fnv1a(text.as_bytes()),
+            undo: self.undo.clone(),
+            redo: self.redo.clone(),
+        };
+        fs::write(path, serde_json::to_string(&journal)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Types `c` at `at` the way the editor would, returning the new cursor.
+    fn type_char(history: &mut History, text: &mut Buffer, at: usize, c: char) -> usize {
+        let edit = Edit::insert(at, &c.to_string());
+        edit.apply(text);
+        history.record_typing(vec![edit], Some(at));
+        at + 1
+    }
+
+    /// Removes the char at `at`, from a cursor at `cursor`.
+    fn remove_char(history: &mut History, text: &mut Buffer, at: usize, cursor: usize) {
+        let edit = Edit {
+            at,
+            removed: text.char_slice(at..at + 1).into_owned(),
+            inserted: String::new(),
+        };
+        edit.apply(text);
+        history.record_typing(vec![edit], Some(cursor));
+    }
+
+    fn type_str(history: &mut History, text: &mut Buffer, mut at: usize, s: &str) -> usize {
+        for c in s.chars() {
+            at = type_char(history, text, at, c);
+        }
+        at
+    }
+
+    #[test]
+    fn words_and_their_trailing_space_are_one_step() {
+        let mut history = History::default();
+        let mut text = Buffer::default();
+        let at = type_str(&mut history, &mut text, 0, "hello ");
+        type_str(&mut history, &mut text, at, "world");
+        assert_eq!(text.to_string(), "hello world");
+
+        history.undo(&mut text);
+        assert_eq!(text.to_string(), "hello ");
+        history.undo(&mut text);
+        assert_eq!(text.to_string(), "");
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn typing_elsewhere_starts_a_new_step() {
+        let mut history = History::default();
+        let mut text = Buffer::from("ab".to_string());
+        type_char(&mut history, &mut text, 2, 'c');
+        type_char(&mut history, &mut text, 0, 'x');
+        assert_eq!(text.to_string(), "xabc");
+
+        history.undo(&mut text);
+        assert_eq!(text.to_string(), "abc");
+    }
+
+    #[test]
+    fn backspace_and_delete_runs_group_separately() {
+        let mut history = History::default();
+        let mut text = Buffer::from("abcdef".to_string());
+        // Backspace twice from the end, then Delete twice from the start
+        remove_char(&mut history, &mut text, 5, 6);
+        remove_char(&mut history, &mut text, 4, 5);
+        remove_char(&mut history, &mut text, 0, 0);
+        remove_char(&mut history, &mut text, 0, 0);
+        assert_eq!(text.to_string(), "cd");
+
+        history.undo(&mut text);
+        assert_eq!(text.to_string(), "abcd");
+        history.undo(&mut text);
+        assert_eq!(text.to_string(), "abcdef");
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn a_lone_delete_is_not_a_backspace() {
+        let mut history = History::default();
+        let mut text = Buffer::from("abc".to_string());
+        remove_char(&mut history, &mut text, 1, 1);
+        // A Backspace right after it doesn't join the Delete
+        remove_char(&mut history, &mut text, 0, 1);
+        assert_eq!(text.to_string(), "c");
+
+        history.undo(&mut text);
+        assert_eq!(text.to_string(), "ac");
+    }
+
+    #[test]
+    fn a_pause_starts_a_new_step() {
+        let mut history = History::default();
+        let mut text = Buffer::default();
+        let at = type_char(&mut history, &mut text, 0, 'a');
+        if let Some((_, _, when)) = &mut history.open {
+            *when = Instant::now() - GROUP_TIMEOUT * 2;
+        }
+        type_char(&mut history, &mut text, at, 'b');
+
+        history.undo(&mut text);
+        assert_eq!(text.to_string(), "a");
+    }
+
+    #[test]
+    fn other_edits_are_steps_of_their_own() {
+        let mut history = History::default();
+        let mut text = Buffer::default();
+        let at = type_char(&mut history, &mut text, 0, 'a');
+        // A paste, then a word right after it
+        let paste = Edit::insert(at, "bc");
+        paste.apply(&mut text);
+        history.record_typing(vec![paste], Some(at));
+        type_char(&mut history, &mut text, at + 2, 'd');
+        assert_eq!(text.to_string(), "abcd");
+
+        history.undo(&mut text);
+        assert_eq!(text.to_string(), "abc");
+        history.undo(&mut text);
+        assert_eq!(text.to_string(), "a");
+    }
+
+    #[test]
+    fn undo_and_redo_track_the_saved_state() {
+        let mut history = History::default();
+        let mut text = Buffer::default();
+        type_char(&mut history, &mut text, 0, 'a');
+        history.mark_saved();
+        let edit = Edit::insert(1, "!");
+        edit.apply(&mut text);
+        history.record_step(vec![edit]);
+        assert!(!history.is_at_saved());
+
+        assert_eq!(history.undo(&mut text), Some(1));
+        assert!(history.is_at_saved());
+        assert_eq!(history.redo(&mut text), Some(2));
+        assert!(!history.is_at_saved());
+    }
+}
@@ -0,0 +1,397 @@
+use crate::buffer::Buffer;
+use crate::history::Edit;
+use eframe::egui::text::{CCursor, CCursorRange};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct IndentSettings {
+    /// Columns per indentation level, and the width a tab is shown with.
+    pub tab_width: usize,
+    pub use_tabs: bool,
+}
+
+impl Default for IndentSettings {
+    fn default() -> Self {
+        Self {
+            tab_width: 4,
+            use_tabs: false,
+        }
+    }
+}
+
+impl IndentSettings {
+    /// Text of one indentation level.
+    pub fn unit(&self) -> String {
+        if self.use_tabs {
+            "\t".to_string()
+        } else {
+            " ".repeat(self.tab_width)
+        }
+    }
+}
+
+/// Edits to make, and where the selection ends up after them.
+pub struct Indented {
+    pub edits: Vec<Edit>,
+    pub selection: CCursorRange,
+}
+
+/// Line range touched by the selection. A selection ending at the very start
+/// of a line doesn't count that line, like in every other editor.
+fn selected_lines(text: &Buffer, selection: &CCursorRange) -> (usize, usize) {
+    let [start, end] = selection.sorted();
+    let rope = text.rope();
+    let first = rope.char_to_line(start.index.min(rope.len_chars()));
+    let mut last = rope.char_to_line(end.index.min(rope.len_chars()));
+    if last > first && rope.line_to_char(last) == end.index {
+        last -= 1;
+    }
+    (first, last)
+}
+
+fn spans_lines(text: &Buffer, selection: &CCursorRange) -> bool {
+    let (first, last) = selected_lines(text, selection);
+    last > first
+}
+
+/// Where `pos` moves to after line-start edits, given as `(line start,
+/// removed chars, inserted chars)` in original coordinates. A position at
+/// the very start of a line stays there, so whole-line selections keep
+/// covering the whole lines.
+fn map_position(pos: usize, changes: &[(usize, usize, usize)]) -> usize {
+    let mut shift = 0isize;
+    for &(at, removed, inserted) in changes {
+        if pos == at {
+            continue;
+        } else if pos >= at + removed {
+            shift += inserted as isize - removed as isize;
+        } else if pos > at {
+            shift += at as isize - pos as isize + inserted as isize;
+        }
+    }
+    (pos as isize + shift) as usize
+}
+
+/// Turns per-line changes into edits, applied bottom up so the offsets of
+/// the lines above stay valid, and maps the selection through them.
+fn finish(
+    text: &Buffer,
+    selection: &CCursorRange,
+    changes: Vec<(usize, usize, String)>,
+) -> Indented {
+    let counts: Vec<_> = changes
+        .iter()
+        .map(|(at, removed, inserted)| (*at, *removed, inserted.chars().count()))
+        .collect();
+    let edits = changes
+        .into_iter()
+        .rev()
+        .map(|(at, removed, inserted)| Edit {
+            at,
+            removed: text.char_slice(at..at + removed).to_string(),
+            inserted,
+        })
+        .collect();
+    let map = |c: CCursor| CCursor::new(map_position(c.index, &counts));
+    Indented {
+        edits,
+        selection: CCursorRange {
+            primary: map(selection.primary),
+            secondary: map(selection.secondary),
+        },
+    }
+}
+
+/// Tab: indents every selected line when the selection spans lines,
+/// otherwise inserts indentation at the cursor.
+pub fn indent(text: &Buffer, selection: &CCursorRange, settings: IndentSettings) -> Indented {
+    if !spans_lines(text, selection) {
+        let [start, end] = selection.sorted();
+        let rope = text.rope();
+        let line_start = rope.line_to_char(rope.char_to_line(start.index));
+        let column = visual_column(
+            &text.char_slice(line_start..start.index),
+            settings.tab_width,
+        );
+        let inserted = if settings.use_tabs {
+            "\t".to_string()
+        } else {
+            " ".repeat(settings.tab_width - column % settings.tab_width)
+        };
+        let cursor = CCursor::new(start.index + inserted.chars().count());
+        return Indented {
+            edits: vec![Edit {
+                at: start.index,
+                removed: text.char_slice(start.index..end.index).to_string(),
+                inserted,
+            }],
+            selection: CCursorRange::one(cursor),
+        };
+    }
+
+    let (first, last) = selected_lines(text, selection);
+    let unit = settings.unit();
+    let rope = text.rope();
+    let changes = (first..=last)
+        .filter(|&line| rope.line(line).chars().any(|c| !c.is_whitespace()))
+        .map(|line| (rope.line_to_char(line), 0, unit.clone()))
+        .collect();
+    finish(text, selection, changes)
+}
+
+/// Shift+Tab: removes one level of indentation from every selected line.
+pub fn outdent(text: &Buffer, selection: &CCursorRange, settings: IndentSettings) -> Indented {
+    let (first, last) = selected_lines(text, selection);
+    let rope = text.rope();
+    let changes = (first..=last)
+        .filter_map(|line| {
+            let mut chars = rope.line(line).chars();
+            let removed = match chars.next() {
+                Some('\t') => 1,
+                Some(' ') => {
+                    let spaces = 1 + chars.take_while(|&c| c == ' ').count();
+                    // Back to the previous tab stop
+                    match spaces % settings.tab_width {
+                        0 => settings.tab_width.min(spaces),
+                        partial => partial,
+                    }
+                }
+                _ => 0,
+            };
+            (removed > 0).then(|| (rope.line_to_char(line), removed, String::new()))
+        })
+        .collect();
+    finish(text, selection, changes)
+}
+
+/// Whether a line ending in `before` opens a block in `syntax`, so the next
+/// line gets one more level.
+fn opens_block(syntax: &str, before: &str) -> bool {
+    match before.trim_end().chars().last() {
+        Some('{' | '[' | '(') => syntax != "Plain Text",
+        Some(':') => matches!(syntax, "Python" | "YAML"),
+        _ => false,
+    }
+}
+
+fn closer_for(opener: char) -> Option<char> {
+    match opener {
+        '{' => Some('}'),
+        '[' => Some(']'),
+        '(' => Some(')'),
+        _ => None,
+    }
+}
+
+/// Enter: a line break that keeps the current line's indentation, one level
+/// deeper after a block opener. Between a bracket pair the closer goes on
+/// its own line below.
+pub fn newline(
+    text: &Buffer,
+    selection: &CCursorRange,
+    settings: IndentSettings,
+    syntax: &str,
+) -> Indented {
+    let [start, end] = selection.sorted();
+    let rope = text.rope();
+    let line = rope.char_to_line(start.index);
+    let line_start = rope.line_to_char(line);
+    let before = text.char_slice(line_start..start.index);
+    let end_line = rope.char_to_line(end.index);
+    let after: String = rope
+        .slice(end.index..rope.line_to_char(end_line + 1))
+        .chars()
+        .collect();
+    let leading: String = before
+        .chars()
+        .take_while(|&c| c == ' ' || c == '\t')
+        .collect();
+
+    let mut inserted = format!("\n{}", leading);
+    let mut cursor_offset = None;
+    if opens_block(syntax, &before) {
+        inserted.push_str(&settings.unit());
+        let closer = before.trim_end().chars().last().and_then(closer_for);
+        if closer.is_some() && closer == after.trim_start().chars().next() {
+            cursor_offset = Some(inserted.chars().count());
+            inserted.push('\n');
+            inserted.push_str(&leading);
+        }
+    }
+    let cursor = start.index + cursor_offset.unwrap_or(inserted.chars().count());
+    Indented {
+        edits: vec![Edit {
+            at: start.index,
+            removed: text.char_slice(start.index..end.index).to_string(),
+            inserted,
+        }],
+        selection: CCursorRange::one(CCursor::new(cursor)),
+    }
+}
+
+/// Width of `text` on screen, with tabs going to the next tab stop.
+pub fn visual_column(text: &str, tab_width: usize) -> usize {
+    text.chars().fold(0, |col, c| {
+        if c == '\t' {
+            col + tab_width - col % tab_width
+        } else {
+            col + 1
+        }
+    })
+}
+
+/// Indentation `text` already uses, going by how its lines start. `None`
+/// when too few lines are indented to tell. Tab-indented files keep
+/// `tab_width`, since tabs don't say how wide they are meant to be.
+pub fn detect(text: &str, tab_width: usize) -> Option<IndentSettings> {
+    let (mut tabs, mut spaces) = (0, 0);
+    // How often the indentation grows by 1..=8 spaces from one line to the next
+    let mut steps = [0usize; 9];
+    let mut previous = 0;
+    for line in text.lines().take(10_000) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with('\t') {
+            tabs += 1;
+            continue;
+        }
+        let width = line.len() - line.trim_start_matches(' ').len();
+        // One space is usually alignment, like ` * ` in block comments
+        if width == 1 {
+            continue;
+        }
+        if width > 0 {
+            spaces += 1;
+        }
+        if width > previous && width - previous <= 8 {
+            steps[width - previous] += 1;
+        }
+        previous = width;
+    }
+
+    if tabs + spaces < 3 {
+        return None;
+    }
+    if tabs > spaces {
+        return Some(IndentSettings {
+            tab_width,
+            use_tabs: true,
+        });
+    }
+    let (step, count) = steps
+        .iter()
+        .enumerate()
+        .skip(2)
+        .fold(
+            (0, 0),
+            |best, (step, &count)| if count > best.1 { (step, count) } else { best },
+        );
+    (count > 0).then_some(IndentSettings {
+        tab_width: step,
+        use_tabs: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPACES: IndentSettings = IndentSettings {
+        tab_width: 4,
+        use_tabs: false,
+    };
+
+    fn range(secondary: usize, primary: usize) -> CCursorRange {
+        CCursorRange {
+            primary: CCursor::new(primary),
+            secondary: CCursor::new(secondary),
+        }
+    }
+
+    /// `text` after the edits, and where the selection ended up.
+    fn apply(text: &str, indented: Indented) -> (String, usize, usize) {
+        let mut buffer = Buffer::from(text.to_string());
+        for edit in &indented.edits {
+            edit.apply(&mut buffer);
+        }
+        let selection = indented.selection;
+        (
+            buffer.to_string(),
+            selection.secondary.index,
+            selection.primary.index,
+        )
+    }
+
+    #[test]
+    fn tab_goes_to_the_next_tab_stop() {
+        let text = Buffer::from("ab".to_string());
+        let indented = indent(&text, &range(1, 1), SPACES);
+        assert_eq!(apply("ab", indented), ("a   b".to_string(), 4, 4));
+    }
+
+    #[test]
+    fn tab_indents_selected_lines_but_not_blank_ones() {
+        let source = "a\n\nb\nc\n";
+        let text = Buffer::from(source.to_string());
+        // From the start of `a` to the start of `c`, which doesn't count
+        let indented = indent(&text, &range(0, 5), SPACES);
+        assert_eq!(
+            apply(source, indented),
+            ("    a\n\n    b\nc\n".to_string(), 0, 13)
+        );
+    }
+
+    #[test]
+    fn shift_tab_goes_back_to_the_previous_tab_stop() {
+        let source = "      a\n\tb\nc\n";
+        let text = Buffer::from(source.to_string());
+        let outdented = outdent(&text, &range(0, 12), SPACES);
+        assert_eq!(apply(source, outdented).0, "    a\nb\nc\n");
+    }
+
+    #[test]
+    fn enter_keeps_indentation_and_opens_blocks() {
+        let source = "    if x {}";
+        let text = Buffer::from(source.to_string());
+        let indented = newline(&text, &range(10, 10), SPACES, "Rust");
+        assert_eq!(
+            apply(source, indented),
+            ("    if x {\n        \n    }".to_string(), 19, 19)
+        );
+
+        let source = "    x: 1";
+        let text = Buffer::from(source.to_string());
+        let indented = newline(&text, &range(8, 8), SPACES, "Plain Text");
+        assert_eq!(apply(source, indented).0, "    x: 1\n    ");
+    }
+
+    #[test]
+    fn visual_column_counts_tabs_to_the_next_stop() {
+        assert_eq!(visual_column("a\tb", 4), 5);
+        assert_eq!(visual_column("\t\t", 8), 16);
+    }
+
+    #[test]
+    fn detects_the_indentation_in_use() {
+        let two = "a {\n  b {\n    c\n  }\n  d\n}\n";
+        assert_eq!(
+            detect(two, 4),
+            Some(IndentSettings {
+                tab_width: 2,
+                use_tabs: false,
+            })
+        );
+        let tabs = "a\n\tb\n\tc\n\t\td\n";
+        assert_eq!(
+            detect(tabs, 8),
+            Some(IndentSettings {
+                tab_width: 8,
+                use_tabs: true,
+            })
+        );
+        // Too little to go by, and one-space alignment doesn't count
+        assert_eq!(detect("a\n  b\n", 4), None);
+        assert_eq!(detect("/*\n * a\n * b\n * c\n */\n", 4), None);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::problems::{self, Problem};
+use crate::tasks::Resolved;
+use eframe::egui;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Output gathered so far, and the problems found in it.
+#[derive(Default)]
+pub struct Output {
+    pub lines: Vec<String>,
+    pub problems: Vec<Problem>,
+}
+
+impl Output {
+    fn push(&mut self, line: &str, stderr: bool, dir: &Path) {
+        let (problem, text) = if stderr {
+            (problems::parse_gcc_line(line, dir), Some(line.to_string()))
+        } else {
+            problems::parse_cargo_line(line, dir)
+        };
+        if let Some(problem) = problem {
+            if !self.problems.contains(&problem) {
+                self.problems.push(problem);
+            }
+        }
+        self.lines.extend(
+            text.iter()
+                .flat_map(|text| text.lines())
+                .map(str::to_string),
+        );
+    }
+}
+
+struct Finished {
+    /// None when waiting for the process failed.
+    status: Option<ExitStatus>,
+    pub elapsed: Duration,
+}
+
+/// A task whose output is captured, running on background threads. Lines
+/// are added as the process writes them; dropping the job stops it.
+pub struct Job {
+    pub name: String,
+    pub command_line: String,
+    pub cwd: PathBuf,
+    pid: u32,
+    started: Instant,
+    output: Arc<Mutex<Output>>,
+    finished: Arc<Mutex<Option<Finished>>>,
+    cancelled: AtomicBool,
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+impl Job {
+    pub fn start(ctx: &egui::Context, task: &Resolved) -> io::Result<Self> {
+        let mut command = task.command();
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // Its own process group, so cancelling reaches whatever it starts
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        let mut child = command.spawn()?;
+        let pid = child.id();
+
+        let output = Arc::new(Mutex::new(Output::default()));
+        let finished = Arc::new(Mutex::new(None));
+        let started = Instant::now();
+        let readers: Vec<_> = [
+            child
+                .stdout
+                .take()
+                .map(|r| Box::new(r) as Box<dyn Read + Send>),
+            child
+                .stderr
+                .take()
+                .map(|r| Box::new(r) as Box<dyn Read + Send>),
+        ]
+        .into_iter()
+        .enumerate()
+        .filter_map(|(idx, reader)| Some((idx == 1, reader?)))
+        .map(|(stderr, reader)| {
+            let output = output.clone();
+            let ctx = ctx.clone();
+            let dir = task.cwd.clone();
+            std::thread::spawn(move || {
+                for line in BufReader::new(reader).split(b'\n') {
+                    let Ok(line) = line else {
+                        break;
+                    };
+                    let line = String::from_utf8_lossy(&line);
+                    output
+                        .lock()
+                        .unwrap()
+                        .push(line.trim_end_matches('\r'), stderr, &dir);
+                    ctx.request_repaint();
+                }
+            })
+        })
+        .collect();
+
+        let done = finished.clone();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            let status = child.wait();
+            // Let the readers drain what is left before calling it done
+            for reader in readers {
+                let _ = reader.join();
+            }
+            *done.lock().unwrap() = Some(Finished {
+                status: status.ok(),
+                elapsed: started.elapsed(),
+            });
+            ctx.request_repaint();
+        });
+
+        Ok(Self {
+            name: task.name.clone(),
+            command_line: task.command_line(),
+            cwd: task.cwd.clone(),
+            pid,
+            started,
+            output,
+            finished,
+            cancelled: AtomicBool::new(false),
+        })
+    }
+
+    /// Kills the process and everything it started.
+    pub fn cancel(&self) {
+        if !self.is_running() || self.cancelled.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = kill_tree(self.pid) {
+            println!("Failed to stop {}: {}", self.name, e);
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.finished.lock().unwrap().is_none()
+    }
+
+    pub fn was_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.finished
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or_else(|| self.started.elapsed(), |f| f.elapsed)
+    }
+
+    /// The exit status, once the process is done.
+    pub fn status(&self) -> Option<ExitStatus> {
+        self.finished
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|f| f.status)
+    }
+
+    pub fn output(&self) -> MutexGuard<'_, Output> {
+        self.output.lock().unwrap()
+    }
+}
+
+#[cfg(unix)]
+fn kill_tree(pid: u32) -> io::Result<()> {
+    // The negative pid names the process group
+    Command::new("kill")
+        .args(["-KILL", "--"])
+        .arg(format!("-{}", pid))
+        .status()?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn kill_tree(pid: u32) -> io::Result<()> {
+    Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &pid.to_string()])
+        .status()?;
+    Ok(())
+}
@@ -0,0 +1,105 @@
+use std::borrow::Cow;
+
+/// The line break a document is saved with. In memory every line ends in a
+/// plain `\n`, which is all the buffer and the gutter count.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    pub const ALL: [Self; 3] = [Self::Lf, Self::CrLf, Self::Cr];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Lf => "\n",
+            Self::CrLf => "\r\n",
+            Self::Cr => "\r",
+        }
+    }
+
+    /// Shown in the status bar.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Lf => "LF",
+            Self::CrLf => "CRLF",
+            Self::Cr => "CR",
+        }
+    }
+
+    /// The most common line ending in `text`, and whether it has more than
+    /// one kind. Text without line breaks counts as LF.
+    pub fn detect(text: &str) -> (Self, bool) {
+        let (mut lf, mut crlf, mut cr) = (0, 0, 0);
+        let mut bytes = text.bytes().peekable();
+        while let Some(b) = bytes.next() {
+            match b {
+                b'\r' if bytes.peek() == Some(&b'\n') => {
+                    bytes.next();
+                    crlf += 1;
+                }
+                b'\r' => cr += 1,
+                b'\n' => lf += 1,
+                _ => {}
+            }
+        }
+        let kinds = [lf, crlf, cr].iter().filter(|&&count| count > 0).count();
+        let dominant = if crlf > lf && crlf >= cr {
+            Self::CrLf
+        } else if cr > lf && cr > crlf {
+            Self::Cr
+        } else {
+            Self::Lf
+        };
+        (dominant, kinds > 1)
+    }
+
+    /// `text` with this line ending in place of every `\n`.
+    pub fn apply(self, text: &str) -> Cow<'_, str> {
+        match self {
+            Self::Lf => Cow::Borrowed(text),
+            _ => Cow::Owned(text.replace('\n', self.as_str())),
+        }
+    }
+}
+
+/// `text` with every CRLF and lone CR turned into `\n`.
+pub fn normalize(text: &str) -> Cow<'_, str> {
+    if !text.contains('\r') {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_the_most_common_line_ending() {
+        assert_eq!(
+            LineEnding::detect("a\r\nb\r\nc\n"),
+            (LineEnding::CrLf, true)
+        );
+        assert_eq!(LineEnding::detect("a\rb\r"), (LineEnding::Cr, false));
+        assert_eq!(LineEnding::detect("a\nb\r\n"), (LineEnding::Lf, true));
+        assert_eq!(LineEnding::detect("no breaks"), (LineEnding::Lf, false));
+    }
+
+    #[test]
+    fn normalizes_every_break_to_lf() {
+        assert_eq!(normalize("a\r\nb\rc\n"), "a\nb\nc\n");
+        assert!(matches!(normalize("a\nb"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn apply_undoes_normalize() {
+        for ending in LineEnding::ALL {
+            let text = format!("a{0}b{0}", ending.as_str());
+            assert_eq!(ending.apply(&normalize(&text)), text);
+        }
+    }
+}
@@ -0,0 +1,1024 @@
+use crate::buffer::{Buffer, Change};
+use crate::document::Tabs;
+use crate::history::Edit;
+use eframe::egui;
+use ropey::Rope;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+/// Writes one JSON-RPC message with its `Content-Length` header.
+pub fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+/// Reads one JSON-RPC message, `None` at the end of the stream.
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; length.unwrap_or_default()];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// A place in a document the way LSP counts it: zero-based line, and
+/// UTF-16 code units into that line.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Position {
+    pub line: u32,
+    pub character: u32,
+}
+
+impl Position {
+    pub fn from_char(rope: &Rope, char_idx: usize) -> Self {
+        let char_idx = char_idx.min(rope.len_chars());
+        let line = rope.char_to_line(char_idx);
+        let line_start = rope.line_to_char(line);
+        Self {
+            line: line as u32,
+            character: (rope.char_to_utf16_cu(char_idx) - rope.char_to_utf16_cu(line_start)) as u32,
+        }
+    }
+
+    /// The char offset, clamped to the text and to the end of the line.
+    pub fn to_char(self, rope: &Rope) -> usize {
+        let line = (self.line as usize).min(rope.len_lines() - 1);
+        let start = rope.line_to_char(line);
+        let mut end = rope.line_to_char(line + 1);
+        if end > start && rope.char(end - 1) == '\n' {
+            end -= 1;
+        }
+        let target = rope.char_to_utf16_cu(start) + self.character as usize;
+        rope.utf16_cu_to_char(target.min(rope.char_to_utf16_cu(end)))
+    }
+
+    fn to_json(self) -> Value {
+        json!({ "line": self.line, "character": self.character })
+    }
+
+    fn from_json(value: &Value) -> Self {
+        Self {
+            line: value["line"].as_u64().unwrap_or_default() as u32,
+            character: value["character"].as_u64().unwrap_or_default() as u32,
+        }
+    }
+}
+
+fn range_from_json(value: &Value) -> (Position, Position) {
+    (
+        Position::from_json(&value["start"]),
+        Position::from_json(&value["end"]),
+    )
+}
+
+pub fn path_to_uri(path: &Path) -> Option<String> {
+    url::Url::from_file_path(path).ok().map(String::from)
+}
+
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    url::Url::parse(uri).ok()?.to_file_path().ok()
+}
+
+/// The LSP language id for a syntect syntax name.
+fn language_id(syntax: &str) -> String {
+    match syntax {
+        "C++" => "cpp".to_string(),
+        "C#" => "csharp".to_string(),
+        "Bourne Again Shell (bash)" => "shellscript".to_string(),
+        _ => syntax.to_lowercase().replace(' ', ""),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub start: Position,
+    pub end: Position,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn char_range(&self, rope: &Rope) -> Range<usize> {
+        let start = self.start.to_char(rope);
+        start..self.end.to_char(rope).max(start)
+    }
+
+    fn from_json(value: &Value) -> Self {
+        let (start, end) = range_from_json(&value["range"]);
+        let severity = match value["severity"].as_u64() {
+            Some(2) => Severity::Warning,
+            Some(3) => Severity::Information,
+            Some(4) => Severity::Hint,
+            _ => Severity::Error,
+        };
+        let mut message = value["message"].as_str().unwrap_or_default().to_string();
+        if let Some(source) = value["source"].as_str() {
+            message = format!("{}: {}", source, message);
+        }
+        Self {
+            start,
+            end,
+            severity,
+            message,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CompletionItem {
+    pub label: String,
+    pub detail: Option<String>,
+    /// What goes in place of the word being completed.
+    pub insert: String,
+    /// What the typed prefix is matched against.
+    pub filter: String,
+}
+
+impl CompletionItem {
+    fn from_json(value: &Value) -> Self {
+        let label = value["label"].as_str().unwrap_or_default().to_string();
+        let insert = value["textEdit"]["newText"]
+            .as_str()
+            .or(value["insertText"].as_str())
+            .unwrap_or(&label)
+            .to_string();
+        Self {
+            filter: value["filterText"].as_str().unwrap_or(&label).to_string(),
+            detail: value["detail"].as_str().map(str::to_string),
+            insert,
+            label,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TextEdit {
+    pub start: Position,
+    pub end: Position,
+    pub new_text: String,
+}
+
+impl TextEdit {
+    fn from_json(value: &Value) -> Self {
+        let (start, end) = range_from_json(&value["range"]);
+        Self {
+            start,
+            end,
+            new_text: value["newText"].as_str().unwrap_or_default().to_string(),
+        }
+    }
+}
+
+/// `edits` as buffer edits, last first so each one's offsets still hold
+/// when it is applied.
+pub fn buffer_edits(text: &Buffer, edits: &[TextEdit]) -> Vec<Edit> {
+    let rope = text.rope();
+    let mut edits: Vec<(Range<usize>, &str)> = edits
+        .iter()
+        .map(|edit| {
+            let start = edit.start.to_char(rope);
+            (
+                start..edit.end.to_char(rope).max(start),
+                edit.new_text.as_str(),
+            )
+        })
+        .collect();
+    edits.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+    edits
+        .into_iter()
+        .map(|(range, inserted)| Edit {
+            at: range.start,
+            removed: text.char_slice(range).to_string(),
+            inserted: inserted.to_string(),
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub path: PathBuf,
+    pub position: Position,
+}
+
+/// Answers from servers, for the editor to act on.
+#[derive(Debug)]
+pub enum Reply {
+    Completion {
+        doc_id: u64,
+        /// Char offset of the word being completed.
+        at: usize,
+        items: Vec<CompletionItem>,
+    },
+    Hover {
+        doc_id: u64,
+        at: usize,
+        text: String,
+    },
+    Definition(Vec<Location>),
+    Rename(Vec<(PathBuf, Vec<TextEdit>)>),
+    Failed(String),
+}
+
+/// What a request in flight was for.
+enum Pending {
+    Initialize,
+    Completion { doc_id: u64, at: usize },
+    Hover { doc_id: u64, at: usize },
+    Definition,
+    Rename,
+}
+
+/// A document as the server last heard of it.
+struct Synced {
+    uri: String,
+    version: i32,
+    revision: u64,
+    rope: Rope,
+    modified: bool,
+}
+
+/// One running server, talking JSON-RPC over its stdin and stdout.
+struct LanguageServer {
+    command: String,
+    child: Child,
+    stdin: ChildStdin,
+    incoming: Receiver<Value>,
+    next_id: u64,
+    pending: HashMap<u64, Pending>,
+    /// Set once the server answered `initialize`; until then messages wait
+    /// in `queued`.
+    ready: bool,
+    queued: Vec<Value>,
+    /// 1 sends the whole text on every change, 2 only what changed.
+    sync_kind: u64,
+    trigger_characters: Vec<String>,
+    documents: HashMap<u64, Synced>,
+}
+
+impl LanguageServer {
+    fn start(command: &str, root: Option<&Path>, ctx: &egui::Context) -> io::Result<Self> {
+        let mut parts = command.split_whitespace();
+        let program = parts
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+        let mut process = Command::new(program);
+        process
+            .args(parts)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        if let Some(root) = root {
+            process.current_dir(root);
+        }
+        let mut child = process.spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let (sender, incoming) = mpsc::channel();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            while let Ok(Some(message)) = read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+                ctx.request_repaint();
+            }
+        });
+
+        let mut server = Self {
+            command: command.to_string(),
+            child,
+            stdin,
+            incoming,
+            next_id: 0,
+            pending: HashMap::new(),
+            ready: false,
+            queued: Vec::new(),
+            sync_kind: 1,
+            trigger_characters: Vec::new(),
+            documents: HashMap::new(),
+        };
+        let root_uri = root.and_then(path_to_uri);
+        let folders = match (&root_uri, root) {
+            (Some(uri), Some(root)) => json!([{
+                "uri": uri,
+                "name": root.file_name().map(|n| n.to_string_lossy().into_owned()),
+            }]),
+            _ => Value::Null,
+        };
+        let params = json!({
+            "processId": std::process::id(),
+            "clientInfo": {
+                "name": "kokona",
+                "version": crate::consts::versioninfo::VERSION,
+            },
+            "rootUri": root_uri,
+            "workspaceFolders": folders,
+            "capabilities": {
+                "general": { "positionEncodings": ["utf-16"] },
+                "textDocument": {
+                    "synchronization": { "didSave": true },
+                    "publishDiagnostics": {},
+                    "completion": { "completionItem": { "snippetSupport": false } },
+                    "hover": { "contentFormat": ["plaintext", "markdown"] },
+                    "definition": { "linkSupport": true },
+                    "rename": {},
+                },
+            },
+        });
+        let id = server.next_id();
+        server.pending.insert(id, Pending::Initialize);
+        write_message(
+            &mut server.stdin,
+            &json!({ "jsonrpc": "2.0", "id": id, "method": "initialize", "params": params }),
+        )?;
+        Ok(server)
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Sends `message` now, or once the server is initialized.
+    fn send(&mut self, message: Value) {
+        if !self.ready {
+            self.queued.push(message);
+            return;
+        }
+        if let Err(e) = write_message(&mut self.stdin, &message) {
+            println!("Failed to write to {}: {}", self.command, e);
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    fn request(&mut self, method: &str, params: Value, pending: Pending) {
+        let id = self.next_id();
+        self.pending.insert(id, pending);
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+    }
+
+    /// Tells the server about `text` if it changed since it last heard, as
+    /// only the changed span where the server allows it.
+    fn sync(&mut self, doc_id: u64, uri: &str, language: &str, text: &Buffer, modified: bool) {
+        if self
+            .documents
+            .get(&doc_id)
+            .is_some_and(|synced| synced.uri != uri)
+        {
+            self.close(doc_id);
+        }
+        let Some(synced) = self.documents.get_mut(&doc_id) else {
+            self.notify(
+                "textDocument/didOpen",
+                json!({ "textDocument": {
+                    "uri": uri,
+                    "languageId": language_id(language),
+                    "version": 0,
+                    "text": text.to_string(),
+                } }),
+            );
+            self.documents.insert(
+                doc_id,
+                Synced {
+                    uri: uri.to_string(),
+                    version: 0,
+                    revision: text.revision(),
+                    rope: text.rope().clone(),
+                    modified,
+                },
+            );
+            return;
+        };
+
+        let change = match text.changes_since(synced.revision) {
+            Change::Unchanged => None,
+            Change::Edited(delta) if self.sync_kind == 2 => {
+                let old = &synced.rope;
+                let start = old.byte_to_char(delta.start);
+                let end = old.byte_to_char(delta.start + delta.old_len);
+                Some(json!({
+                    "range": {
+                        "start": Position::from_char(old, start).to_json(),
+                        "end": Position::from_char(old, end).to_json(),
+                    },
+                    "text": text.byte_slice(delta.start..delta.start + delta.new_len),
+                }))
+            }
+            _ => Some(json!({ "text": text.to_string() })),
+        };
+        let saved = synced.modified && !modified;
+        synced.modified = modified;
+        if let Some(change) = change {
+            synced.version += 1;
+            synced.revision = text.revision();
+            synced.rope = text.rope().clone();
+            let params = json!({
+                "textDocument": { "uri": uri, "version": synced.version },
+                "contentChanges": [change],
+            });
+            self.notify("textDocument/didChange", params);
+        }
+        if saved {
+            self.notify(
+                "textDocument/didSave",
+                json!({ "textDocument": { "uri": uri } }),
+            );
+        }
+    }
+
+    fn close(&mut self, doc_id: u64) {
+        if let Some(synced) = self.documents.remove(&doc_id) {
+            self.notify(
+                "textDocument/didClose",
+                json!({ "textDocument": { "uri": synced.uri } }),
+            );
+        }
+    }
+
+    /// Handles what the server sent since the last call.
+    fn receive(&mut self, diagnostics: &mut HashMap<PathBuf, Vec<Diagnostic>>) -> Vec<Reply> {
+        let mut replies = Vec::new();
+        while let Ok(message) = self.incoming.try_recv() {
+            let method = message["method"].as_str();
+            match (method, message.get("id")) {
+                // A request from the server. Nothing it asks for is
+                // supported, but it gets an answer so it doesn't wait
+                (Some(method), Some(id)) => {
+                    let result = match method {
+                        "workspace/configuration" => {
+                            let items = message["params"]["items"].as_array().map_or(0, Vec::len);
+                            Value::Array(vec![Value::Null; items])
+                        }
+                        _ => Value::Null,
+                    };
+                    let reply = json!({ "jsonrpc": "2.0", "id": id, "result": result });
+                    if let Err(e) = write_message(&mut self.stdin, &reply) {
+                        println!("Failed to write to {}: {}", self.command, e);
+                    }
+                }
+                (Some("textDocument/publishDiagnostics"), None) => {
+                    let params = &message["params"];
+                    if let Some(path) = params["uri"].as_str().and_then(uri_to_path) {
+                        let list = params["diagnostics"]
+                            .as_array()
+                            .map(|list| list.iter().map(Diagnostic::from_json).collect())
+                            .unwrap_or_default();
+                        diagnostics.insert(path, list);
+                    }
+                }
+                (Some(_), None) => {}
+                (None, Some(id)) => {
+                    let Some(pending) = id.as_u64().and_then(|id| self.pending.remove(&id)) else {
+                        continue;
+                    };
+                    if let Some(error) = message.get("error") {
+                        let text = error["message"].as_str().unwrap_or("request failed");
+                        replies.push(Reply::Failed(text.to_string()));
+                        continue;
+                    }
+                    replies.extend(self.handle_result(pending, &message["result"]));
+                }
+                (None, None) => {}
+            }
+        }
+        replies
+    }
+
+    fn handle_result(&mut self, pending: Pending, result: &Value) -> Option<Reply> {
+        match pending {
+            Pending::Initialize => {
+                let capabilities = &result["capabilities"];
+                let sync = &capabilities["textDocumentSync"];
+                self.sync_kind = sync.as_u64().or(sync["change"].as_u64()).unwrap_or(1);
+                self.trigger_characters = capabilities["completionProvider"]["triggerCharacters"]
+                    .as_array()
+                    .map(|chars| {
+                        chars
+                            .iter()
+                            .filter_map(|c| c.as_str().map(str::to_string))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                self.ready = true;
+                self.notify("initialized", json!({}));
+                for message in std::mem::take(&mut self.queued) {
+                    self.send(message);
+                }
+                None
+            }
+            Pending::Completion { doc_id, at } => {
+                let list = result.get("items").unwrap_or(result);
+                let items = list
+                    .as_array()
+                    .map(|items| items.iter().map(CompletionItem::from_json).collect())
+                    .unwrap_or_default();
+                Some(Reply::Completion { doc_id, at, items })
+            }
+            Pending::Hover { doc_id, at } => {
+                let text = hover_text(&result["contents"]);
+                Some(Reply::Hover { doc_id, at, text })
+            }
+            Pending::Definition => {
+                let list = match result {
+                    Value::Array(list) => list.clone(),
+                    Value::Null => Vec::new(),
+                    single => vec![single.clone()],
+                };
+                let locations = list
+                    .iter()
+                    .filter_map(|location| {
+                        // Either a Location or a LocationLink
+                        let uri = location["uri"]
+                            .as_str()
+                            .or(location["targetUri"].as_str())?;
+                        let range = if location.get("targetSelectionRange").is_some() {
+                            &location["targetSelectionRange"]
+                        } else {
+                            &location["range"]
+                        };
+                        Some(Location {
+                            path: uri_to_path(uri)?,
+                            position: range_from_json(range).0,
+                        })
+                    })
+                    .collect();
+                Some(Reply::Definition(locations))
+            }
+            Pending::Rename => Some(Reply::Rename(workspace_edit(result))),
+        }
+    }
+}
+
+impl Drop for LanguageServer {
+    fn drop(&mut self) {
+        if self.ready {
+            let id = self.next_id();
+            let _ = write_message(
+                &mut self.stdin,
+                &json!({ "jsonrpc": "2.0", "id": id, "method": "shutdown" }),
+            );
+            let _ = write_message(
+                &mut self.stdin,
+                &json!({ "jsonrpc": "2.0", "method": "exit" }),
+            );
+        }
+        // Give it a moment to exit on its own before it is killed
+        let deadline = Instant::now() + Duration::from_millis(300);
+        while Instant::now() < deadline {
+            if let Ok(Some(_)) = self.child.try_wait() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Plain text of hover contents, whichever of the shapes LSP allows.
+fn hover_text(contents: &Value) -> String {
+    match contents {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .map(hover_text)
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        Value::Object(object) => object
+            .get("value")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        _ => String::new(),
+    }
+}
+
+/// The text edits of a `WorkspaceEdit`, by file.
+fn workspace_edit(result: &Value) -> Vec<(PathBuf, Vec<TextEdit>)> {
+    let mut files = Vec::new();
+    if let Some(changes) = result["changes"].as_object() {
+        for (uri, edits) in changes {
+            if let (Some(path), Some(edits)) = (uri_to_path(uri), edits.as_array()) {
+                files.push((path, edits.iter().map(TextEdit::from_json).collect()));
+            }
+        }
+    }
+    if let Some(changes) = result["documentChanges"].as_array() {
+        for change in changes {
+            let uri = change["textDocument"]["uri"].as_str();
+            if let (Some(path), Some(edits)) =
+                (uri.and_then(uri_to_path), change["edits"].as_array())
+            {
+                files.push((path, edits.iter().map(TextEdit::from_json).collect()));
+            }
+        }
+    }
+    files
+}
+
+/// Runs a language server for each language that has one set up, keeps
+/// them in step with the open documents, and collects what they report.
+#[derive(Default)]
+pub struct LspClient {
+    /// Keyed by syntax name.
+    servers: HashMap<String, LanguageServer>,
+    /// Commands that failed to start, so they aren't retried every frame.
+    failed: HashSet<String>,
+    diagnostics: HashMap<PathBuf, Vec<Diagnostic>>,
+    replies: Vec<Reply>,
+}
+
+impl LspClient {
+    /// Starts and stops servers as documents come and go, sends them edits,
+    /// and picks up their messages. `commands` maps syntax names to server
+    /// command lines.
+    pub fn update(
+        &mut self,
+        ctx: &egui::Context,
+        tabs: &Tabs,
+        commands: &BTreeMap<String, String>,
+        root: Option<&Path>,
+    ) {
+        let failed = &mut self.failed;
+        self.servers.retain(|language, server| {
+            if let Ok(Some(status)) = server.child.try_wait() {
+                println!("Language server {} exited: {}", server.command, status);
+                failed.insert(server.command.clone());
+                return false;
+            }
+            commands
+                .get(language)
+                .is_some_and(|command| *command == server.command)
+        });
+
+        for doc in &tabs.documents {
+            let (Some(path), Some(language)) = (&doc.path, &doc.syntax) else {
+                continue;
+            };
+            let Some(command) = commands.get(language).filter(|c| !c.trim().is_empty()) else {
+                continue;
+            };
+            if !self.servers.contains_key(language) {
+                if self.failed.contains(command) {
+                    continue;
+                }
+                let root = root.or(path.parent());
+                match LanguageServer::start(command, root, ctx) {
+                    Ok(server) => {
+                        self.servers.insert(language.clone(), server);
+                    }
+                    Err(e) => {
+                        println!("Failed to start language server {}: {}", command, e);
+                        self.failed.insert(command.clone());
+                        continue;
+                    }
+                }
+            }
+            let Some(uri) = path_to_uri(path) else {
+                continue;
+            };
+            if let Some(server) = self.servers.get_mut(language) {
+                server.sync(doc.id, &uri, language, &doc.text, doc.modified);
+            }
+        }
+
+        for (language, server) in &mut self.servers {
+            let gone: Vec<u64> = server
+                .documents
+                .keys()
+                .copied()
+                .filter(|&id| {
+                    tabs.index_of(id).is_none_or(|idx| {
+                        let doc = &tabs.documents[idx];
+                        doc.path.is_none() || doc.syntax.as_ref() != Some(language)
+                    })
+                })
+                .collect();
+            for id in gone {
+                server.close(id);
+            }
+            self.replies.extend(server.receive(&mut self.diagnostics));
+        }
+    }
+
+    /// The server for `doc`, told about the document's latest text.
+    fn server_for(
+        &mut self,
+        doc: &crate::document::Document,
+    ) -> Option<(&mut LanguageServer, String, Rope)> {
+        let language = doc.syntax.as_ref()?;
+        let uri = path_to_uri(doc.path.as_ref()?)?;
+        let server = self.servers.get_mut(language)?;
+        server.sync(doc.id, &uri, language, &doc.text, doc.modified);
+        Some((server, uri, doc.text.rope().clone()))
+    }
+
+    pub fn has_server(&self, doc: &crate::document::Document) -> bool {
+        doc.syntax
+            .as_ref()
+            .is_some_and(|language| self.servers.contains_key(language))
+    }
+
+    /// Characters that open the completion list when typed in `doc`.
+    pub fn trigger_characters(&self, doc: &crate::document::Document) -> &[String] {
+        doc.syntax
+            .as_ref()
+            .and_then(|language| self.servers.get(language))
+            .map_or(&[], |server| &server.trigger_characters)
+    }
+
+    /// Asks for completions at `cursor`; `at` is where the word being
+    /// completed starts.
+    pub fn completion(&mut self, doc: &crate::document::Document, at: usize, cursor: usize) {
+        let doc_id = doc.id;
+        if let Some((server, uri, rope)) = self.server_for(doc) {
+            let params = json!({
+                "textDocument": { "uri": uri },
+                "position": Position::from_char(&rope, cursor).to_json(),
+            });
+            server.request(
+                "textDocument/completion",
+                params,
+                Pending::Completion { doc_id, at },
+            );
+        }
+    }
+
+    pub fn hover(&mut self, doc: &crate::document::Document, at: usize) {
+        let doc_id = doc.id;
+        if let Some((server, uri, rope)) = self.server_for(doc) {
+            let params = json!({
+                "textDocument": { "uri": uri },
+                "position": Position::from_char(&rope, at).to_json(),
+            });
+            server.request("textDocument/hover", params, Pending::Hover { doc_id, at });
+        }
+    }
+
+    pub fn definition(&mut self, doc: &crate::document::Document, at: usize) {
+        if let Some((server, uri, rope)) = self.server_for(doc) {
+            let params = json!({
+                "textDocument": { "uri": uri },
+                "position": Position::from_char(&rope, at).to_json(),
+            });
+            server.request("textDocument/definition", params, Pending::Definition);
+        }
+    }
+
+    pub fn rename(&mut self, doc: &crate::document::Document, at: usize, new_name: &str) {
+        if let Some((server, uri, rope)) = self.server_for(doc) {
+            let params = json!({
+                "textDocument": { "uri": uri },
+                "position": Position::from_char(&rope, at).to_json(),
+                "newName": new_name,
+            });
+            server.request("textDocument/rename", params, Pending::Rename);
+        }
+    }
+
+    pub fn diagnostics(&self, path: &Path) -> &[Diagnostic] {
+        self.diagnostics.get(path).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn take_replies(&mut self) -> Vec<Reply> {
+        std::mem::take(&mut self.replies)
+    }
+
+    /// Stops every server, for when Kokona exits.
+    pub fn shutdown(&mut self) {
+        self.servers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Edit;
+    use std::time::{Duration, Instant};
+
+    /// The fake server from `examples/`, which `cargo test` builds next to
+    /// the test binary.
+    fn fake_server() -> String {
+        let exe = std::env::current_exe().unwrap();
+        let dir = exe.parent().unwrap().parent().unwrap().join("examples");
+        dir.join(format!("fake_lsp_server{}", std::env::consts::EXE_SUFFIX))
+            .display()
+            .to_string()
+    }
+
+    struct Fixture {
+        ctx: egui::Context,
+        client: LspClient,
+        tabs: Tabs,
+        commands: BTreeMap<String, String>,
+        dir: PathBuf,
+    }
+
+    impl Fixture {
+        fn new(name: &str, text: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("kokona-lsp-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("main.rs");
+            std::fs::write(&path, text).unwrap();
+            let mut tabs = Tabs::default();
+            tabs.open(&path).unwrap();
+            tabs.documents[0].syntax = Some("Rust".to_string());
+            let commands = BTreeMap::from([("Rust".to_string(), fake_server())]);
+            Self {
+                ctx: egui::Context::default(),
+                client: LspClient::default(),
+                tabs,
+                commands,
+                dir,
+            }
+        }
+
+        fn doc(&self) -> &crate::document::Document {
+            &self.tabs.documents[0]
+        }
+
+        fn update(&mut self) {
+            self.client
+                .update(&self.ctx, &self.tabs, &self.commands, Some(&self.dir));
+        }
+
+        /// Updates until `found` picks something out of the replies.
+        fn wait_for<T>(&mut self, mut found: impl FnMut(Reply) -> Option<T>) -> T {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while Instant::now() < deadline {
+                self.update();
+                for reply in self.client.take_replies() {
+                    if let Some(value) = found(reply) {
+                        return value;
+                    }
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            panic!("no reply from the fake language server");
+        }
+
+        fn wait_for_diagnostics(&mut self, count: usize) -> Vec<Diagnostic> {
+            let path = self.doc().path.clone().unwrap();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while Instant::now() < deadline {
+                self.update();
+                let diagnostics = self.client.diagnostics(&path);
+                if diagnostics.len() == count {
+                    return diagnostics.to_vec();
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            panic!("expected {} diagnostics", count);
+        }
+
+        /// The text as the server has it, which it answers hovers with.
+        fn server_text(&mut self) -> String {
+            let doc = &self.tabs.documents[0];
+            self.client.hover(doc, 0);
+            self.wait_for(|reply| match reply {
+                Reply::Hover { text, .. } => Some(text),
+                _ => None,
+            })
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            self.client.shutdown();
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn incremental_sync_and_diagnostics() {
+        let mut fixture = Fixture::new("sync", "fn main() {\n    bad();\n}\n");
+        let diagnostics = fixture.wait_for_diagnostics(1);
+        let rope = fixture.doc().text.rope().clone();
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(
+            rope.slice(diagnostics[0].char_range(&rope)).to_string(),
+            "bad"
+        );
+
+        // Characters outside the BMP take two UTF-16 units on the wire
+        let doc = &mut fixture.tabs.documents[0];
+        doc.edit(vec![Edit {
+            at: 16,
+            removed: String::new(),
+            inserted: "\"😀é\"; ".to_string(),
+        }]);
+        let expected = doc.text.to_string();
+        fixture.update();
+        assert_eq!(fixture.server_text(), expected);
+
+        let diagnostics = fixture.wait_for_diagnostics(1);
+        let rope = fixture.doc().text.rope().clone();
+        assert_eq!(
+            rope.slice(diagnostics[0].char_range(&rope)).to_string(),
+            "bad"
+        );
+
+        let doc = &mut fixture.tabs.documents[0];
+        let at = doc.text.to_string().find("bad").unwrap();
+        let at = doc.text.byte_to_char(at);
+        doc.edit(vec![Edit {
+            at,
+            removed: "bad".to_string(),
+            inserted: "good".to_string(),
+        }]);
+        let expected = doc.text.to_string();
+        fixture.wait_for_diagnostics(0);
+        assert_eq!(fixture.server_text(), expected);
+    }
+
+    #[test]
+    fn completion_definition_and_rename() {
+        let mut fixture = Fixture::new("requests", "let value = 1;\nvalue.\n");
+        fixture.update();
+        let doc = &fixture.tabs.documents[0];
+        fixture.client.completion(doc, 21, 21);
+        let items = fixture.wait_for(|reply| match reply {
+            Reply::Completion { at: 21, items, .. } => Some(items),
+            _ => None,
+        });
+        let labels: Vec<&str> = items.iter().map(|item| item.label.as_str()).collect();
+        assert_eq!(labels, ["alpha", "beta"]);
+        assert_eq!(items[1].insert, "beta()");
+        assert_eq!(
+            fixture.client.trigger_characters(fixture.doc()),
+            [".".to_string()]
+        );
+
+        let doc = &fixture.tabs.documents[0];
+        fixture.client.definition(doc, 17);
+        let locations = fixture.wait_for(|reply| match reply {
+            Reply::Definition(locations) => Some(locations),
+            _ => None,
+        });
+        assert_eq!(locations.len(), 1);
+        assert_eq!(
+            locations[0].path,
+            std::fs::canonicalize(fixture.doc().path.as_ref().unwrap()).unwrap()
+        );
+        assert_eq!(
+            locations[0].position,
+            Position {
+                line: 0,
+                character: 0
+            }
+        );
+
+        let doc = &fixture.tabs.documents[0];
+        fixture.client.rename(doc, 6, "answer");
+        let changes = fixture.wait_for(|reply| match reply {
+            Reply::Rename(changes) => Some(changes),
+            _ => None,
+        });
+        assert_eq!(changes.len(), 1);
+        let doc = &mut fixture.tabs.documents[0];
+        let edits = buffer_edits(&doc.text, &changes[0].1);
+        doc.edit(edits);
+        assert_eq!(doc.text.to_string(), "let answer = 1;\nvalue.\n");
+    }
+}
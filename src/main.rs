@@ -1,46 +1,130 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod atomic;
+mod autosave;
+mod buffer;
 pub mod consts;
+mod document;
+mod editorconfig;
+mod encoding;
+mod find_in_files;
+mod highlight;
+mod history;
+mod indent;
+mod job;
+mod line_ending;
+mod lsp;
+mod problems;
+mod project;
+mod recent;
+mod session;
+mod symbols;
+mod tasks;
+mod terminal;
 mod views;
+mod watcher;
 use clap::Parser;
 use discord_rich_presence::{DiscordIpc, DiscordIpcClient};
+use document::Tabs;
 use eframe::{egui, App, Frame, NativeOptions};
-use std::sync::atomic::Ordering;
 use views::ViewType;
-use views::WAS_MODIFIED;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     file: Option<String>,
+    /// Don't restore the last session, and don't save this one
+    #[arg(long)]
+    no_session: bool,
 }
 #[derive(Default)]
 struct MyApp {
     current_view: ViewType,
-    opened: String,
-    filename: String,
-    show_confirm_dialog: bool,
-    is_modified: bool,
+    tabs: Tabs,
+    /// Set while the unsaved-changes dialog is walking the dirty tabs on exit.
+    quitting: bool,
     initial_file: Option<String>,
     discord: Option<DiscordIpcClient>,
     start_timestamp: i64,
+    snapshots: autosave::Snapshots,
+    autosave: autosave::Autosave,
+    watcher: Option<watcher::FileWatcher>,
+    /// The last session, restored on the first frame.
+    session: Option<session::Session>,
+    save_session: bool,
+}
+
+impl MyApp {
+    /// Asks about the first queued dirty tab. Returns true while a dialog is
+    /// still waiting for an answer.
+    fn show_unsaved_dialog(&mut self, ctx: &egui::Context) -> bool {
+        while let Some(&id) = self.tabs.close_queue.front() {
+            match self.tabs.index_of(id) {
+                Some(idx) if self.tabs.documents[idx].modified => break,
+                // Saved or closed some other way in the meantime
+                Some(idx) => self.tabs.close(idx),
+                None => {
+                    self.tabs.close_queue.pop_front();
+                }
+            }
+        }
+        let Some(&id) = self.tabs.close_queue.front() else {
+            return false;
+        };
+        let Some(idx) = self.tabs.index_of(id) else {
+            return false;
+        };
+        let name = self.tabs.documents[idx].title();
+
+        egui::Window::new("Unsaved Changes")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .fixed_size([300.0, 65.0])
+            .show(ctx, |ui| {
+                ui.vertical_centered(|ui| {
+                    ui.label(format!("{} has unsaved changes.", name));
+                    ui.label("Would you like to save them before closing?");
+                    ui.add_space(8.0);
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        if ui.button("Cancel").clicked() {
+                            self.tabs.close_queue.clear();
+                            self.quitting = false;
+                        }
+                        if ui.button("Don't Save").clicked() {
+                            self.tabs.close(idx);
+                        }
+                        if ui.button("Save").clicked()
+                            && views::save_document(ctx, &mut self.tabs.documents[idx], false)
+                        {
+                            self.tabs.close(idx);
+                        }
+                    });
+                });
+            });
+        true
+    }
 }
 
 impl App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut Frame) {
+        if let Some(session) = self.session.take() {
+            if session.restore(ctx, &mut self.tabs) {
+                self.current_view = ViewType::Editor;
+            }
+        }
         if let Some(file_path) = self.initial_file.take() {
-            match std::fs::read_to_string(&file_path) {
-                Ok(content) => {
+            match self.tabs.open(&file_path) {
+                Ok(()) => {
+                    views::remember_recent(file_path.as_ref(), recent::RecentKind::File);
                     self.current_view = ViewType::Editor;
-                    self.filename = file_path;
-                    self.opened = content;
                     ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
                     if let Some(discord) = &mut self.discord {
                         discord
                             .set_activity(
                                 discord_rich_presence::activity::Activity::new()
                                     .state("Editing")
-                                    .details(&self.filename),
+                                    .details(&file_path),
                             )
                             .ok();
                     }
@@ -48,60 +132,44 @@ impl App for MyApp {
                 Err(e) => {
                     rfd::MessageDialog::new()
                         .set_title("Error")
-                        .set_description(&format!("Error opening file: {}", e))
+                        .set_description(format!("Error opening file: {}", e))
                         .set_level(rfd::MessageLevel::Error)
                         .show();
                 }
             }
         }
         // Handle close request first, before any other updates
-        if ctx.input(|i| i.viewport().close_requested()) && !self.show_confirm_dialog {
-            let modif = WAS_MODIFIED.load(Ordering::SeqCst);
-            if modif {
-                self.show_confirm_dialog = true;
+        if ctx.input(|i| i.viewport().close_requested()) {
+            // Taken now, while the unsaved-changes dialogs haven't closed
+            // any tabs yet
+            if self.save_session {
+                if let Err(e) = session::Session::capture(ctx, &self.tabs).save() {
+                    println!("Failed to save the session: {}", e);
+                }
+            }
+            if self.tabs.any_modified() {
+                self.quitting = true;
+                self.tabs.request_close_all_modified();
                 ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
-                return; // Exit early to prevent the close
             } else {
+                self.snapshots.clear();
+                views::shutdown_language_servers();
                 ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                 return;
             }
         }
 
-        // Show dialog if needed
-        if self.show_confirm_dialog {
-            egui::Window::new("Unsaved Changes")
-                .collapsible(false)
-                .resizable(false)
-                .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
-                .fixed_size([300.0, 65.0])
-                .show(ctx, |ui| {
-                    ui.vertical_centered(|ui| {
-                        ui.label("You have unsaved changes.");
-                        ui.label("Would you really like to close Kokona?");
-                        ui.add_space(8.0);
-                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                            if ui.button("Yes").clicked() {
-                                WAS_MODIFIED.store(false, Ordering::SeqCst);
-                                self.show_confirm_dialog = false;
-                                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
-                                return;
-                            }
-                            if ui.button("No").clicked() {
-                                self.show_confirm_dialog = false;
-                            }
-                        });
-                    });
-                });
+        // Show dialog if needed, one dirty tab at a time
+        if !self.show_unsaved_dialog(ctx) && self.quitting {
+            self.quitting = false;
+            self.snapshots.clear();
+            views::shutdown_language_servers();
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
         }
 
         match self.current_view {
             ViewType::Home => {
-                views::home_view(
-                    ctx,
-                    &mut self.current_view,
-                    &mut self.filename,
-                    &mut self.opened,
-                );
+                views::home_view(ctx, &mut self.current_view, &mut self.tabs);
                 if let Some(discord) = &mut self.discord {
                     discord
                         .set_activity(
@@ -117,25 +185,18 @@ impl App for MyApp {
                 }
             }
             ViewType::Editor => {
-                let modified = views::editor_view(
-                    ctx,
-                    &mut self.opened,
-                    &mut self.filename,
-                    &mut self.current_view,
-                );
-                if modified {
-                    self.is_modified = true;
-                }
+                views::editor_view(ctx, &mut self.tabs, &mut self.current_view);
+                let filename = self.tabs.active().map(|d| d.filename()).unwrap_or_default();
                 if let Some(discord) = &mut self.discord {
                     discord
                         .set_activity(
                             discord_rich_presence::activity::Activity::new()
                                 .details(&format!(
                                     "In {}",
-                                    if self.filename == "untitled.txt" {
+                                    if filename == document::UNTITLED {
                                         "no directory"
                                     } else {
-                                        std::path::Path::new(&self.filename)
+                                        std::path::Path::new(&filename)
                                             .parent()
                                             .and_then(|p| p.file_name())
                                             .and_then(|n| n.to_str())
@@ -144,10 +205,10 @@ impl App for MyApp {
                                 ))
                                 .state(&format!(
                                     "Working on {}",
-                                    std::path::Path::new(&self.filename)
+                                    std::path::Path::new(&filename)
                                         .file_name()
                                         .and_then(|n| n.to_str())
-                                        .unwrap_or(&self.filename)
+                                        .unwrap_or(&filename)
                                 ))
                                 .timestamps(
                                     discord_rich_presence::activity::Timestamps::new()
@@ -159,6 +220,19 @@ impl App for MyApp {
             }
         }
 
+        let settings = views::current_settings();
+        self.autosave.update(
+            ctx,
+            &mut self.tabs,
+            settings.autosave,
+            std::time::Duration::from_millis(settings.autosave_delay_ms),
+        );
+        self.snapshots.update(ctx, &self.tabs);
+        if let Some(watcher) = &mut self.watcher {
+            watcher.update(ctx, &mut self.tabs);
+        }
+        views::update_language_servers(ctx, &self.tabs);
+
         //        if ctx.input(|i| i.key_pressed(egui::Key::Tab)) {
         //            self.current_view = match self.current_view {
         //                ViewType::Home => ViewType::Editor,
@@ -175,14 +249,30 @@ fn main() -> Result<(), eframe::Error> {
     let mut discord = DiscordIpcClient::new("1332264064025362493").unwrap();
     discord.connect().ok();
 
+    let session = if cli.no_session {
+        None
+    } else {
+        session::Session::load()
+    };
+    let mut viewport = egui::ViewportBuilder::default().with_inner_size(
+        session
+            .as_ref()
+            .and_then(|s| s.window_size)
+            .unwrap_or([1280.0, 720.0]),
+    );
+    if let Some(position) = session.as_ref().and_then(|s| s.window_position) {
+        viewport = viewport.with_position(position);
+    }
     let options = NativeOptions {
         vsync: true,
         multisampling: 4,
-        viewport: egui::ViewportBuilder::default().with_inner_size([1280.0, 720.0]),
+        viewport,
         ..Default::default()
     };
 
     let mut app = MyApp::default();
+    app.session = session;
+    app.save_session = !cli.no_session;
     app.initial_file = cli.file;
     app.discord = Some(discord);
     app.start_timestamp = std::time::SystemTime::now()
@@ -192,9 +282,15 @@ fn main() -> Result<(), eframe::Error> {
     eframe::run_native(
         "Kokona",
         options,
-        Box::new(move |_cc| {
+        Box::new(move |cc| {
             #[cfg(any(target_os = "windows", target_os = "macos"))]
-            chinese_characters_support::add_font(_cc);
+            chinese_characters_support::add_font(cc);
+
+            app.watcher = watcher::FileWatcher::new(&cc.egui_ctx)
+                .map_err(|e| {
+                    views::report_error(format!("Failed to start the file watcher: {}", e))
+                })
+                .ok();
 
             Ok(Box::new(app))
         }),
@@ -0,0 +1,249 @@
+use crate::lsp::{Diagnostic, Position, Severity};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use ropey::Rope;
+use serde_json::Value;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// An error or warning reported by a compiler, for the Problems window and
+/// the editor's underlines.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    pub path: PathBuf,
+    /// Zero-based line and char column.
+    pub line: usize,
+    pub column: usize,
+    /// Where the span ends, when the compiler says.
+    pub end: Option<(usize, usize)>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Problem {
+    /// The span in `rope`, or the word at the start when the compiler only
+    /// gave a point.
+    pub fn char_range(&self, rope: &Rope) -> Range<usize> {
+        let start = char_at(rope, self.line, self.column);
+        let end = match self.end {
+            Some((line, column)) => char_at(rope, line, column),
+            None => {
+                let word = rope
+                    .chars_at(start)
+                    .take_while(|&c| c.is_alphanumeric() || c == '_')
+                    .count();
+                let end = start + word.max(1);
+                if end > rope.len_chars() || rope.char(start) == '\n' {
+                    start
+                } else {
+                    end
+                }
+            }
+        };
+        start..end.max(start)
+    }
+
+    /// The problem as a diagnostic in `rope`, so it is drawn and explained
+    /// like the language server's.
+    pub fn diagnostic(&self, rope: &Rope) -> Diagnostic {
+        let range = self.char_range(rope);
+        Diagnostic {
+            start: Position::from_char(rope, range.start),
+            end: Position::from_char(rope, range.end),
+            severity: self.severity,
+            message: self.message.clone(),
+        }
+    }
+
+    /// `file:line:column`, relative to `root` when it is inside it.
+    pub fn location(&self, root: Option<&Path>) -> String {
+        let path = root
+            .and_then(|root| self.path.strip_prefix(root).ok())
+            .unwrap_or(&self.path);
+        format!("{}:{}:{}", path.display(), self.line + 1, self.column + 1)
+    }
+}
+
+/// Char index of a line and column, clamped to the line's text.
+fn char_at(rope: &Rope, line: usize, column: usize) -> usize {
+    if line >= rope.len_lines() {
+        return rope.len_chars();
+    }
+    let text = rope.line(line);
+    let len = text.chars().filter(|&c| c != '\n' && c != '\r').count();
+    rope.line_to_char(line) + column.min(len)
+}
+
+/// Absolute form of a path from compiler output, which may be relative to
+/// `dir` or one of its parents.
+fn resolve(dir: &Path, file: &str) -> PathBuf {
+    let file = Path::new(file);
+    let path = if file.is_absolute() {
+        file.to_path_buf()
+    } else {
+        dir.ancestors()
+            .map(|dir| dir.join(file))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| dir.join(file))
+    };
+    std::fs::canonicalize(&path).unwrap_or(path)
+}
+
+/// What a line of `cargo … --message-format=json` output says: the problem
+/// it reports, if any, and the text to show for it, which is the compiler's
+/// own rendering. Lines that aren't JSON are shown as they are, other cargo
+/// messages not at all.
+pub fn parse_cargo_line(line: &str, dir: &Path) -> (Option<Problem>, Option<String>) {
+    let Ok(value) = serde_json::from_str::<Value>(line) else {
+        return (None, Some(line.to_string()));
+    };
+    if value["reason"] != "compiler-message" {
+        return (None, None);
+    }
+    let message = &value["message"];
+    let rendered = message["rendered"].as_str().map(str::to_string);
+    let severity = match message["level"].as_str().unwrap_or_default() {
+        "warning" => Severity::Warning,
+        "note" | "help" => Severity::Information,
+        "failure-note" => return (None, rendered),
+        _ => Severity::Error,
+    };
+    // Paths are relative to the workspace, which holds the package
+    let base = value["manifest_path"]
+        .as_str()
+        .and_then(|manifest| Path::new(manifest).parent())
+        .unwrap_or(dir);
+    let Some(span) = message["spans"]
+        .as_array()
+        .and_then(|spans| spans.iter().find(|span| span["is_primary"] == true))
+    else {
+        return (None, rendered);
+    };
+    let number = |key: &str| span[key].as_u64().unwrap_or(1).max(1) as usize - 1;
+    let mut text = message["message"].as_str().unwrap_or_default().to_string();
+    if let Some(code) = message["code"]["code"].as_str() {
+        text = format!("{}: {}", code, text);
+    }
+    let problem = Problem {
+        path: resolve(base, span["file_name"].as_str().unwrap_or_default()),
+        line: number("line_start"),
+        column: number("column_start"),
+        end: Some((number("line_end"), number("column_end"))),
+        severity,
+        message: text,
+    };
+    (Some(problem), rendered)
+}
+
+static GCC_PATTERN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^(.+?):(\d+):(?:(\d+):)? (fatal error|error|warning|note): (.*)$").unwrap()
+});
+
+/// The problem in a line of gcc or clang output, `file:line:column: error:
+/// message`, with paths relative to `dir`.
+pub fn parse_gcc_line(line: &str, dir: &Path) -> Option<Problem> {
+    let caps = GCC_PATTERN.captures(line.trim_end())?;
+    let number = |idx: usize| {
+        caps.get(idx)
+            .and_then(|m| m.as_str().parse::<usize>().ok())
+            .unwrap_or(1)
+            .max(1)
+            - 1
+    };
+    let severity = match &caps[4] {
+        "warning" => Severity::Warning,
+        "note" => Severity::Information,
+        _ => Severity::Error,
+    };
+    Some(Problem {
+        path: resolve(dir, &caps[1]),
+        line: number(2),
+        column: number(3),
+        end: None,
+        severity,
+        message: caps[5].to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIR: &str = "/nonexistent/workspace";
+
+    #[test]
+    fn cargo_messages_give_the_primary_span() {
+        let line = r#"{"reason":"compiler-message","manifest_path":"/nonexistent/workspace/Cargo.toml","message":{"rendered":"warning: unused variable: `x`\n","level":"warning","message":"unused variable: `x`","code":{"code":"unused_variables"},"spans":[{"file_name":"src/other.rs","line_start":1,"column_start":1,"line_end":1,"column_end":2,"is_primary":false},{"file_name":"src/main.rs","line_start":3,"column_start":9,"line_end":3,"column_end":10,"is_primary":true}]}}"#;
+        let (problem, rendered) = parse_cargo_line(line, Path::new(DIR));
+        assert_eq!(
+            problem,
+            Some(Problem {
+                path: PathBuf::from(DIR).join("src/main.rs"),
+                line: 2,
+                column: 8,
+                end: Some((2, 9)),
+                severity: Severity::Warning,
+                message: "unused_variables: unused variable: `x`".to_string(),
+            })
+        );
+        assert_eq!(rendered.as_deref(), Some("warning: unused variable: `x`\n"));
+    }
+
+    #[test]
+    fn other_cargo_output_is_shown_or_skipped() {
+        let dir = Path::new(DIR);
+        assert_eq!(
+            parse_cargo_line("   Compiling kokona v0.1.0", dir),
+            (None, Some("   Compiling kokona v0.1.0".to_string()))
+        );
+        assert_eq!(
+            parse_cargo_line(r#"{"reason":"build-finished","success":true}"#, dir),
+            (None, None)
+        );
+        // Summaries like "aborting due to 2 previous errors" have no span
+        let summary = r#"{"reason":"compiler-message","message":{"rendered":"error: aborting\n","level":"error","message":"aborting","spans":[]}}"#;
+        assert_eq!(
+            parse_cargo_line(summary, dir),
+            (None, Some("error: aborting\n".to_string()))
+        );
+    }
+
+    #[test]
+    fn gcc_lines_with_and_without_a_column() {
+        let dir = Path::new(DIR);
+        assert_eq!(
+            parse_gcc_line("main.c:12:5: error: expected ';' before '}' token", dir),
+            Some(Problem {
+                path: PathBuf::from(DIR).join("main.c"),
+                line: 11,
+                column: 4,
+                end: None,
+                severity: Severity::Error,
+                message: "expected ';' before '}' token".to_string(),
+            })
+        );
+        let problem = parse_gcc_line("/tmp/x.h:3: warning: unused\r\n", dir).unwrap();
+        assert_eq!((problem.line, problem.column), (2, 0));
+        assert_eq!(problem.severity, Severity::Warning);
+        assert_eq!(parse_gcc_line("make: *** [all] Error 1", dir), None);
+    }
+
+    #[test]
+    fn a_point_covers_the_word_there() {
+        let rope = Rope::from_str("let value = 1;\n");
+        let problem = Problem {
+            path: PathBuf::new(),
+            line: 0,
+            column: 4,
+            end: None,
+            severity: Severity::Error,
+            message: String::new(),
+        };
+        assert_eq!(problem.char_range(&rope), 4..9);
+        let past_the_end = Problem {
+            column: 40,
+            ..problem
+        };
+        assert_eq!(past_the_end.char_range(&rope), 14..14);
+    }
+}
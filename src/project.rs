@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Debug)]
+pub struct TreeEntry {
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+
+impl TreeEntry {
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
+
+/// A folder opened as the project: the root of the explorer, Find in Files
+/// and build commands. Folders are listed the first time they are expanded
+/// and kept until refreshed.
+pub struct Project {
+    pub root: PathBuf,
+    children: HashMap<PathBuf, Vec<TreeEntry>>,
+}
+
+impl Project {
+    pub fn open(root: &Path) -> io::Result<Self> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::other("not a folder"));
+        }
+        Ok(Self {
+            root,
+            children: HashMap::new(),
+        })
+    }
+
+    /// The root's folder name, shown as the top of the tree.
+    pub fn name(&self) -> String {
+        self.root.file_name().map_or_else(
+            || self.root.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        )
+    }
+
+    pub fn children(&mut self, dir: &Path) -> Vec<TreeEntry> {
+        self.children
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| list_dir(dir))
+            .clone()
+    }
+
+    /// Forgets the listing of `dir`, so it is read again when next shown.
+    pub fn forget(&mut self, dir: &Path) {
+        self.children.remove(dir);
+    }
+
+    pub fn refresh(&mut self) {
+        self.children.clear();
+    }
+}
+
+/// Entries of `dir` that aren't ignored by `.gitignore` and friends, folders
+/// first, then by name.
+fn list_dir(dir: &Path) -> Vec<TreeEntry> {
+    let mut entries: Vec<TreeEntry> = ignore::WalkBuilder::new(dir)
+        .max_depth(Some(1))
+        .hidden(false)
+        .require_git(false)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.depth() == 1)
+        .map(|entry| TreeEntry {
+            is_dir: entry.file_type().is_some_and(|t| t.is_dir()),
+            path: entry.into_path(),
+        })
+        .collect();
+    entries.sort_by_cached_key(|entry| (!entry.is_dir, entry.name().to_lowercase()));
+    entries
+}
+
+/// Rejects names that would land somewhere other than the folder they are
+/// created in.
+fn check_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("\"{}\" is not a valid name", name),
+        ));
+    }
+    Ok(())
+}
+
+pub fn create_file(dir: &Path, name: &str) -> io::Result<PathBuf> {
+    check_name(name)?;
+    let path = dir.join(name);
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    Ok(path)
+}
+
+pub fn create_folder(dir: &Path, name: &str) -> io::Result<PathBuf> {
+    check_name(name)?;
+    let path = dir.join(name);
+    fs::create_dir(&path)?;
+    Ok(path)
+}
+
+/// Renames `path` within its folder, refusing to replace anything.
+pub fn rename(path: &Path, name: &str) -> io::Result<PathBuf> {
+    check_name(name)?;
+    let to = path.with_file_name(name);
+    if to.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", to.display()),
+        ));
+    }
+    fs::rename(path, &to)?;
+    Ok(to)
+}
+
+pub fn move_to_trash(path: &Path) -> Result<(), trash::Error> {
+    trash::delete(path)
+}
+
+/// Shows `path` in the system file manager, selected where the platform
+/// allows it.
+pub fn reveal(path: &Path) -> io::Result<()> {
+    #[cfg(target_os = "windows")]
+    {
+        let mut select = std::ffi::OsString::from("/select,");
+        select.push(path);
+        std::process::Command::new("explorer").arg(select).spawn()?;
+    }
+    #[cfg(target_os = "macos")]
+    {
+        std::process::Command::new("open")
+            .arg("-R")
+            .arg(path)
+            .spawn()?;
+    }
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    {
+        let folder = if path.is_dir() {
+            path
+        } else {
+            path.parent().unwrap_or(path)
+        };
+        open::that(folder)?;
+    }
+    Ok(())
+}
+
+pub fn file_icon(path: &Path, is_dir: bool) -> &'static str {
+    if is_dir {
+        return "📁";
+    }
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "rs" => "🦀",
+        "py" => "🐍",
+        "c" | "h" | "cpp" | "hpp" | "cc" | "go" | "js" | "ts" | "java" => "📜",
+        "md" | "txt" | "rst" => "📝",
+        "toml" | "json" | "yaml" | "yml" | "ini" | "cfg" | "lock" => "⚙",
+        "png" | "jpg" | "jpeg" | "gif" | "svg" | "bmp" | "ico" | "webp" => "🖼",
+        "sh" | "bash" | "zsh" | "fish" | "ps1" | "bat" => "🖥",
+        "zip" | "tar" | "gz" | "xz" | "7z" => "📦",
+        _ => "📄",
+    }
+}
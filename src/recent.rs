@@ -0,0 +1,106 @@
+use directories_next::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many unpinned files, and as many folders, are remembered.
+const MAX_RECENT: usize = 10;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum RecentKind {
+    File,
+    Folder,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecentEntry {
+    pub path: PathBuf,
+    pub kind: RecentKind,
+    /// Seconds since the Unix epoch.
+    pub opened_at: u64,
+    /// Pinned entries stay at the top and are never dropped to make room.
+    pub pinned: bool,
+}
+
+/// Files and folders opened lately, most recent first, stored in the config
+/// dir so the home screen and File → Open Recent can offer them again.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct RecentList {
+    entries: Vec<RecentEntry>,
+}
+
+fn recent_file() -> Option<PathBuf> {
+    let proj_dirs = ProjectDirs::from("dev", "nijika", "kokona")?;
+    Some(proj_dirs.config_dir().join("recent.json"))
+}
+
+impl RecentList {
+    pub fn load() -> Self {
+        recent_file()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(file) = recent_file() else {
+            return Ok(());
+        };
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        crate::atomic::write(&file, serde_json::to_string_pretty(self)?.as_bytes(), false)?;
+        Ok(())
+    }
+
+    /// Entries of one kind, pinned ones first.
+    pub fn entries(&self, kind: RecentKind) -> impl Iterator<Item = &RecentEntry> {
+        let pinned = self.entries.iter().filter(|e| e.pinned);
+        let unpinned = self.entries.iter().filter(|e| !e.pinned);
+        pinned.chain(unpinned).filter(move |e| e.kind == kind)
+    }
+
+    /// Moves `path` to the top of its list, adding it if it's new.
+    pub fn add(&mut self, path: &Path, kind: RecentKind) {
+        // Stored absolute, so the entry still works from another directory
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let pinned = self
+            .entries
+            .iter()
+            .any(|e| e.path == path && e.kind == kind && e.pinned);
+        self.entries.retain(|e| !(e.path == path && e.kind == kind));
+        self.entries.insert(
+            0,
+            RecentEntry {
+                path,
+                kind,
+                opened_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+                pinned,
+            },
+        );
+        let mut unpinned = 0;
+        self.entries.retain(|e| {
+            if e.kind != kind || e.pinned {
+                return true;
+            }
+            unpinned += 1;
+            unpinned <= MAX_RECENT
+        });
+    }
+
+    pub fn remove(&mut self, path: &Path, kind: RecentKind) {
+        self.entries.retain(|e| !(e.path == path && e.kind == kind));
+    }
+
+    pub fn toggle_pin(&mut self, path: &Path, kind: RecentKind) {
+        for entry in &mut self.entries {
+            if entry.path == path && entry.kind == kind {
+                entry.pinned = !entry.pinned;
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::document::Tabs;
+use crate::views;
+use directories_next::ProjectDirs;
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionDocument {
+    pub path: PathBuf,
+    /// Char offsets of the selection, `(primary, secondary)`.
+    pub cursor: Option<(usize, usize)>,
+    pub scroll: [f32; 2],
+}
+
+/// What was open when Kokona last exited, restored on the next launch.
+/// Untitled buffers aren't part of it; crash recovery covers unsaved text.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Session {
+    pub documents: Vec<SessionDocument>,
+    /// Index into `documents`.
+    pub active: usize,
+    pub terminal_open: bool,
+    /// The open project folder.
+    pub project: Option<PathBuf>,
+    /// Inner size and outer position of the window, in points.
+    pub window_size: Option<[f32; 2]>,
+    pub window_position: Option<[f32; 2]>,
+}
+
+fn session_file() -> Option<PathBuf> {
+    let proj_dirs = ProjectDirs::from("dev", "nijika", "kokona")?;
+    Some(proj_dirs.config_dir().join("session.json"))
+}
+
+impl Session {
+    pub fn load() -> Option<Self> {
+        let contents = fs::read_to_string(session_file()?).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(file) = session_file() else {
+            return Ok(());
+        };
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        crate::atomic::write(&file, serde_json::to_string_pretty(self)?.as_bytes(), false)?;
+        Ok(())
+    }
+
+    /// The session as it is right now.
+    pub fn capture(ctx: &egui::Context, tabs: &Tabs) -> Self {
+        let mut session = Self {
+            terminal_open: views::terminal_open(),
+            project: views::project_root(),
+            ..Self::default()
+        };
+        for (idx, doc) in tabs.documents.iter().enumerate() {
+            let Some(path) = &doc.path else {
+                continue;
+            };
+            if idx == tabs.active {
+                session.active = session.documents.len();
+            }
+            session.documents.push(SessionDocument {
+                path: path.clone(),
+                cursor: doc
+                    .cursor
+                    .map(|range| (range.primary.index, range.secondary.index)),
+                scroll: [doc.scroll_offset.x, doc.scroll_offset.y],
+            });
+        }
+        ctx.input(|i| {
+            let viewport = i.viewport();
+            session.window_size = viewport.inner_rect.map(|rect| rect.size().into());
+            session.window_position = viewport.outer_rect.map(|rect| rect.min.into());
+        });
+        session
+    }
+
+    /// Reopens the session's documents that still exist. Returns whether
+    /// any did.
+    pub fn restore(&self, ctx: &egui::Context, tabs: &mut Tabs) -> bool {
+        if let Some(root) = self.project.as_ref().filter(|root| root.is_dir()) {
+            views::open_project(root);
+        }
+        let mut active = None;
+        for (idx, saved) in self.documents.iter().enumerate() {
+            if let Err(e) = tabs.open(&saved.path) {
+                println!(
+                    "Skipping {} from the last session: {}",
+                    saved.path.display(),
+                    e
+                );
+                continue;
+            }
+            let Some(doc) = tabs.active_mut() else {
+                continue;
+            };
+            if let Some((primary, secondary)) = saved.cursor {
+                let len = doc.text.len_chars();
+                let range = egui::text::CCursorRange {
+                    primary: egui::text::CCursor::new(primary.min(len)),
+                    secondary: egui::text::CCursor::new(secondary.min(len)),
+                };
+                doc.cursor = Some(range);
+                views::store_selection(ctx, doc.id, range);
+            }
+            doc.scroll_to = Some(egui::vec2(saved.scroll[0], saved.scroll[1]));
+            if idx == self.active {
+                active = Some(tabs.active);
+            }
+        }
+        if let Some(active) = active {
+            tabs.active = active;
+        }
+        views::set_terminal_open(self.terminal_open);
+        !tabs.is_empty()
+    }
+}
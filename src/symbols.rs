@@ -0,0 +1,99 @@
+use crate::buffer::Buffer;
+use std::borrow::Cow;
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxSet};
+
+/// Scopes that name something worth jumping to, with the label shown for
+/// them. Syntaxes use `entity.name.*` for definitions, and Markdown for its
+/// headings.
+const KINDS: &[(&str, &str)] = &[
+    ("entity.name.function", "fn"),
+    ("entity.name.method", "fn"),
+    ("entity.name.struct", "struct"),
+    ("entity.name.class", "class"),
+    ("entity.name.union", "union"),
+    ("entity.name.enum", "enum"),
+    ("entity.name.trait", "trait"),
+    ("entity.name.interface", "interface"),
+    ("entity.name.impl", "impl"),
+    ("entity.name.type", "type"),
+    ("entity.name.namespace", "mod"),
+    ("entity.name.module", "mod"),
+    ("entity.name.macro", "macro"),
+    ("entity.name.section", "heading"),
+];
+
+pub struct Symbol {
+    pub name: String,
+    pub kind: &'static str,
+    /// Zero-based line and char offset of the name in the buffer.
+    pub line: usize,
+    pub char_index: usize,
+}
+
+/// Definitions in `text`, found by running the syntax's parser over it and
+/// collecting the text under the scopes in `KINDS`.
+pub fn extract(ps: &SyntaxSet, syntax: &str, text: &Buffer) -> Vec<Symbol> {
+    let Some(syntax) = ps.find_syntax_by_name(syntax) else {
+        return Vec::new();
+    };
+    let kinds: Vec<(Scope, &str)> = KINDS
+        .iter()
+        .filter_map(|&(scope, label)| Some((Scope::new(scope).ok()?, label)))
+        .collect();
+    let kind_of = |stack: &ScopeStack| {
+        stack.as_slice().iter().rev().find_map(|scope| {
+            kinds
+                .iter()
+                .find(|(kind, _)| kind.is_prefix_of(*scope))
+                .map(|&(_, label)| label)
+        })
+    };
+
+    let mut parse = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let mut symbols: Vec<Symbol> = Vec::new();
+    for (line_idx, line) in text.rope().lines().enumerate() {
+        let line = Cow::<str>::from(line);
+        let Ok(ops) = parse.parse_line(&line, ps) else {
+            break;
+        };
+        let line_start = text.rope().line_to_char(line_idx);
+        let mut pos = 0;
+        // Name pieces that touch are one name, e.g. `a::b` split by scopes
+        let mut open: Option<usize> = None;
+        let mut flush = |from: usize, to: usize, kind: Option<&'static str>| {
+            let piece = &line[from..to];
+            match kind {
+                Some(kind) if !piece.trim().is_empty() => {
+                    if let Some(symbol) = open.and_then(|i| symbols.get_mut(i)) {
+                        symbol.name.push_str(piece);
+                    } else {
+                        open = Some(symbols.len());
+                        symbols.push(Symbol {
+                            name: piece.to_string(),
+                            kind,
+                            line: line_idx,
+                            char_index: line_start + line[..from].chars().count(),
+                        });
+                    }
+                }
+                _ => open = None,
+            }
+        };
+        for (at, op) in ops {
+            if at > pos {
+                flush(pos, at, kind_of(&stack));
+                pos = at;
+            }
+            if stack.apply(&op).is_err() {
+                break;
+            }
+        }
+        flush(pos, line.len(), kind_of(&stack));
+    }
+
+    for symbol in &mut symbols {
+        symbol.name = symbol.name.trim().to_string();
+    }
+    symbols
+}
@@ -0,0 +1,357 @@
+use directories_next::ProjectDirs;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskOutput {
+    /// Runs interactively in the terminal panel.
+    Terminal,
+    /// Runs to completion and shows the output, picking out problems.
+    #[default]
+    Capture,
+}
+
+/// A command the Run menu offers. Strings may use `${file}`,
+/// `${fileDirname}`, `${fileBasename}`, `${fileBasenameNoExtension}`,
+/// `${workspaceRoot}` and `${env:NAME}`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Task {
+    pub name: String,
+    pub command: String,
+    pub args: Vec<String>,
+    /// Folder to run in; the workspace root when unset.
+    pub cwd: Option<String>,
+    pub env: BTreeMap<String, String>,
+    /// Glob the active file has to match, like `*.rs`; any file, or none,
+    /// when unset.
+    pub files: Option<String>,
+    pub output: TaskOutput,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct TaskFile {
+    tasks: Vec<Task>,
+}
+
+/// `${name}`, a variable in a task string.
+static VARIABLE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\$\{([^}]+)\}").unwrap());
+
+/// What the variables in a task stand for.
+pub struct Variables {
+    pub file: Option<PathBuf>,
+    /// The project folder when the file is in it, else the file's folder.
+    pub workspace_root: PathBuf,
+}
+
+impl Variables {
+    pub fn expand(&self, text: &str) -> String {
+        let file = self.file.as_deref();
+        let lossy = |path: Option<&Path>| path.map(|p| p.to_string_lossy().into_owned());
+        VARIABLE
+            .replace_all(text, |caps: &Captures| {
+                let value = match &caps[1] {
+                    "file" => lossy(file),
+                    "fileDirname" => lossy(file.and_then(Path::parent)),
+                    "fileBasename" => lossy(file.and_then(Path::file_name).map(Path::new)),
+                    "fileBasenameNoExtension" => {
+                        lossy(file.and_then(Path::file_stem).map(Path::new))
+                    }
+                    "workspaceRoot" => lossy(Some(&self.workspace_root)),
+                    name => match name.strip_prefix("env:") {
+                        Some(var) => Some(std::env::var(var).unwrap_or_default()),
+                        // Left alone so the mistake shows in the command
+                        None => return caps[0].to_string(),
+                    },
+                };
+                value.unwrap_or_default()
+            })
+            .into_owned()
+    }
+}
+
+/// A task with its variables filled in, ready to start.
+pub struct Resolved {
+    pub name: String,
+    pub program: String,
+    pub args: Vec<String>,
+    pub cwd: PathBuf,
+    pub env: Vec<(String, String)>,
+    pub output: TaskOutput,
+}
+
+impl Resolved {
+    pub fn command(&self) -> std::process::Command {
+        let mut command = std::process::Command::new(&self.program);
+        command
+            .args(&self.args)
+            .current_dir(&self.cwd)
+            .envs(self.env.iter().map(|(k, v)| (k, v)));
+        command
+    }
+
+    pub fn pty_command(&self) -> portable_pty::CommandBuilder {
+        let mut command = portable_pty::CommandBuilder::new(&self.program);
+        command.args(&self.args);
+        command.cwd(&self.cwd);
+        for (key, value) in &self.env {
+            command.env(key, value);
+        }
+        command
+    }
+
+    /// The command as it would be typed, for showing.
+    pub fn command_line(&self) -> String {
+        std::iter::once(&self.program)
+            .chain(&self.args)
+            .map(|part| {
+                if part.is_empty() || part.contains(char::is_whitespace) {
+                    format!("\"{}\"", part)
+                } else {
+                    part.clone()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Task {
+    fn new(name: &str, command: &str, args: &[&str], files: &str, output: TaskOutput) -> Self {
+        Self {
+            name: name.to_string(),
+            command: command.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            files: Some(files.to_string()),
+            output,
+            ..Self::default()
+        }
+    }
+
+    /// Whether the task is offered for `file`. The glob is matched against
+    /// the path relative to the workspace root.
+    pub fn applies_to(&self, vars: &Variables) -> bool {
+        let Some(glob) = &self.files else {
+            return true;
+        };
+        let Some(file) = &vars.file else {
+            return false;
+        };
+        let relative = file.strip_prefix(&vars.workspace_root).unwrap_or(file);
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        crate::editorconfig::glob_to_regex(glob).is_some_and(|re| re.is_match(&relative))
+    }
+
+    pub fn resolve(&self, vars: &Variables) -> Resolved {
+        Resolved {
+            name: self.name.clone(),
+            program: vars.expand(&self.command),
+            args: self.args.iter().map(|arg| vars.expand(arg)).collect(),
+            cwd: self.cwd.as_ref().map_or_else(
+                || vars.workspace_root.clone(),
+                |cwd| vars.workspace_root.join(vars.expand(cwd)),
+            ),
+            env: self
+                .env
+                .iter()
+                .map(|(key, value)| (key.clone(), vars.expand(value)))
+                .collect(),
+            output: self.output,
+        }
+    }
+}
+
+/// The tasks used when there is no global tasks file: what the Rust,
+/// Python and C menus used to do.
+pub fn defaults() -> Vec<Task> {
+    use TaskOutput::*;
+    vec![
+        Task::new(
+            "Cargo Build",
+            "cargo",
+            &["build", "--message-format=json"],
+            "*.rs",
+            Capture,
+        ),
+        Task::new(
+            "Cargo Check",
+            "cargo",
+            &["check", "--message-format=json"],
+            "*.rs",
+            Capture,
+        ),
+        Task::new("Cargo Run", "cargo", &["run"], "*.rs", Terminal),
+        Task {
+            cwd: Some("${fileDirname}".to_string()),
+            ..Task::new("Python Run", "python", &["${file}"], "*.py", Terminal)
+        },
+        Task::new(
+            "C Build",
+            "gcc",
+            &["${file}", "-o", "${fileBasenameNoExtension}"],
+            "*.c",
+            Capture,
+        ),
+        Task::new(
+            "C Run",
+            "./${fileBasenameNoExtension}",
+            &[],
+            "*.c",
+            Terminal,
+        ),
+    ]
+}
+
+/// `tasks.json` in the config dir, for tasks available everywhere.
+pub fn global_file() -> Option<PathBuf> {
+    let proj_dirs = ProjectDirs::from("dev", "nijika", "kokona")?;
+    Some(proj_dirs.config_dir().join("tasks.json"))
+}
+
+pub fn project_file(root: &Path) -> PathBuf {
+    root.join(".kokona").join("tasks.json")
+}
+
+fn read(path: &Path) -> Result<Option<Vec<Task>>, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    serde_json::from_str::<TaskFile>(&contents)
+        .map(|file| Some(file.tasks))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// The project's tasks, then the global ones it doesn't override by name,
+/// along with any errors reading them.
+pub fn load(project: Option<&Path>) -> (Vec<Task>, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut tasks = Vec::new();
+    if let Some(root) = project {
+        match read(&project_file(root)) {
+            Ok(found) => tasks.extend(found.unwrap_or_default()),
+            Err(e) => errors.push(e),
+        }
+    }
+    let global = match global_file().map(|file| read(&file)) {
+        Some(Ok(Some(found))) => found,
+        Some(Err(e)) => {
+            errors.push(e);
+            defaults()
+        }
+        _ => defaults(),
+    };
+    for task in global {
+        if !tasks.iter().any(|t| t.name == task.name) {
+            tasks.push(task);
+        }
+    }
+    (tasks, errors)
+}
+
+/// Makes sure `path` exists so it can be opened for editing, writing
+/// `tasks` into it when it doesn't.
+pub fn create_file(path: &Path, tasks: Vec<Task>) -> Result<(), Box<dyn std::error::Error>> {
+    if path.exists() {
+        return Ok(());
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let contents = serde_json::to_string_pretty(&TaskFile { tasks })?;
+    crate::atomic::write(path, contents.as_bytes(), false)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(file: Option<&str>) -> Variables {
+        Variables {
+            file: file.map(PathBuf::from),
+            workspace_root: PathBuf::from("/work"),
+        }
+    }
+
+    #[test]
+    fn expands_file_and_workspace_variables() {
+        let vars = vars(Some("/work/src/main.rs"));
+        assert_eq!(
+            vars.expand("${file} ${fileDirname} ${fileBasename} ${fileBasenameNoExtension}"),
+            "/work/src/main.rs /work/src main.rs main"
+        );
+        assert_eq!(vars.expand("${workspaceRoot}/target"), "/work/target");
+    }
+
+    #[test]
+    fn unknown_and_missing_variables() {
+        let vars = vars(None);
+        // Nothing to fill in becomes empty, a typo stays for the user to see
+        assert_eq!(vars.expand("[${file}]"), "[]");
+        assert_eq!(vars.expand("${fiel}"), "${fiel}");
+        assert_eq!(vars.expand("${env:KOKONA_TEST_SURELY_UNSET}"), "");
+    }
+
+    #[test]
+    fn file_globs_match_relative_to_the_workspace() {
+        let task = Task::new("Check", "cargo", &[], "*.rs", TaskOutput::Capture);
+        assert!(task.applies_to(&vars(Some("/work/src/main.rs"))));
+        assert!(!task.applies_to(&vars(Some("/work/README.md"))));
+        assert!(!task.applies_to(&vars(None)));
+
+        let nested = Task {
+            files: Some("src/**/*.c".to_string()),
+            ..task.clone()
+        };
+        assert!(nested.applies_to(&vars(Some("/work/src/a/b.c"))));
+        assert!(!nested.applies_to(&vars(Some("/work/test/b.c"))));
+
+        let anywhere = Task {
+            files: None,
+            ..task
+        };
+        assert!(anywhere.applies_to(&vars(None)));
+    }
+
+    #[test]
+    fn resolve_fills_in_every_field() {
+        let task = Task {
+            cwd: Some("${fileDirname}".to_string()),
+            env: BTreeMap::from([("NAME".to_string(), "${fileBasename}".to_string())]),
+            ..Task::new(
+                "C Build",
+                "gcc",
+                &["${file}", "-o", "${fileBasenameNoExtension}"],
+                "*.c",
+                TaskOutput::Capture,
+            )
+        };
+        let resolved = task.resolve(&vars(Some("/work/src/hello world.c")));
+        assert_eq!(resolved.program, "gcc");
+        assert_eq!(
+            resolved.args,
+            ["/work/src/hello world.c", "-o", "hello world"]
+        );
+        assert_eq!(resolved.cwd, PathBuf::from("/work/src"));
+        assert_eq!(
+            resolved.env,
+            [("NAME".to_string(), "hello world.c".to_string())]
+        );
+        assert_eq!(
+            resolved.command_line(),
+            r#"gcc "/work/src/hello world.c" -o "hello world""#
+        );
+
+        // Without a cwd it runs in the workspace root
+        let plain = Task::new("Run", "cargo", &["run"], "*.rs", TaskOutput::Terminal);
+        assert_eq!(plain.resolve(&vars(None)).cwd, PathBuf::from("/work"));
+    }
+}
@@ -0,0 +1,336 @@
+use eframe::egui;
+use portable_pty::{CommandBuilder, PtySize};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Lines kept above the screen for scrolling back.
+const SCROLLBACK: usize = 5000;
+
+/// A program running in a PTY, and the screen it has drawn so far. Output
+/// is parsed on a background thread as it arrives.
+pub struct Terminal {
+    /// What runs in it, for when it exits.
+    pub title: String,
+    master: Box<dyn portable_pty::MasterPty + Send>,
+    writer: Box<dyn Write + Send>,
+    child: Box<dyn portable_pty::Child + Send + Sync>,
+    parser: Arc<Mutex<vt100::Parser>>,
+    size: (u16, u16),
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+    }
+}
+
+impl Terminal {
+    pub fn spawn(
+        ctx: &egui::Context,
+        mut cmd: CommandBuilder,
+        title: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let size = (24, 80);
+        let pair = portable_pty::native_pty_system().openpty(PtySize {
+            rows: size.0,
+            cols: size.1,
+            pixel_width: 0,
+            pixel_height: 0,
+        })?;
+        cmd.env("TERM", "xterm-256color");
+        cmd.env("COLORTERM", "truecolor");
+        let child = pair.slave.spawn_command(cmd)?;
+        // Only the child holds the other end now, so reading ends with it
+        drop(pair.slave);
+        let mut reader = pair.master.try_clone_reader()?;
+        let writer = pair.master.take_writer()?;
+
+        let parser = Arc::new(Mutex::new(vt100::Parser::new(size.0, size.1, SCROLLBACK)));
+        let screen = parser.clone();
+        let ctx = ctx.clone();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 4096];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        screen.lock().unwrap().process(&buffer[..n]);
+                        ctx.request_repaint();
+                    }
+                }
+            }
+            ctx.request_repaint();
+        });
+
+        Ok(Self {
+            title: title.to_string(),
+            master: pair.master,
+            writer,
+            child,
+            parser,
+            size,
+        })
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        if let Err(e) = self
+            .writer
+            .write_all(bytes)
+            .and_then(|_| self.writer.flush())
+        {
+            println!("Failed to write to terminal: {}", e);
+        }
+    }
+
+    /// Resizes the screen and tells the program, which redraws to fit.
+    pub fn resize(&mut self, rows: u16, cols: u16) {
+        if (rows, cols) == self.size || rows == 0 || cols == 0 {
+            return;
+        }
+        self.size = (rows, cols);
+        self.parser
+            .lock()
+            .unwrap()
+            .screen_mut()
+            .set_size(rows, cols);
+        if let Err(e) = self.master.resize(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        }) {
+            println!("Failed to resize terminal: {}", e);
+        }
+    }
+
+    pub fn has_exited(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(Some(_)))
+    }
+
+    pub fn parser(&self) -> MutexGuard<'_, vt100::Parser> {
+        self.parser.lock().unwrap()
+    }
+
+    /// Moves the view `lines` further back into the scrollback, or forward
+    /// when negative.
+    pub fn scroll(&self, lines: isize) {
+        let mut parser = self.parser();
+        let screen = parser.screen_mut();
+        let offset = screen.scrollback().saturating_add_signed(lines);
+        screen.set_scrollback(offset);
+    }
+}
+
+/// What a key sends to the program, as xterm encodes it.
+pub fn key_bytes(
+    key: egui::Key,
+    modifiers: egui::Modifiers,
+    application_cursor: bool,
+) -> Option<Vec<u8>> {
+    use egui::Key;
+    // xterm's modifier parameter: 1 plus shift 1, alt 2, ctrl 4
+    let param = 1 + modifiers.shift as u8 + 2 * modifiers.alt as u8 + 4 * modifiers.ctrl as u8;
+    let cursor = |c: char| {
+        if param > 1 {
+            format!("\x1b[1;{}{}", param, c)
+        } else if application_cursor {
+            format!("\x1bO{}", c)
+        } else {
+            format!("\x1b[{}", c)
+        }
+    };
+    let function = |c: char| {
+        if param > 1 {
+            format!("\x1b[1;{}{}", param, c)
+        } else {
+            format!("\x1bO{}", c)
+        }
+    };
+    let tilde = |n: u8| {
+        if param > 1 {
+            format!("\x1b[{};{}~", n, param)
+        } else {
+            format!("\x1b[{}~", n)
+        }
+    };
+    let text = match key {
+        Key::Enter => "\r".to_string(),
+        Key::Backspace if modifiers.ctrl => "\x08".to_string(),
+        Key::Backspace => "\x7f".to_string(),
+        Key::Tab if modifiers.shift => "\x1b[Z".to_string(),
+        Key::Tab => "\t".to_string(),
+        Key::Escape => "\x1b".to_string(),
+        Key::ArrowUp => cursor('A'),
+        Key::ArrowDown => cursor('B'),
+        Key::ArrowRight => cursor('C'),
+        Key::ArrowLeft => cursor('D'),
+        Key::Home => cursor('H'),
+        Key::End => cursor('F'),
+        Key::Insert => tilde(2),
+        Key::Delete => tilde(3),
+        Key::PageUp => tilde(5),
+        Key::PageDown => tilde(6),
+        Key::F1 => function('P'),
+        Key::F2 => function('Q'),
+        Key::F3 => function('R'),
+        Key::F4 => function('S'),
+        Key::F5 => tilde(15),
+        Key::F6 => tilde(17),
+        Key::F7 => tilde(18),
+        Key::F8 => tilde(19),
+        Key::F9 => tilde(20),
+        Key::F10 => tilde(21),
+        Key::F11 => tilde(23),
+        Key::F12 => tilde(24),
+        Key::Space if modifiers.ctrl => "\0".to_string(),
+        Key::OpenBracket if modifiers.ctrl => "\x1b".to_string(),
+        Key::Backslash if modifiers.ctrl => "\x1c".to_string(),
+        Key::CloseBracket if modifiers.ctrl => "\x1d".to_string(),
+        _ if modifiers.ctrl => {
+            // Ctrl+letter is the letter's control code
+            let name = key.name();
+            let letter = name
+                .chars()
+                .next()
+                .filter(|c| name.len() == 1 && c.is_ascii_alphabetic())?;
+            ((letter.to_ascii_uppercase() as u8 - b'A' + 1) as char).to_string()
+        }
+        _ => return None,
+    };
+    Some(text.into_bytes())
+}
+
+/// The screen colour for a terminal colour, with `default` standing in for
+/// the terminal's own foreground or background.
+pub fn color(color: vt100::Color, default: egui::Color32) -> egui::Color32 {
+    const BASIC: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (205, 49, 49),
+        (13, 188, 121),
+        (229, 229, 16),
+        (36, 114, 200),
+        (188, 63, 188),
+        (17, 168, 205),
+        (229, 229, 229),
+        (102, 102, 102),
+        (241, 76, 76),
+        (35, 209, 139),
+        (245, 245, 67),
+        (59, 142, 234),
+        (214, 112, 214),
+        (41, 184, 219),
+        (255, 255, 255),
+    ];
+    match color {
+        vt100::Color::Default => default,
+        vt100::Color::Rgb(r, g, b) => egui::Color32::from_rgb(r, g, b),
+        vt100::Color::Idx(idx @ 0..=15) => {
+            let (r, g, b) = BASIC[idx as usize];
+            egui::Color32::from_rgb(r, g, b)
+        }
+        vt100::Color::Idx(idx @ 16..=231) => {
+            // 6×6×6 colour cube
+            let level = |n: u8| if n == 0 { 0 } else { 55 + n * 40 };
+            let idx = idx - 16;
+            egui::Color32::from_rgb(level(idx / 36), level(idx / 6 % 6), level(idx % 6))
+        }
+        vt100::Color::Idx(idx) => {
+            let gray = 8 + (idx - 232) * 10;
+            egui::Color32::from_rgb(gray, gray, gray)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egui::{Key, Modifiers};
+
+    fn bytes(key: Key, modifiers: Modifiers) -> Option<Vec<u8>> {
+        key_bytes(key, modifiers, false)
+    }
+
+    #[test]
+    fn control_keys_send_their_codes() {
+        assert_eq!(bytes(Key::C, Modifiers::CTRL), Some(vec![0x03]));
+        assert_eq!(bytes(Key::W, Modifiers::CTRL), Some(vec![0x17]));
+        assert_eq!(bytes(Key::Z, Modifiers::CTRL), Some(vec![0x1a]));
+        assert_eq!(bytes(Key::Space, Modifiers::CTRL), Some(vec![0]));
+        assert_eq!(bytes(Key::OpenBracket, Modifiers::CTRL), Some(vec![0x1b]));
+        assert_eq!(bytes(Key::Enter, Modifiers::NONE), Some(b"\r".to_vec()));
+        assert_eq!(bytes(Key::Backspace, Modifiers::NONE), Some(vec![0x7f]));
+        assert_eq!(bytes(Key::Tab, Modifiers::SHIFT), Some(b"\x1b[Z".to_vec()));
+        // Printable keys arrive as text events instead
+        assert_eq!(bytes(Key::A, Modifiers::NONE), None);
+        assert_eq!(bytes(Key::Num1, Modifiers::CTRL), None);
+    }
+
+    #[test]
+    fn cursor_keys_follow_the_cursor_mode_and_modifiers() {
+        assert_eq!(
+            bytes(Key::ArrowUp, Modifiers::NONE),
+            Some(b"\x1b[A".to_vec())
+        );
+        assert_eq!(
+            key_bytes(Key::ArrowUp, Modifiers::NONE, true),
+            Some(b"\x1bOA".to_vec())
+        );
+        // Modified keys use the CSI form in either mode
+        assert_eq!(
+            key_bytes(Key::ArrowLeft, Modifiers::CTRL, true),
+            Some(b"\x1b[1;5D".to_vec())
+        );
+        assert_eq!(
+            bytes(Key::End, Modifiers::SHIFT | Modifiers::ALT),
+            Some(b"\x1b[1;4F".to_vec())
+        );
+    }
+
+    #[test]
+    fn function_and_editing_keys() {
+        assert_eq!(bytes(Key::F1, Modifiers::NONE), Some(b"\x1bOP".to_vec()));
+        assert_eq!(bytes(Key::F5, Modifiers::NONE), Some(b"\x1b[15~".to_vec()));
+        assert_eq!(
+            bytes(Key::F12, Modifiers::SHIFT),
+            Some(b"\x1b[24;2~".to_vec())
+        );
+        assert_eq!(
+            bytes(Key::Delete, Modifiers::NONE),
+            Some(b"\x1b[3~".to_vec())
+        );
+        assert_eq!(
+            bytes(Key::PageDown, Modifiers::CTRL),
+            Some(b"\x1b[6;5~".to_vec())
+        );
+    }
+
+    #[test]
+    fn colors_cover_the_basic_cube_and_gray_ranges() {
+        let default = egui::Color32::from_rgb(1, 2, 3);
+        assert_eq!(color(vt100::Color::Default, default), default);
+        assert_eq!(
+            color(vt100::Color::Rgb(10, 20, 30), default),
+            egui::Color32::from_rgb(10, 20, 30)
+        );
+        assert_eq!(
+            color(vt100::Color::Idx(1), default),
+            egui::Color32::from_rgb(205, 49, 49)
+        );
+        assert_eq!(color(vt100::Color::Idx(15), default), egui::Color32::WHITE);
+        // The cube runs 0, 95, 135, … 255 on each axis
+        assert_eq!(color(vt100::Color::Idx(16), default), egui::Color32::BLACK);
+        assert_eq!(
+            color(vt100::Color::Idx(16 + 36 + 5), default),
+            egui::Color32::from_rgb(95, 0, 255)
+        );
+        assert_eq!(color(vt100::Color::Idx(231), default), egui::Color32::WHITE);
+        assert_eq!(
+            color(vt100::Color::Idx(232), default),
+            egui::Color32::from_rgb(8, 8, 8)
+        );
+        assert_eq!(
+            color(vt100::Color::Idx(255), default),
+            egui::Color32::from_rgb(238, 238, 238)
+        );
+    }
+}
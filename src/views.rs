@@ -1,25 +1,45 @@
+use crate::autosave::{self, AutosaveMode, Snapshot};
+use crate::buffer::{Buffer, Change};
+use crate::document::{Document, Tabs};
+use crate::encoding::{self, TextEncoding};
+use crate::find_in_files::{compile_query, FindInFiles};
+use crate::highlight::{Highlighter, Line};
+use crate::history::{Edit, History, RecordingBuffer};
+use crate::indent::{self, IndentSettings};
+use crate::job::Job;
+use crate::line_ending::{self, LineEnding};
+use crate::lsp::{self, Diagnostic, LspClient, Reply, Severity};
+use crate::problems::Problem;
+use crate::project::{self, Project, TreeEntry};
+use crate::recent::{RecentEntry, RecentKind, RecentList};
+use crate::symbols::{self, Symbol};
+use crate::tasks::{self, Task, TaskOutput};
+use crate::terminal::{self, Terminal};
+use crate::watcher::DiskChange;
 use directories_next::ProjectDirs;
 use eframe::egui;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::BTreeMap;
 use std::fs;
-use std::io::Write;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::{Duration, Instant};
-use syntect::easy::HighlightLines;
-use syntect::highlighting::{Theme, ThemeSet};
-use syntect::parsing::{SyntaxReference, SyntaxSet};
-use syntect::util::LinesWithEndings;
+use std::sync::Arc;
+use syntect::highlighting::{FontStyle, Style, Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
 
 static UPDATE_CHECK_DONE: AtomicBool = AtomicBool::new(false);
 static SHOULD_SHOW_UPDATE: OnceCell<(String, String)> = OnceCell::new();
 static UPDATE_DIALOG_SHOWN: AtomicBool = AtomicBool::new(false);
 
-static mut TERMINAL_PTY: Option<portable_pty::PtyPair> = None;
-static mut TERMINAL_OUTPUT: Option<String> = None;
-static mut TERMINAL_INPUT: Option<String> = None;
-static mut TERMINAL_WRITER: Option<Box<dyn Write + Send>> = None;
+static mut TERMINAL: Option<Terminal> = None;
 static mut TERMINAL_OPEN: bool = false;
+/// Height of the terminal panel, which the editor leaves room for.
+static mut TERMINAL_HEIGHT: f32 = 220.0;
+/// Last failure of something running on its own, like autosave, shown in
+/// the status bar until dismissed.
+static mut STATUS_ERROR: Option<String> = None;
 
 #[derive(Deserialize)]
 struct GithubRelease {
@@ -27,8 +47,26 @@ struct GithubRelease {
 }
 
 #[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct EditorSettings {
     pub font_size: f32,
+    /// Keep each file's undo history on disk so it survives restarts.
+    pub persistent_undo: bool,
+    /// Keep the previous contents as `file~` when saving over a file.
+    pub backup_on_save: bool,
+    pub autosave: AutosaveMode,
+    /// How long a file goes unedited before `AutosaveMode::AfterDelay`
+    /// saves it.
+    pub autosave_delay_ms: u64,
+    /// Name of the syntect theme, built in or from the `themes` folder.
+    pub theme: String,
+    /// Indentation for languages without an override.
+    pub indent: IndentSettings,
+    /// Per-language indentation, keyed by syntax name.
+    pub language_indent: BTreeMap<String, IndentSettings>,
+    /// Language server command lines, keyed by syntax name. An empty
+    /// command turns the server off.
+    pub language_servers: BTreeMap<String, String>,
 }
 
 #[derive(Default)]
@@ -42,20 +80,49 @@ pub enum ViewType {
 pub struct SearchState {
     open: bool,
     query: String,
+    replacement: String,
     case_sensitive: bool,
+    whole_word: bool,
+    /// Treat the query as a regex; replacements may then use `$1`, `${name}`.
+    regex: bool,
+    in_selection: bool,
+    /// Byte range searched when `in_selection` is set, kept in step with edits.
+    scope: Option<std::ops::Range<usize>>,
+    /// Compiled query, `None` when empty or invalid.
+    pattern: Option<regex::Regex>,
+    error: Option<String>,
     current_match: usize,
     matches: Vec<(usize, usize)>,
+    /// Document and buffer revision `matches` were computed for.
+    doc_id: u64,
+    revision: u64,
+    /// Bumped whenever the highlighted matches change.
+    generation: u64,
 }
 
+const DEFAULT_THEME: &str = "base16-ocean.dark";
+
 pub struct EditorState {
-    ps: SyntaxSet,
-    syntax: Option<SyntaxReference>,
-    theme: Theme,
-    cached_highlights: Vec<(egui::TextFormat, String)>,
-    last_text: String, // rhythm game waiter be like: this is your last dish
-    last_update: Instant,
-    is_typing: bool,
+    ps: Arc<SyntaxSet>,
+    themes: ThemeSet,
+    highlighter: Highlighter,
+    theme_name: String,
+    /// The editor galley, with the line of each of its rows for the gutter.
+    galley_cache: Option<(GalleyKey, Arc<egui::Galley>, Arc<[usize]>)>,
+}
+/// Everything the editor galley depends on; layout is skipped while it
+/// stays the same.
+#[derive(PartialEq)]
+struct GalleyKey {
+    doc_id: u64,
+    revision: u64,
+    /// Lines of the document that went into the galley.
+    lines: std::ops::Range<usize>,
+    highlights: u64,
+    search: Option<u64>,
+    wrap_width: u32,
 }
+
 fn compare_versions(current: &str, latest: &str) -> bool {
     println!(
         "Comparing versions - Current: {}, Latest: {}",
@@ -127,187 +194,300 @@ fn compare_versions(current: &str, latest: &str) -> bool {
     false
 }
 impl EditorState {
-    pub fn new() -> Self {
-        let ps = SyntaxSet::load_defaults_newlines();
-        let ts = ThemeSet::load_defaults();
-        let theme = ts.themes["base16-ocean.dark"].clone();
+    /// Loads the built-in syntaxes and themes plus the user's own from the
+    /// `syntaxes` and `themes` folders in the config dir.
+    pub fn new(ctx: &egui::Context) -> Self {
+        let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+        let mut ts = ThemeSet::load_defaults();
+        if let Some(proj_dirs) = ProjectDirs::from("dev", "nijika", "kokona") {
+            let config_dir = proj_dirs.config_dir();
+            let syntaxes = config_dir.join("syntaxes");
+            if syntaxes.is_dir() {
+                if let Err(e) = builder.add_from_folder(&syntaxes, true) {
+                    println!("Failed to load syntaxes: {}", e);
+                }
+            }
+            let themes = config_dir.join("themes");
+            if themes.is_dir() {
+                if let Err(e) = ts.add_from_folder(&themes) {
+                    println!("Failed to load themes: {}", e);
+                }
+            }
+        }
+        let ps = Arc::new(builder.build());
 
+        let (name, theme) = Self::find_theme(&ts, &current_settings().theme);
+        apply_theme_visuals(ctx, &theme);
         Self {
+            highlighter: Highlighter::new(ctx, ps.clone(), theme),
             ps,
-            syntax: None,
-            theme,
-            cached_highlights: Vec::new(),
-            last_text: String::new(),
-            last_update: Instant::now(),
-            is_typing: false,
+            themes: ts,
+            theme_name: name,
+            galley_cache: None,
+        }
+    }
+
+    /// Falls back to the default theme when `name` is gone, e.g. its file
+    /// was removed from the themes folder.
+    fn find_theme(ts: &ThemeSet, name: &str) -> (String, Theme) {
+        match ts.themes.get(name) {
+            Some(theme) => (name.to_string(), theme.clone()),
+            None => (DEFAULT_THEME.to_string(), ts.themes[DEFAULT_THEME].clone()),
+        }
+    }
+
+    pub fn theme_names(&self) -> impl Iterator<Item = &String> {
+        self.themes.themes.keys()
+    }
+
+    pub fn theme_name(&self) -> &str {
+        &self.theme_name
+    }
+
+    pub fn set_theme(&mut self, ctx: &egui::Context, name: &str) {
+        if name == self.theme_name {
+            return;
         }
+        let (name, theme) = Self::find_theme(&self.themes, name);
+        apply_theme_visuals(ctx, &theme);
+        self.highlighter.set_theme(theme);
+        self.theme_name = name;
+        self.invalidate();
     }
 
-    pub fn set_syntax_for_extension(&mut self, filename: &str) {
-        self.syntax = self
-            .ps
+    pub fn syntax_name_for(&self, filename: &str) -> String {
+        self.ps
             .find_syntax_for_file(filename)
             .ok()
             .flatten()
-            .cloned();
-    }
-
-    pub fn get_or_update_highlights(&mut self, text: &str) -> &Vec<(egui::TextFormat, String)> {
-        if self.last_text != text {
-            self.last_text = text.to_string();
-            self.is_typing = true;
-            self.last_update = Instant::now();
-
-            let line_count = text.matches('\n').count() + 1;
-            //println!("Line count: {}", line_count);
-
-            if line_count > 500 {
-                //println!("Large file detected, using plain text");
-                // if file exceeds 500 lines, disable real-time highlighting for performance
-                self.cached_highlights = vec![(
-                    egui::TextFormat {
-                        font_id: egui::FontId::monospace(unsafe {
-                            SETTINGS.as_ref().map_or(12.0, |s| s.font_size)
-                        }),
-                        ..Default::default()
-                    },
-                    text.to_string(),
-                )];
-                return &self.cached_highlights;
-            }
-
-            // Only reach this code for small files
-            if let Some(syntax) = &self.syntax {
-                let mut h = HighlightLines::new(syntax, &self.theme);
-                let mut highlights = Vec::new();
-
-                for line in LinesWithEndings::from(text) {
-                    if let Ok(line_highlights) = h.highlight_line(line, &self.ps) {
-                        for (style, text) in line_highlights {
-                            let format = egui::TextFormat {
-                                color: egui::Color32::from_rgb(
-                                    style.foreground.r,
-                                    style.foreground.g,
-                                    style.foreground.b,
-                                ),
-                                font_id: egui::FontId::monospace(unsafe {
-                                    SETTINGS.as_ref().map_or(12.0, |s| s.font_size)
-                                }),
-                                ..Default::default()
-                            };
-                            highlights.push((format, text.to_string()));
-                        }
-                    }
-                }
+            .unwrap_or_else(|| self.ps.find_syntax_plain_text())
+            .name
+            .clone()
+    }
 
-                self.cached_highlights = highlights;
-            } else {
-                self.cached_highlights = vec![(
-                    egui::TextFormat {
-                        font_id: egui::FontId::monospace(unsafe {
-                            SETTINGS.as_ref().map_or(12.0, |s| s.font_size)
-                        }),
-                        ..Default::default()
-                    },
-                    text.to_string(),
-                )];
-            }
-        }
+    /// Forces the layout to be rebuilt, e.g. after a font change.
+    pub fn invalidate(&mut self) {
+        self.galley_cache = None;
+    }
+}
 
-        // Check if we should update syntax highlighting for large files
-        if self.is_typing && self.last_update.elapsed() >= Duration::from_millis(500) {
-            let line_count = self.last_text.matches('\n').count() + 1;
-            if line_count > 500 {
-                self.is_typing = false;
-                let current_text = self.last_text.clone();
-                self.update_highlights(&current_text);
-            }
-        }
+/// Switches egui between its light and dark look to match the theme, and
+/// uses the theme's own background behind the text.
+fn apply_theme_visuals(ctx: &egui::Context, theme: &Theme) {
+    let Some(bg) = theme.settings.background else {
+        ctx.set_visuals(egui::Visuals::dark());
+        return;
+    };
+    let luminance = 0.299 * bg.r as f32 + 0.587 * bg.g as f32 + 0.114 * bg.b as f32;
+    let mut visuals = if luminance < 128.0 {
+        egui::Visuals::dark()
+    } else {
+        egui::Visuals::light()
+    };
+    visuals.extreme_bg_color = egui::Color32::from_rgb(bg.r, bg.g, bg.b);
+    ctx.set_visuals(visuals);
+}
 
-        &self.cached_highlights
+fn style_format(style: &Style, font_id: &egui::FontId) -> egui::TextFormat {
+    let fg = style.foreground;
+    let color = egui::Color32::from_rgb(fg.r, fg.g, fg.b);
+    egui::TextFormat {
+        font_id: font_id.clone(),
+        color,
+        italics: style.font_style.contains(FontStyle::ITALIC),
+        underline: if style.font_style.contains(FontStyle::UNDERLINE) {
+            egui::Stroke::new(1.0, color)
+        } else {
+            egui::Stroke::NONE
+        },
+        ..Default::default()
     }
+}
 
-    pub fn force_highlight_update(&mut self) {
-        self.is_typing = false;
-        let current_text = self.last_text.clone();
-        if let Some(syntax) = &self.syntax {
-            let mut h = HighlightLines::new(syntax, &self.theme);
-            let mut highlights = Vec::new();
-
-            for line in LinesWithEndings::from(&current_text) {
-                if let Ok(line_highlights) = h.highlight_line(line, &self.ps) {
-                    for (style, text) in line_highlights {
-                        let format = egui::TextFormat {
-                            color: egui::Color32::from_rgb(
-                                style.foreground.r,
-                                style.foreground.g,
-                                style.foreground.b,
-                            ),
-                            font_id: egui::FontId::monospace(unsafe {
-                                SETTINGS.as_ref().map_or(12.0, |s| s.font_size)
-                            }),
-                            ..Default::default()
-                        };
-                        highlights.push((format, text.to_string()));
-                    }
+/// Appends `string[range]` to the job, giving the parts covered by search
+/// matches a highlighted background. `next` is the first match that may
+/// still overlap, carried over between calls since ranges come in order.
+fn append_with_matches(
+    job: &mut egui::text::LayoutJob,
+    string: &str,
+    range: std::ops::Range<usize>,
+    format: &egui::TextFormat,
+    matches: &[(usize, usize)],
+    next: &mut usize,
+    current: Option<usize>,
+) {
+    let mut pos = range.start;
+    while *next < matches.len() && matches[*next].1 <= pos {
+        *next += 1;
+    }
+    while pos < range.end {
+        match matches.get(*next) {
+            Some(&(s, e)) if s < range.end => {
+                let s = s.max(pos);
+                let e = e.min(range.end);
+                if s > pos {
+                    job.append(&string[pos..s], 0.0, format.clone());
+                }
+                let mut highlight_format = format.clone();
+                highlight_format.background = if Some(*next) == current {
+                    // Current match highlight - bright yellow
+                    egui::Color32::from_rgb(255, 255, 0)
+                } else {
+                    // Other matches highlight - darker yellow
+                    egui::Color32::from_rgb(180, 180, 0)
+                };
+                job.append(&string[s..e], 0.0, highlight_format);
+                pos = e;
+                if e == matches[*next].1 {
+                    *next += 1;
                 }
             }
-
-            self.cached_highlights = highlights;
+            _ => {
+                job.append(&string[pos..range.end], 0.0, format.clone());
+                pos = range.end;
+            }
         }
     }
+}
 
-    fn update_highlights(&mut self, text: &str) {
-        if let Some(syntax) = &self.syntax {
-            let mut h = HighlightLines::new(syntax, &self.theme);
-            let mut highlights = Vec::new();
-
-            for line in LinesWithEndings::from(text) {
-                if let Ok(line_highlights) = h.highlight_line(line, &self.ps) {
-                    for (style, text) in line_highlights {
-                        let format = egui::TextFormat {
-                            color: egui::Color32::from_rgb(
-                                style.foreground.r,
-                                style.foreground.g,
-                                style.foreground.b,
-                            ),
-                            font_id: egui::FontId::monospace(unsafe {
-                                SETTINGS.as_ref().map_or(12.0, |s| s.font_size)
-                            }),
-                            ..Default::default()
-                        };
-                        highlights.push((format, text.to_string()));
-                    }
-                }
+/// Builds the editor's layout from the per-line highlights. Lines the
+/// highlighter hasn't caught up with yet are drawn plain.
+fn editor_layout_job(
+    string: &str,
+    lines: &[Line],
+    matches: &[(usize, usize)],
+    current: Option<usize>,
+    font_id: &egui::FontId,
+) -> egui::text::LayoutJob {
+    let mut job = egui::text::LayoutJob::default();
+    let plain = egui::TextFormat {
+        font_id: font_id.clone(),
+        ..Default::default()
+    };
+    let mut next_match = 0;
+    let mut line_start = 0;
+    for (i, line) in string.split_inclusive('\n').enumerate() {
+        let mut pos = 0;
+        for (range, style) in lines.get(i).map_or(&[][..], |l| &l[..]) {
+            // Spans from before an edit may no longer fit the line
+            if range.start != pos || range.end > line.len() || !line.is_char_boundary(range.end) {
+                break;
             }
-
-            self.cached_highlights = highlights;
-        } else {
-            self.cached_highlights = vec![(
-                egui::TextFormat {
-                    font_id: egui::FontId::monospace(unsafe {
-                        SETTINGS.as_ref().map_or(12.0, |s| s.font_size)
-                    }),
-                    ..Default::default()
-                },
-                text.to_string(),
-            )];
+            append_with_matches(
+                &mut job,
+                string,
+                line_start + range.start..line_start + range.end,
+                &style_format(style, font_id),
+                matches,
+                &mut next_match,
+                current,
+            );
+            pos = range.end;
         }
+        if pos < line.len() {
+            append_with_matches(
+                &mut job,
+                string,
+                line_start + pos..line_start + line.len(),
+                &plain,
+                matches,
+                &mut next_match,
+                current,
+            );
+        }
+        line_start += line.len();
     }
+    job
 }
-pub static WAS_MODIFIED: AtomicBool = AtomicBool::new(false);
 static mut SEARCH_STATE: Option<SearchState> = None;
 static mut EDITOR_STATE: Option<EditorState> = None;
-static mut CACHED_LINE_NUMBERS: Option<(String, usize)> = None;
 static mut SETTINGS: Option<EditorSettings> = None;
 static mut SETTINGS_WINDOW_OPEN: bool = false;
+/// Snapshots found at startup that haven't been recovered or discarded yet.
+static mut RECOVERY: Option<Vec<Snapshot>> = None;
+static mut DIFF_VIEW: Option<DiffView> = None;
+static mut RECENT: Option<RecentList> = None;
+static mut PROJECT: Option<Project> = None;
+static mut EXPLORER_OPEN: bool = false;
+static mut EXPLORER_PROMPT: Option<ExplorerPrompt> = None;
+static mut LSP: Option<LspClient> = None;
+static mut COMPLETION: Option<CompletionPopup> = None;
+static mut HOVER: Option<HoverState> = None;
+static mut RENAME_PROMPT: Option<RenamePrompt> = None;
+static mut PROBLEMS: Vec<Problem> = Vec::new();
+/// Canonical form of document paths, the way `PROBLEMS` has them. Kept
+/// until the list changes rather than asking the disk every frame.
+static mut PROBLEM_PATHS: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
+static mut PROBLEMS_OPEN: bool = false;
+static mut TASK_PICKER: Option<TaskPicker> = None;
+static mut JOB: Option<Job> = None;
+static mut OUTPUT_OPEN: bool = false;
 
 impl Default for EditorSettings {
     fn default() -> Self {
-        Self { font_size: 12.0 }
+        Self {
+            font_size: 12.0,
+            persistent_undo: false,
+            backup_on_save: false,
+            autosave: AutosaveMode::default(),
+            autosave_delay_ms: 1000,
+            theme: DEFAULT_THEME.to_string(),
+            indent: IndentSettings::default(),
+            language_indent: BTreeMap::from([
+                (
+                    "Go".to_string(),
+                    IndentSettings {
+                        tab_width: 4,
+                        use_tabs: true,
+                    },
+                ),
+                (
+                    "Makefile".to_string(),
+                    IndentSettings {
+                        tab_width: 4,
+                        use_tabs: true,
+                    },
+                ),
+                (
+                    "YAML".to_string(),
+                    IndentSettings {
+                        tab_width: 2,
+                        use_tabs: false,
+                    },
+                ),
+            ]),
+            language_servers: BTreeMap::from([
+                ("Rust".to_string(), "rust-analyzer".to_string()),
+                ("C".to_string(), "clangd".to_string()),
+                ("C++".to_string(), "clangd".to_string()),
+                (
+                    "Python".to_string(),
+                    "pyright-langserver --stdio".to_string(),
+                ),
+            ]),
+        }
+    }
+}
+
+/// Snapshot of the settings, loading them from disk on first use.
+pub fn current_settings() -> EditorSettings {
+    unsafe {
+        let settings = &mut *std::ptr::addr_of_mut!(SETTINGS);
+        settings.get_or_insert_with(EditorSettings::load).clone()
     }
 }
 impl EditorSettings {
+    /// Indentation for documents in `syntax`.
+    pub fn indent_for(&self, syntax: &str) -> IndentSettings {
+        let mut indent = self
+            .language_indent
+            .get(syntax)
+            .copied()
+            .unwrap_or(self.indent);
+        indent.tab_width = indent.tab_width.max(1);
+        indent
+    }
+
     pub fn load() -> Self {
         if let Some(proj_dirs) = ProjectDirs::from("dev", "nijika", "kokona") {
             let config_dir = proj_dirs.config_dir();
@@ -337,39 +517,176 @@ impl EditorSettings {
     }
 }
 impl SearchState {
-    fn find_matches(&mut self, text: &str) {
-        self.matches.clear();
+    fn compile(&mut self) {
+        self.pattern = None;
+        self.error = None;
         if self.query.is_empty() {
             return;
         }
+        match compile_query(
+            &self.query,
+            self.regex,
+            self.whole_word,
+            self.case_sensitive,
+        ) {
+            Ok(re) => self.pattern = Some(re),
+            Err(e) => self.error = Some(e),
+        }
+    }
 
-        let text_to_search = if self.case_sensitive {
-            text.to_string()
-        } else {
-            text.to_lowercase()
+    fn find_matches(&mut self, text: &Buffer, doc_id: u64) {
+        self.compile();
+        self.doc_id = doc_id;
+        self.revision = text.revision();
+        self.generation += 1;
+        self.matches = self.find_in(&text.to_string(), 0);
+        if self.current_match >= self.matches.len() {
+            self.current_match = 0;
+        }
+    }
+
+    /// Limits the search to `selection` (bytes), or lifts the limit.
+    fn set_scope(&mut self, selection: Option<std::ops::Range<usize>>) {
+        self.scope = selection.filter(|range| !range.is_empty());
+        self.in_selection = self.scope.is_some();
+    }
+
+    /// Brings the matches up to date with the buffer, re-searching only the
+    /// lines touched since the last call.
+    fn update(&mut self, text: &Buffer, doc_id: u64) {
+        if doc_id != self.doc_id {
+            self.set_scope(None);
+            self.find_matches(text, doc_id);
+            return;
+        }
+        let delta = match text.changes_since(self.revision) {
+            Change::Unchanged => return,
+            Change::Replaced => {
+                self.set_scope(None);
+                self.find_matches(text, doc_id);
+                return;
+            }
+            Change::Edited(delta) => delta,
         };
-        let query = if self.case_sensitive {
-            self.query.clone()
-        } else {
-            self.query.to_lowercase()
+        if let Some(scope) = &mut self.scope {
+            let old_end = delta.start + delta.old_len;
+            if delta.start < scope.start {
+                scope.start = delta.start.max(delta.shift(scope.start.max(old_end)));
+            }
+            if delta.start <= scope.end {
+                scope.end = delta.shift(scope.end.max(old_end));
+            }
+        }
+        // A regex may match across lines, so only plain queries can be
+        // patched up line by line
+        if self.regex {
+            self.find_matches(text, doc_id);
+            return;
+        }
+        self.revision = text.revision();
+        self.generation += 1;
+
+        // Matches never span lines, so whole lines around the edit are enough
+        let span = text.line_span(delta.start..delta.start + delta.new_len);
+        let old_end = span.end + delta.old_len - delta.new_len;
+        let found = self.find_in(&text.byte_slice(span.clone()), span.start);
+        let keep = self.matches.partition_point(|&(_, e)| e <= span.start);
+        let after = self.matches.partition_point(|&(s, _)| s < old_end);
+        let shifted: Vec<_> = self.matches[after..]
+            .iter()
+            .map(|&(s, e)| (delta.shift(s), delta.shift(e)))
+            .collect();
+        self.matches.truncate(keep);
+        self.matches.extend(found);
+        self.matches.extend(shifted);
+        if self.current_match >= self.matches.len() {
+            self.current_match = 0;
+        }
+    }
+
+    /// Byte offsets of the non-empty matches in `text`, a piece of the buffer
+    /// starting at byte `offset`, clipped to the scope. Pieces start at a
+    /// line start, where `^` and `\b` behave the same as in the whole text.
+    fn find_in(&self, text: &str, offset: usize) -> Vec<(usize, usize)> {
+        let mut matches = Vec::new();
+        let Some(re) = &self.pattern else {
+            return matches;
         };
+        let range = match &self.scope {
+            Some(scope) if self.in_selection => {
+                let end = scope.end.saturating_sub(offset).min(text.len());
+                scope.start.saturating_sub(offset).min(end)..end
+            }
+            _ => 0..text.len(),
+        };
+        let haystack = &text[..range.end];
+        let mut at = range.start;
+        while at <= range.end {
+            let Some(found) = re.find_at(haystack, at) else {
+                break;
+            };
+            if found.is_empty() {
+                at = found.end()
+                    + haystack[found.end()..]
+                        .chars()
+                        .next()
+                        .map_or(1, char::len_utf8);
+                continue;
+            }
+            matches.push((offset + found.start(), offset + found.end()));
+            at = found.end();
+        }
+        matches
+    }
 
-        let mut start = 0;
-        while let Some(found) = text_to_search[start..].find(&query) {
-            let match_start = start + found;
-            let match_end = match_start + query.len();
-            self.matches.push((match_start, match_end));
-            start = match_end;
+    /// Text a match would be replaced with, capture groups expanded.
+    fn replacement_for(&self, text: &Buffer, (start, end): (usize, usize)) -> String {
+        match &self.pattern {
+            Some(re) if self.regex => {
+                let mut expanded = String::new();
+                let span = text.line_span(start..end);
+                let lines = text.byte_slice(span.start..end);
+                if let Some(caps) = re.captures_at(&lines, start - span.start) {
+                    caps.expand(&self.replacement, &mut expanded);
+                }
+                expanded
+            }
+            _ => self.replacement.clone(),
         }
     }
 
+    /// Edit replacing the current match, if there is one.
+    fn replace_current(&self, text: &Buffer) -> Option<Edit> {
+        let &(start, end) = self.matches.get(self.current_match)?;
+        Some(Edit {
+            at: text.byte_to_char(start),
+            removed: text.byte_slice(start..end).into_owned(),
+            inserted: self.replacement_for(text, (start, end)),
+        })
+    }
+
+    /// Edits replacing every match, last first so earlier offsets stay valid.
+    fn replace_all(&self, text: &Buffer) -> Vec<Edit> {
+        self.matches
+            .iter()
+            .rev()
+            .map(|&(start, end)| Edit {
+                at: text.byte_to_char(start),
+                removed: text.byte_slice(start..end).into_owned(),
+                inserted: self.replacement_for(text, (start, end)),
+            })
+            .collect()
+    }
+
     fn next_match(&mut self) {
+        self.generation += 1;
         if !self.matches.is_empty() {
             self.current_match = (self.current_match + 1) % self.matches.len();
         }
     }
 
     fn prev_match(&mut self) {
+        self.generation += 1;
         if !self.matches.is_empty() {
             self.current_match = if self.current_match == 0 {
                 self.matches.len() - 1
@@ -386,119 +703,110 @@ static mut INSERT_OPEN: bool = false;
 
 static mut COMMIT_WINDOW_OPEN: bool = false;
 static mut COMMIT_MESSAGE: String = String::new();
+static mut FIND_IN_FILES: Option<FindInFiles> = None;
+/// Document whose editor should scroll to its cursor on the next frame.
+static mut REVEAL_CURSOR: Option<u64> = None;
+static mut GOTO_LINE_OPEN: bool = false;
+static mut GOTO_LINE_INPUT: String = String::new();
+static mut SYMBOL_PICKER: Option<SymbolPicker> = None;
+
+/// Ctrl+Shift+O list of the active document's symbols.
+struct SymbolPicker {
+    doc_id: u64,
+    revision: u64,
+    symbols: Vec<Symbol>,
+    filter: String,
+    selected: usize,
+}
+
+impl SymbolPicker {
+    /// Symbols whose name contains every character of the filter, in order.
+    fn visible(&self) -> Vec<&Symbol> {
+        let filter = self.filter.to_lowercase();
+        self.symbols
+            .iter()
+            .filter(|symbol| {
+                let mut name = symbol
+                    .name
+                    .to_lowercase()
+                    .chars()
+                    .collect::<Vec<_>>()
+                    .into_iter();
+                filter.chars().all(|c| name.any(|n| n == c))
+            })
+            .collect()
+    }
+}
 static mut COMMIT_RESULT: Option<Result<std::process::Output, std::io::Error>> = None;
 
 static mut UNICHAR: String = String::new();
 
-pub fn show_top_panel(
-    ctx: &egui::Context,
-    filename: &mut String,
-    text_content: &mut String,
-    current_view: &mut ViewType,
-) {
-    let content_clone = text_content.clone();
+pub fn show_top_panel(ctx: &egui::Context, tabs: &mut Tabs, current_view: &mut ViewType) {
+    let filename = tabs.active().map(Document::filename).unwrap_or_default();
     egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
         egui::menu::bar(ui, |ui| {
             ui.menu_button("Kokona", |ui| {
                 if ui.button("New").clicked() {
-                    *text_content = String::new();
-                    *filename = "untitled.txt".to_string();
+                    tabs.new_document();
                     *current_view = ViewType::Editor;
-                    unsafe {
-                        if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                            editor_state.set_syntax_for_extension(&filename);
-                        }
-                    }
                     ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
-                    WAS_MODIFIED.store(false, Ordering::SeqCst);
                     ui.close_menu();
                 }
                 if ui.button("Open").clicked() {
-                    if let Some(path) = rfd::FileDialog::new().set_title("Open File").pick_file() {
-                        // Read the file contents first
-                        match std::fs::read_to_string(&path) {
-                            Ok(content) => {
-                                *text_content = content; // Update the TextEdit content
-                                *filename = path.display().to_string(); // Set filename to the path
-                                *current_view = ViewType::Editor; // Switch view after content is loaded
-                                unsafe {
-                                    if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                                        editor_state.set_syntax_for_extension(&filename);
-                                    }
-                                }
-                                ctx.send_viewport_cmd(egui::ViewportCommand::Title(
-                                    "Kokona".into(),
-                                ));
-                                println!("File opened successfully from: {}", path.display());
-                            }
-                            Err(e) => {
-                                rfd::MessageDialog::new()
-                                    .set_title("Error")
-                                    .set_description(&format!("Error opening file: {}", e))
-                                    .set_level(rfd::MessageLevel::Error)
-                                    .show();
-                            }
-                        }
-                    }
+                    open_file_dialog(ctx, tabs, current_view);
+                    ui.close_menu();
+                }
+                if ui.button("Open Folder").clicked() {
+                    open_folder_dialog();
+                    ui.close_menu();
+                }
+                if project_root().is_some() && ui.button("Close Folder").clicked() {
+                    set_project(None);
                     ui.close_menu();
                 }
                 if ui.button("Save").clicked() {
-                    if filename == "untitled.txt" {
-                        if let Some(path) = rfd::FileDialog::new()
-                            .set_title("Save")
-                            .set_file_name(&filename[..])
-                            .save_file()
-                        {
-                            // Save the contents to the file
-                            if let Err(e) = std::fs::write(&path, &text_content) {
-                                println!("Error saving file: {}", e);
-                            } else {
-                                println!("File saved successfully to: {}", path.display());
-                                WAS_MODIFIED.store(false, Ordering::SeqCst);
-                            }
-                            unsafe {
-                                if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                                    editor_state.force_highlight_update();
-                                }
-                            }
-                            *filename = path.display().to_string();
-                            ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
-                        }
-                    } else {
-                        // Save directly to existing path
-                        if let Err(e) = std::fs::write(&filename, &text_content) {
-                            println!("Error saving file: {}", e);
-                        } else {
-                            println!("File saved successfully to: {}", filename);
-                            WAS_MODIFIED.store(false, Ordering::SeqCst);
-                        }
-                        ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
+                    if let Some(doc) = tabs.active_mut() {
+                        save_document(ctx, doc, false);
                     }
                     ui.close_menu();
                 }
                 if ui.button("Save As").clicked() {
-                    if let Some(path) = rfd::FileDialog::new()
-                        .set_title("Save As")
-                        .set_file_name(&filename[..])
-                        .save_file()
-                    {
-                        // Save the contents to the file
-                        if let Err(e) = std::fs::write(&path, text_content) {
-                            println!("Error saving file: {}", e);
-                        } else {
-                            println!("File saved successfully to: {}", path.display());
-                            WAS_MODIFIED.store(false, Ordering::SeqCst);
+                    if let Some(doc) = tabs.active_mut() {
+                        save_document(ctx, doc, true);
+                    }
+                    ui.close_menu();
+                }
+                let has_path = tabs.active().is_some_and(|doc| !doc.is_untitled());
+                ui.add_enabled_ui(has_path, |ui| {
+                    ui.menu_button("Reopen with Encoding…", |ui| {
+                        for &encoding in encoding::CHOICES {
+                            if ui.button(encoding.name()).clicked() {
+                                if let Some(doc) = tabs.active_mut() {
+                                    if let Err(e) = doc.reopen_with(encoding) {
+                                        println!("Error reopening file: {}", e);
+                                    }
+                                    ctx.send_viewport_cmd(egui::ViewportCommand::Title(
+                                        "Kokona".into(),
+                                    ));
+                                }
+                                ui.close_menu();
+                            }
                         }
-                        unsafe {
-                            if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                                editor_state.force_highlight_update();
+                    });
+                });
+                ui.menu_button("Save with Encoding…", |ui| {
+                    for &encoding in encoding::CHOICES {
+                        if ui.button(encoding.name()).clicked() {
+                            if let Some(doc) = tabs.active_mut() {
+                                let previous = std::mem::replace(&mut doc.encoding, encoding);
+                                if !save_document(ctx, doc, false) {
+                                    doc.encoding = previous;
+                                }
                             }
+                            ui.close_menu();
                         }
-                        *filename = path.display().to_string();
-                        ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
                     }
-                    ui.close_menu();
-                }
+                });
                 if ui.button("Settings").clicked() {
                     unsafe {
                         SETTINGS_WINDOW_OPEN = true;
@@ -506,10 +814,7 @@ pub fn show_top_panel(
                     ui.close_menu();
                 }
                 if ui.button("Close").clicked() {
-                    *filename = String::from("");
-                    *current_view = ViewType::Home;
-                    WAS_MODIFIED.store(false, Ordering::SeqCst);
-                    ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
+                    tabs.request_close(tabs.active);
                     ui.close_menu();
                 }
                 if ui.button("Exit").clicked() {
@@ -518,27 +823,46 @@ pub fn show_top_panel(
                 }
             });
             ui.menu_button("File", |ui| {
+                ui.menu_button("Open Recent", |ui| {
+                    let files = recent_menu_ui(ui, RecentKind::File);
+                    ui.separator();
+                    let folders = recent_menu_ui(ui, RecentKind::Folder);
+                    if let Some(action) = files.or(folders) {
+                        apply_recent_action(ctx, tabs, current_view, action);
+                        ui.close_menu();
+                    }
+                });
+                if ui.button("Find in Files").clicked() {
+                    unsafe {
+                        let find = &mut *std::ptr::addr_of_mut!(FIND_IN_FILES);
+                        find.get_or_insert_with(FindInFiles::default).open = true;
+                    }
+                    ui.close_menu();
+                }
                 if ui.button("Search").clicked() {
                     unsafe {
                         if SEARCH_STATE.is_none() {
                             SEARCH_STATE = Some(SearchState::default());
                         }
-                        if let Some(state) = SEARCH_STATE.as_mut() {
+                        if let (Some(state), Some(doc)) = (SEARCH_STATE.as_mut(), tabs.active()) {
                             state.open = true;
-                            state.find_matches(&content_clone);
+                            state.find_matches(&doc.text, doc.id);
                         }
                     }
                     ui.close_menu();
                 }
                 if ui.button("New file in working directory").clicked() {
-                    if let Some(path) = rfd::FileDialog::new()
-                        .set_title("New file in working directory")
-                        .save_file()
-                    {
+                    let mut dialog =
+                        rfd::FileDialog::new().set_title("New file in working directory");
+                    if let Some(root) = project_root() {
+                        dialog = dialog.set_directory(root);
+                    }
+                    if let Some(path) = dialog.save_file() {
                         let newpath = path.display().to_string();
                         if let Err(e) = std::fs::write(&newpath, "") {
                             println!("Error creating file: {}", e);
                         }
+                        forget_listing(&path);
                     }
                     ui.close_menu();
                 }
@@ -552,257 +876,88 @@ pub fn show_top_panel(
                     unsafe { TERMINAL_OPEN = !TERMINAL_OPEN };
                     ui.close_menu();
                 }
-                if filename.ends_with(".rs") {
-                    ui.menu_button("Rust options", |ui| {
-                        if ui.button("Build").clicked() {
-                            unsafe {
-                                GIT_RESULT_OPEN = true;
-                                let file_path = std::path::Path::new(&filename);
-                                let parent_dir =
-                                    file_path.parent().unwrap_or(std::path::Path::new(""));
-
-                                GIT_RESULT = Some(
-                                    std::process::Command::new("cargo")
-                                        .current_dir(parent_dir)
-                                        .arg("build")
-                                        .output(),
-                                );
-                            }
-                            ui.close_menu();
-                        }
-                        if ui.button("Run").clicked() {
-                            unsafe {
-                                TERMINAL_OPEN = true;
-                                if TERMINAL_PTY.is_none() {
-                                    if let Ok(pty_pair) = create_pty() {
-                                        TERMINAL_PTY = Some(pty_pair);
-                                        TERMINAL_OUTPUT = Some(String::new());
-                                        TERMINAL_INPUT = Some(String::new());
-
-                                        if let Some(pty_pair) = &TERMINAL_PTY {
-                                            if let Ok(writer) = pty_pair.master.take_writer() {
-                                                TERMINAL_WRITER = Some(writer);
-                                            }
-
-                                            let mut cmd =
-                                                portable_pty::CommandBuilder::new("cargo");
-                                            cmd.env("TERM", "dumb");
-                                            cmd.arg("run");
-
-                                            if let Some(parent) =
-                                                std::path::Path::new(&filename).parent()
-                                            {
-                                                cmd.cwd(parent);
-                                            }
-
-                                            if let Some(writer) = &mut TERMINAL_WRITER {
-                                                if let Ok(child) = pty_pair.slave.spawn_command(cmd)
-                                                {
-                                                    let mut reader =
-                                                        pty_pair.master.try_clone_reader().unwrap();
-                                                    std::thread::spawn(move || {
-                                                        let mut buffer = [0u8; 1024];
-                                                        loop {
-                                                            match reader.read(&mut buffer) {
-                                                                Ok(0) => break,
-                                                                Ok(n) => {
-                                                                    let str =
-                                                                        String::from_utf8_lossy(
-                                                                            &buffer[..n],
-                                                                        )
-                                                                        .into_owned();
-                                                                    unsafe {
-                                                                        if let Some(output) =
-                                                                            &mut TERMINAL_OUTPUT
-                                                                        {
-                                                                            output.push_str(&str);
-                                                                        }
-                                                                    }
-                                                                }
-                                                                Err(_) => break,
-                                                            }
-                                                        }
-                                                    });
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            ui.close_menu();
-                        }
-                        if ui.button("Check").clicked() {
-                            unsafe {
-                                GIT_RESULT_OPEN = true;
-                                let file_path = std::path::Path::new(&filename);
-                                let parent_dir =
-                                    file_path.parent().unwrap_or(std::path::Path::new(""));
-
-                                GIT_RESULT = Some(
-                                    std::process::Command::new("cargo")
-                                        .current_dir(parent_dir)
-                                        .arg("check")
-                                        .output(),
-                                );
-                            }
-                            ui.close_menu();
-                        }
-                    });
+                if ui.button("Problems").clicked() {
+                    unsafe { PROBLEMS_OPEN = !PROBLEMS_OPEN };
+                    ui.close_menu();
                 }
-                if filename.ends_with(".py") {
-                    ui.menu_button("Python options", |ui| {
-                        if ui.button("Run").clicked() {
-                            unsafe {
-                                TERMINAL_OPEN = true;
-                                if TERMINAL_PTY.is_none() {
-                                    if let Ok(pty_pair) = create_pty() {
-                                        TERMINAL_PTY = Some(pty_pair);
-                                        TERMINAL_OUTPUT = Some(String::new());
-                                        TERMINAL_INPUT = Some(String::new());
-
-                                        if let Some(pty_pair) = &TERMINAL_PTY {
-                                            if let Ok(writer) = pty_pair.master.take_writer() {
-                                                TERMINAL_WRITER = Some(writer);
-                                            }
-
-                                            let mut cmd =
-                                                portable_pty::CommandBuilder::new("python");
-                                            cmd.env("TERM", "dumb");
-                                            cmd.arg(&filename);
-
-                                            if let Some(parent) =
-                                                std::path::Path::new(filename).parent()
-                                            {
-                                                cmd.cwd(parent);
-                                            }
+                if ui
+                    .add_enabled(
+                        project_root().is_some(),
+                        egui::Button::new("Toggle explorer"),
+                    )
+                    .clicked()
+                {
+                    unsafe { EXPLORER_OPEN = !EXPLORER_OPEN };
+                    ui.close_menu();
+                }
+            });
 
-                                            if let Some(writer) = &mut TERMINAL_WRITER {
-                                                if let Ok(child) = pty_pair.slave.spawn_command(cmd)
-                                                {
-                                                    let mut reader =
-                                                        pty_pair.master.try_clone_reader().unwrap();
-                                                    std::thread::spawn(move || {
-                                                        let mut buffer = [0u8; 1024];
-                                                        loop {
-                                                            match reader.read(&mut buffer) {
-                                                                Ok(0) => break,
-                                                                Ok(n) => {
-                                                                    let str =
-                                                                        String::from_utf8_lossy(
-                                                                            &buffer[..n],
-                                                                        )
-                                                                        .into_owned();
-                                                                    unsafe {
-                                                                        if let Some(output) =
-                                                                            &mut TERMINAL_OUTPUT
-                                                                        {
-                                                                            output.push_str(&str);
-                                                                        }
-                                                                    }
-                                                                }
-                                                                Err(_) => break,
-                                                            }
-                                                        }
-                                                    });
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            ui.close_menu();
+            ui.menu_button("Edit", |ui| {
+                let (can_undo, can_redo) = tabs.active().map_or((false, false), |d| {
+                    (d.history.can_undo(), d.history.can_redo())
+                });
+                if ui
+                    .add_enabled(can_undo, egui::Button::new("Undo"))
+                    .clicked()
+                {
+                    if let Some(doc) = tabs.active_mut() {
+                        if let Some(cursor) = doc.undo() {
+                            set_cursor(ctx, doc.id, cursor);
                         }
-                    });
+                    }
+                    ui.close_menu();
                 }
-                if filename.ends_with(".c") {
-                    ui.menu_button("C options", |ui| {
-                        if ui.button("Build").clicked() {
-                            unsafe {
-                                GIT_RESULT_TEXT = String::from("Project built successfully");
-                                GIT_RESULT_OPEN = true;
-                                let file_path = std::path::Path::new(&filename);
-                                let parent_dir =
-                                    file_path.parent().unwrap_or(std::path::Path::new(""));
-                                let output_name = file_path.file_stem().unwrap().to_str().unwrap();
-
-                                GIT_RESULT = Some(
-                                    std::process::Command::new("gcc")
-                                        .current_dir(parent_dir)
-                                        .arg(&filename)
-                                        .arg("-o")
-                                        .arg(output_name)
-                                        .output(),
-                                );
-                            }
-                            ui.close_menu();
-                        }
-                        if ui.button("Run").clicked() {
-                            unsafe {
-                                TERMINAL_OPEN = true;
-                                if TERMINAL_PTY.is_none() {
-                                    if let Ok(pty_pair) = create_pty() {
-                                        TERMINAL_PTY = Some(pty_pair);
-                                        TERMINAL_OUTPUT = Some(String::new());
-                                        TERMINAL_INPUT = Some(String::new());
-
-                                        if let Some(pty_pair) = &TERMINAL_PTY {
-                                            if let Ok(writer) = pty_pair.master.take_writer() {
-                                                TERMINAL_WRITER = Some(writer);
-                                            }
-
-                                            let file_path = std::path::Path::new(&filename);
-                                            let parent_dir = file_path
-                                                .parent()
-                                                .unwrap_or(std::path::Path::new(""));
-                                            let output_name =
-                                                file_path.file_stem().unwrap().to_str().unwrap();
-
-                                            let mut cmd = portable_pty::CommandBuilder::new(
-                                                format!("./{}", output_name),
-                                            );
-                                            cmd.env("TERM", "dumb");
-                                            cmd.cwd(parent_dir);
-
-                                            if let Some(writer) = &mut TERMINAL_WRITER {
-                                                if let Ok(child) = pty_pair.slave.spawn_command(cmd)
-                                                {
-                                                    let mut reader =
-                                                        pty_pair.master.try_clone_reader().unwrap();
-                                                    std::thread::spawn(move || {
-                                                        let mut buffer = [0u8; 1024];
-                                                        loop {
-                                                            match reader.read(&mut buffer) {
-                                                                Ok(0) => break,
-                                                                Ok(n) => {
-                                                                    let str =
-                                                                        String::from_utf8_lossy(
-                                                                            &buffer[..n],
-                                                                        )
-                                                                        .into_owned();
-                                                                    unsafe {
-                                                                        if let Some(output) =
-                                                                            &mut TERMINAL_OUTPUT
-                                                                        {
-                                                                            output.push_str(&str);
-                                                                        }
-                                                                    }
-                                                                }
-                                                                Err(_) => break,
-                                                            }
-                                                        }
-                                                    });
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            ui.close_menu();
+                if ui
+                    .add_enabled(can_redo, egui::Button::new("Redo"))
+                    .clicked()
+                {
+                    if let Some(doc) = tabs.active_mut() {
+                        if let Some(cursor) = doc.redo() {
+                            set_cursor(ctx, doc.id, cursor);
                         }
-                    });
+                    }
+                    ui.close_menu();
                 }
             });
 
+            ui.menu_button("Run", |ui| {
+                let vars = task_variables(tabs);
+                let (found, errors) = tasks::load(project_root().as_deref());
+                for error in &errors {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+                let found: Vec<&Task> = found.iter().filter(|t| t.applies_to(&vars)).collect();
+                if found.is_empty() {
+                    ui.weak("No tasks for this file");
+                }
+                for task in found {
+                    if ui
+                        .button(&task.name)
+                        .on_hover_text(task.resolve(&vars).command_line())
+                        .clicked()
+                    {
+                        run_task(ctx, tabs, task);
+                        ui.close_menu();
+                    }
+                }
+                ui.separator();
+                if ui.button("Run Task…").clicked() {
+                    open_task_picker(tabs);
+                    ui.close_menu();
+                }
+                if let Some(root) = project_root() {
+                    if ui.button("Edit Project Tasks").clicked() {
+                        edit_tasks_file(ctx, tabs, current_view, &tasks::project_file(&root));
+                        ui.close_menu();
+                    }
+                }
+                if let Some(file) = tasks::global_file() {
+                    if ui.button("Edit Global Tasks").clicked() {
+                        edit_tasks_file(ctx, tabs, current_view, &file);
+                        ui.close_menu();
+                    }
+                }
+            });
             ui.menu_button("Git", |ui| {
                 if ui.button("Add current file").clicked() {
                     unsafe {
@@ -938,7 +1093,18 @@ pub fn show_top_panel(
                                     .font(egui::TextStyle::Monospace)
                                     .background_color(egui::Color32::from_rgb(0, 0, 0)),
                             );
-                        });
+
+                            if ui.button("Insert").clicked() && !display_text.is_empty() {
+                                if let Some(doc) = tabs.active_mut() {
+                                    let at = doc
+                                        .cursor
+                                        .map_or(doc.text.len_chars(), |c| c.primary.index);
+                                    let edit = Edit::insert(at, &display_text);
+                                    doc.edit(vec![edit]);
+                                    set_cursor(ctx, doc.id, at + display_text.chars().count());
+                                }
+                            }
+                        });
                     });
             }
             unsafe {
@@ -965,13 +1131,234 @@ pub fn show_top_panel(
                                             });
                                             // Force highlights refresh
                                             if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                                                editor_state.cached_highlights.clear();
-                                                editor_state.last_text.clear();
+                                                editor_state.invalidate();
                                             }
                                             ctx.request_repaint();
                                         }
                                     });
 
+                                    let editor_state = &mut *std::ptr::addr_of_mut!(EDITOR_STATE);
+                                    if let Some(editor_state) = editor_state.as_mut() {
+                                        ui.horizontal(|ui| {
+                                            ui.label("Theme:");
+                                            let mut selected =
+                                                editor_state.theme_name().to_string();
+                                            egui::ComboBox::from_id_salt("theme")
+                                                .selected_text(&selected)
+                                                .show_ui(ui, |ui| {
+                                                    for name in editor_state.theme_names() {
+                                                        ui.selectable_value(
+                                                            &mut selected,
+                                                            name.clone(),
+                                                            name,
+                                                        );
+                                                    }
+                                                });
+                                            if selected != editor_state.theme_name() {
+                                                editor_state.set_theme(ctx, &selected);
+                                                settings.theme = selected;
+                                                settings.save().unwrap_or_else(|e| {
+                                                    println!("Failed to save settings: {}", e);
+                                                });
+                                            }
+                                        });
+                                    }
+
+                                    if ui
+                                        .checkbox(
+                                            &mut settings.persistent_undo,
+                                            "Keep undo history between sessions",
+                                        )
+                                        .changed()
+                                    {
+                                        settings.save().unwrap_or_else(|e| {
+                                            println!("Failed to save settings: {}", e);
+                                        });
+                                    }
+                                    if ui
+                                        .checkbox(
+                                            &mut settings.backup_on_save,
+                                            "Keep a backup (file~) when saving",
+                                        )
+                                        .changed()
+                                    {
+                                        settings.save().unwrap_or_else(|e| {
+                                            println!("Failed to save settings: {}", e);
+                                        });
+                                    }
+                                    ui.horizontal(|ui| {
+                                        ui.label("Autosave:");
+                                        let mut changed = false;
+                                        egui::ComboBox::from_id_salt("autosave")
+                                            .selected_text(settings.autosave.label())
+                                            .show_ui(ui, |ui| {
+                                                for mode in AutosaveMode::ALL {
+                                                    changed |= ui
+                                                        .selectable_value(
+                                                            &mut settings.autosave,
+                                                            mode,
+                                                            mode.label(),
+                                                        )
+                                                        .changed();
+                                                }
+                                            });
+                                        if settings.autosave == AutosaveMode::AfterDelay {
+                                            changed |= ui
+                                                .add(
+                                                    egui::DragValue::new(
+                                                        &mut settings.autosave_delay_ms,
+                                                    )
+                                                    .range(100..=60_000)
+                                                    .suffix(" ms"),
+                                                )
+                                                .changed();
+                                        }
+                                        if changed {
+                                            settings.save().unwrap_or_else(|e| {
+                                                println!("Failed to save settings: {}", e);
+                                            });
+                                        }
+                                    });
+
+                                    ui.separator();
+                                    ui.label("Indentation:");
+                                    let mut changed = indent_settings_ui(ui, &mut settings.indent);
+                                    egui::CollapsingHeader::new("Per-language indentation").show(
+                                        ui,
+                                        |ui| {
+                                            let mut remove = None;
+                                            for (language, indent) in
+                                                settings.language_indent.iter_mut()
+                                            {
+                                                ui.horizontal(|ui| {
+                                                    ui.label(language.as_str());
+                                                    changed |= indent_settings_ui(ui, indent);
+                                                    if ui.small_button("✖").clicked() {
+                                                        remove = Some(language.clone());
+                                                    }
+                                                });
+                                            }
+                                            if let Some(language) = remove {
+                                                settings.language_indent.remove(&language);
+                                                changed = true;
+                                            }
+
+                                            let editor_state = &*std::ptr::addr_of!(EDITOR_STATE);
+                                            if let Some(editor_state) = editor_state.as_ref() {
+                                                let mut added = None;
+                                                egui::ComboBox::from_id_salt("add_language_indent")
+                                                    .selected_text("Add language…")
+                                                    .show_ui(ui, |ui| {
+                                                        for syntax in editor_state.ps.syntaxes() {
+                                                            if settings
+                                                                .language_indent
+                                                                .contains_key(&syntax.name)
+                                                            {
+                                                                continue;
+                                                            }
+                                                            if ui
+                                                                .selectable_label(
+                                                                    false,
+                                                                    &syntax.name,
+                                                                )
+                                                                .clicked()
+                                                            {
+                                                                added = Some(syntax.name.clone());
+                                                            }
+                                                        }
+                                                    });
+                                                if let Some(language) = added {
+                                                    settings
+                                                        .language_indent
+                                                        .insert(language, settings.indent);
+                                                    changed = true;
+                                                }
+                                            }
+                                        },
+                                    );
+                                    if changed {
+                                        settings.save().unwrap_or_else(|e| {
+                                            println!("Failed to save settings: {}", e);
+                                        });
+                                    }
+
+                                    ui.separator();
+                                    let mut changed = false;
+                                    egui::CollapsingHeader::new("Language servers").show(
+                                        ui,
+                                        |ui| {
+                                            ui.weak("Command lines, started per language. Leave empty to turn one off.");
+                                            let mut remove = None;
+                                            egui::Grid::new("language_servers").show(ui, |ui| {
+                                                for (language, command) in
+                                                    settings.language_servers.iter_mut()
+                                                {
+                                                    ui.label(language.as_str());
+                                                    // Edited on the side, so servers
+                                                    // aren't started for half-typed commands
+                                                    let id = ui.id().with(language.as_str());
+                                                    let mut draft = ui
+                                                        .data(|d| d.get_temp::<String>(id))
+                                                        .unwrap_or_else(|| command.clone());
+                                                    let response =
+                                                        ui.text_edit_singleline(&mut draft);
+                                                    if response.lost_focus() {
+                                                        changed |= *command != draft;
+                                                        *command = draft;
+                                                        ui.data_mut(|d| d.remove::<String>(id));
+                                                    } else if response.has_focus() {
+                                                        ui.data_mut(|d| d.insert_temp(id, draft));
+                                                    }
+                                                    if ui.small_button("✖").clicked() {
+                                                        remove = Some(language.clone());
+                                                    }
+                                                    ui.end_row();
+                                                }
+                                            });
+                                            if let Some(language) = remove {
+                                                settings.language_servers.remove(&language);
+                                                changed = true;
+                                            }
+
+                                            let editor_state = &*std::ptr::addr_of!(EDITOR_STATE);
+                                            if let Some(editor_state) = editor_state.as_ref() {
+                                                let mut added = None;
+                                                egui::ComboBox::from_id_salt("add_language_server")
+                                                    .selected_text("Add language…")
+                                                    .show_ui(ui, |ui| {
+                                                        for syntax in editor_state.ps.syntaxes() {
+                                                            if settings
+                                                                .language_servers
+                                                                .contains_key(&syntax.name)
+                                                            {
+                                                                continue;
+                                                            }
+                                                            if ui
+                                                                .selectable_label(
+                                                                    false,
+                                                                    &syntax.name,
+                                                                )
+                                                                .clicked()
+                                                            {
+                                                                added = Some(syntax.name.clone());
+                                                            }
+                                                        }
+                                                    });
+                                                if let Some(language) = added {
+                                                    settings
+                                                        .language_servers
+                                                        .insert(language, String::new());
+                                                    changed = true;
+                                                }
+                                            }
+                                        },
+                                    );
+                                    if changed {
+                                        settings.save().unwrap_or_else(|e| {
+                                            println!("Failed to save settings: {}", e);
+                                        });
+                                    }
+
                                     ui.separator();
 
                                     if ui.button("Reset to Defaults").clicked() {
@@ -981,8 +1368,8 @@ pub fn show_top_panel(
                                         });
                                         // Force highlights refresh
                                         if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                                            editor_state.cached_highlights.clear();
-                                            editor_state.last_text.clear();
+                                            editor_state.set_theme(ctx, &settings.theme);
+                                            editor_state.invalidate();
                                         }
                                         ctx.request_repaint();
                                     }
@@ -1137,745 +1524,3859 @@ pub fn show_top_panel(
             }
         });
     });
+    show_find_in_files(ctx, tabs, current_view);
+    show_problems(ctx, tabs, current_view);
+    show_task_picker(ctx, tabs);
+    show_output(ctx);
 }
 
-pub fn home_view(
-    ctx: &egui::Context,
-    current_view: &mut ViewType,
-    filename: &mut String,
-    text: &mut String,
-) {
+/// Id of the main `TextEdit` for a document, so its state can be reached
+/// from outside the editor view.
+fn editor_id(doc_id: u64) -> egui::Id {
+    egui::Id::new(("editor", doc_id))
+}
+
+/// Id of the terminal's screen, which takes keyboard focus like an editor.
+fn terminal_id() -> egui::Id {
+    egui::Id::new("terminal")
+}
+
+/// True while the terminal has focus, when shortcuts belong to the shell.
+fn terminal_focused(ctx: &egui::Context) -> bool {
+    ctx.memory(|m| m.has_focus(terminal_id()))
+}
+
+/// Moves the cursor of a document's editor. The editor scrolls it into view
+/// and takes focus the next time it is shown.
+fn set_cursor(ctx: &egui::Context, doc_id: u64, char_index: usize) {
+    set_selection(
+        ctx,
+        doc_id,
+        egui::text::CCursorRange::one(egui::text::CCursor::new(char_index)),
+    );
+}
+
+fn set_selection(ctx: &egui::Context, doc_id: u64, range: egui::text::CCursorRange) {
+    store_selection(ctx, doc_id, range);
     unsafe {
-        SETTINGS = Some(EditorSettings::load());
-    }
-    let mut should_create_new = false; // flag for new file
-    if let Some((current_version, latest_version)) = SHOULD_SHOW_UPDATE.get() {
-        if !UPDATE_DIALOG_SHOWN.load(Ordering::SeqCst) {
-            egui::Window::new("Update Available")
-                .collapsible(false)
-                .resizable(false)
-                .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
-                .show(ctx, |ui| {
-                    ui.vertical_centered(|ui| {
-                        ui.label(format!(
-                            "A new version of Kokona is available!\nCurrent: v{}\nLatest: v{}",
-                            current_version, latest_version
-                        ));
-                        ui.add_space(8.0);
-                        ui.horizontal(|ui| {
-                            ui.with_layout(
-                                egui::Layout::right_to_left(egui::Align::Center),
-                                |ui| {
-                                    if ui.button("Open Download Page").clicked() {
-                                        if let Err(e) = open::that(
-                                            "https://github.com/Noxyntious/kokona/releases/latest",
-                                        ) {
-                                            println!("Failed to open URL: {}", e);
-                                        }
-                                        UPDATE_DIALOG_SHOWN.store(true, Ordering::SeqCst);
-                                    }
-                                    if ui.button("Dismiss").clicked() {
-                                        UPDATE_DIALOG_SHOWN.store(true, Ordering::SeqCst);
-                                    }
-                                },
-                            );
-                        });
-                    });
-                });
-        }
+        REVEAL_CURSOR = Some(doc_id);
     }
+}
 
-    if !UPDATE_CHECK_DONE.load(Ordering::SeqCst) {
-        UPDATE_CHECK_DONE.store(true, Ordering::SeqCst);
+/// Sets the selection of a document's editor without scrolling to it.
+pub fn store_selection(ctx: &egui::Context, doc_id: u64, range: egui::text::CCursorRange) {
+    let id = editor_id(doc_id);
+    let mut state = egui::TextEdit::load_state(ctx, id).unwrap_or_default();
+    state.cursor.set_char_range(Some(range));
+    state.store(ctx, id);
+}
 
-        let ctx_clone = ctx.clone();
-        std::thread::spawn(move || {
-            // wait for 2 seconds to let the application load
-            std::thread::sleep(std::time::Duration::from_millis(500));
+/// Reports a failure nobody asked about directly, e.g. an autosave, in the
+/// status bar.
+pub fn report_error(message: String) {
+    unsafe {
+        STATUS_ERROR = Some(message);
+    }
+}
 
-            std::thread::Builder::new()
-                .name("update-checker".to_string())
-                .spawn(move || {
-                    let runtime = tokio::runtime::Runtime::new().unwrap();
-                    runtime.block_on(async {
-                        let client = reqwest::Client::new();
-                        match client
-                            .get("https://api.github.com/repos/Noxyntious/kokona/releases/latest")
-                            .header("User-Agent", "kokona-update-checker")
-                            .send()
-                            .await
-                        {
-                            Ok(response) => {
-                                match response.text().await {
-                                    Ok(text) => {
-                                        match serde_json::from_str::<GithubRelease>(&text) {
-                                            Ok(release) => {
-                                                let latest_version = release
-                                                    .tag_name
-                                                    .trim_start_matches('v')
-                                                    .to_string();
-                                                let current_version =
-                                                    crate::consts::versioninfo::VERSION.to_string();
+pub fn terminal_open() -> bool {
+    unsafe { TERMINAL_OPEN }
+}
 
-                                                if compare_versions(
-                                                    &current_version,
-                                                    &latest_version,
-                                                ) {
-                                                    let _ = SHOULD_SHOW_UPDATE
-                                                        .set((current_version, latest_version));
-                                                    ctx_clone.request_repaint();
-                                                    // Request UI update
-                                                }
-                                            }
-                                            Err(e) => println!("Failed to parse JSON: {}", e),
-                                        }
-                                    }
-                                    Err(e) => println!("Failed to get response text: {}", e),
-                                }
-                            }
-                            Err(e) => println!("Failed to get response from GitHub: {}", e),
-                        }
-                    });
-                })
-                .expect("Failed to spawn update checker thread");
-        });
+pub fn set_terminal_open(open: bool) {
+    unsafe {
+        TERMINAL_OPEN = open;
     }
-    egui::CentralPanel::default().show(ctx, |ui| {
-        ui.add_space(15.0);
-        ui.horizontal(|ui| {
-            ui.heading(egui::RichText::new("Kokona").size(72.0));
-            ui.vertical(|ui| {
-                ui.add_space(55.5);
-                ui.label(format!("ver {}", crate::consts::versioninfo::VERSION));
-            });
-        });
-        ui.add_space(30.0);
-        ui.group(|ui| {
-            ui.vertical(|ui| {
-                ui.label("Start");
-                ui.add_space(5.0);
-                if ui.button("New File").clicked() {
-                    should_create_new = true;
-                    *current_view = ViewType::Editor;
-                    unsafe {
-                        if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                            editor_state.set_syntax_for_extension(&filename);
-                        }
-                    }
-                }
-                if ui.button("Open File").clicked() {
-                    if let Some(path) = rfd::FileDialog::new().set_title("Open File").pick_file() {
-                        match std::fs::read_to_string(&path) {
-                            Ok(content) => {
-                                *current_view = ViewType::Editor; // Switch to editor view
-                                *filename = path.display().to_string(); // Set the filename
-                                *text = content; // This will be shown in the TextEdit
-                                unsafe {
-                                    if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                                        editor_state.set_syntax_for_extension(&filename);
-                                    }
-                                }
-                            }
-                            Err(e) => println!("Error opening file: {}", e),
-                        }
-                    }
-                }
-            });
-        });
-        ui.with_layout(egui::Layout::bottom_up(egui::Align::RIGHT), |ui| {
-            if ui.link("kokona.nijika.dev").clicked() {
-                if let Err(e) = open::that("https://kokona.nijika.dev") {
-                    println!("Failed to open URL: {}", e);
-                }
-            }
-        });
-    });
+}
 
-    // Handle filename change after UI
-    if should_create_new {
-        *filename = String::from("untitled.txt");
-        *text = String::new();
-        ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
-    }
-    // Check for Ctrl+O
-    if ctx.input(|i| i.key_pressed(egui::Key::O) && i.modifiers.command) {
-        if let Some(path) = rfd::FileDialog::new().set_title("Open File").pick_file() {
-            match std::fs::read_to_string(&path) {
-                Ok(content) => {
-                    *current_view = ViewType::Editor; // Switch to editor view
-                    *filename = path.display().to_string(); // Set the filename
-                    *text = content; // This will be shown in the TextEdit
-                    unsafe {
-                        if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                            editor_state.set_syntax_for_extension(&filename);
-                        }
-                    }
-                }
-                Err(e) => println!("Error opening file: {}", e),
-            }
+/// Char offset of a one-based `line` and tab-aware `column`, clamped to
+/// the text.
+fn char_at(text: &Buffer, line: usize, column: usize, tab_width: usize) -> usize {
+    let rope = text.rope();
+    let line = line.saturating_sub(1).min(rope.len_lines() - 1);
+    let start = rope.line_to_char(line);
+    let mut col = 1;
+    let mut offset = 0;
+    for c in rope.line(line).chars() {
+        if col >= column || c == '\n' || c == '\r' {
+            break;
         }
+        col += if c == '\t' {
+            tab_width - (col - 1) % tab_width
+        } else {
+            1
+        };
+        offset += 1;
+    }
+    start + offset
+}
+
+pub fn open_file_dialog(ctx: &egui::Context, tabs: &mut Tabs, current_view: &mut ViewType) {
+    if let Some(path) = rfd::FileDialog::new().set_title("Open File").pick_file() {
+        open_path(ctx, tabs, current_view, &path);
     }
-    show_top_panel(ctx, filename, text, current_view);
 }
 
-pub fn editor_view(
+/// Opens `path` in a tab and switches to the editor, reporting failures in
+/// a dialog.
+pub fn open_path(
     ctx: &egui::Context,
-    text: &mut String,
-    filename: &mut String,
+    tabs: &mut Tabs,
     current_view: &mut ViewType,
+    path: &std::path::Path,
 ) -> bool {
-    let mut was_modified = WAS_MODIFIED.load(Ordering::SeqCst);
-
-    unsafe {
-        if SEARCH_STATE.is_none() {
-            SEARCH_STATE = Some(SearchState::default());
+    match tabs.open(path) {
+        Ok(()) => {
+            *current_view = ViewType::Editor;
+            ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
+            println!("File opened successfully from: {}", path.display());
+            remember_recent(path, RecentKind::File);
+            true
         }
-        if EDITOR_STATE.is_none() {
-            EDITOR_STATE = Some(EditorState::new());
-            if let Some(state) = EDITOR_STATE.as_mut() {
-                state.set_syntax_for_extension(filename);
-            }
+        Err(e) => {
+            rfd::MessageDialog::new()
+                .set_title("Error")
+                .set_description(format!("Error opening file: {}", e))
+                .set_level(rfd::MessageLevel::Error)
+                .show();
+            false
         }
     }
+}
 
-    show_top_panel(ctx, filename, text, current_view);
-
-    // Check for Ctrl+F
-    if ctx.input(|i| i.key_pressed(egui::Key::F) && i.modifiers.command) {
-        unsafe {
-            if let Some(state) = SEARCH_STATE.as_mut() {
-                state.open = true;
-                state.find_matches(text);
-            }
+/// Opens `path` with the cursor at the start of `line` (zero-based).
+fn open_at_line(
+    ctx: &egui::Context,
+    tabs: &mut Tabs,
+    current_view: &mut ViewType,
+    path: &std::path::Path,
+    line: usize,
+) {
+    if open_path(ctx, tabs, current_view, path) {
+        if let Some(doc) = tabs.active() {
+            let line = line.min(doc.text.len_lines() - 1);
+            set_cursor(ctx, doc.id, doc.text.rope().line_to_char(line));
         }
     }
+}
 
-    // Check for Ctrl+O
-    if ctx.input(|i| i.key_pressed(egui::Key::O) && i.modifiers.command) {
-        if let Some(path) = rfd::FileDialog::new().set_title("Open File").pick_file() {
-            match std::fs::read_to_string(&path) {
-                Ok(content) => {
-                    *current_view = ViewType::Editor; // Switch to editor view
-                    *filename = path.display().to_string(); // Set the filename
-                    *text = content; // This will be shown in the TextEdit
-                    unsafe {
-                        if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                            editor_state.set_syntax_for_extension(&filename);
-                        }
-                    }
-                }
-                Err(e) => println!("Error opening file: {}", e),
-            }
-        }
+/// Folder Find in Files searches when no root was chosen: the project, or
+/// else the current file's.
+fn default_search_root(tabs: &Tabs) -> Option<PathBuf> {
+    if let Some(root) = project_root() {
+        return Some(root);
     }
+    tabs.active()
+        .and_then(|doc| doc.path.as_deref())
+        .and_then(|path| path.parent())
+        .map(|dir| dir.to_path_buf())
+        .or_else(|| std::env::current_dir().ok())
+}
 
-    // Check for Ctrl+S
-    if ctx.input(|i| i.key_pressed(egui::Key::S) && i.modifiers.command) {
-        if filename == "untitled.txt" {
-            if let Some(path) = rfd::FileDialog::new()
-                .set_title("Save")
-                .set_file_name(&filename[..])
-                .save_file()
-            {
-                if let Err(e) = std::fs::write(&path, &text) {
-                    println!("Error saving file: {}", e);
-                } else {
-                    println!("File saved successfully to: {}", path.display());
-                    WAS_MODIFIED.store(false, Ordering::SeqCst);
-                }
-                unsafe {
-                    if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                        editor_state.force_highlight_update();
-                    }
+/// Writes the Find in Files replacements. Open documents get them as one
+/// undoable edit instead of having the file changed under them. Returns the
+/// files that were skipped.
+fn replace_in_files(tabs: &mut Tabs, find: &FindInFiles) -> Vec<String> {
+    let mut skipped = Vec::new();
+    let Some(results) = find.results() else {
+        return skipped;
+    };
+    for file in results.iter() {
+        let open = tabs
+            .documents
+            .iter_mut()
+            .find(|d| d.path.as_deref() == Some(file.path.as_path()));
+        // Files that aren't open are loaded the same way, so they are
+        // written back in their own encoding and line endings
+        let mut loaded = None;
+        let doc = match open {
+            Some(doc) => doc,
+            None => match Document::open(&file.path) {
+                Ok(doc) => loaded.insert(doc),
+                Err(e) => {
+                    skipped.push(format!("{} ({})", file.path.display(), e));
+                    continue;
                 }
-                *filename = path.display().to_string();
-                ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
-            }
-        } else {
-            if let Err(e) = std::fs::write(&filename, &text) {
-                println!("Error saving file: {}", e);
-            } else {
-                println!("File saved successfully to: {}", filename);
-                WAS_MODIFIED.store(false, Ordering::SeqCst);
+            },
+        };
+        let Some(replacements) = find.replacements(file, &doc.text.to_string()) else {
+            skipped.push(format!(
+                "{} (changed since the search)",
+                file.path.display()
+            ));
+            continue;
+        };
+        let edits = replacements
+            .into_iter()
+            .map(|(range, inserted)| Edit {
+                at: doc.text.byte_to_char(range.start),
+                removed: doc.text.byte_slice(range).into_owned(),
+                inserted,
+            })
+            .collect();
+        doc.edit(edits);
+        // Without the .editorconfig fixes, which would touch lines nobody
+        // asked to replace
+        if let Some(doc) = loaded.as_mut() {
+            if let Err(e) = doc.autosave() {
+                skipped.push(format!("{} ({})", file.path.display(), e));
             }
-            ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
         }
     }
+    skipped
+}
 
-    let text_ref = text.clone(); // Clone the text before the search window
+fn show_find_in_files(ctx: &egui::Context, tabs: &mut Tabs, current_view: &mut ViewType) {
+    let find = unsafe { &mut *std::ptr::addr_of_mut!(FIND_IN_FILES) };
+    let find = find.get_or_insert_with(FindInFiles::default);
+    if !terminal_focused(ctx)
+        && ctx.input_mut(|i| {
+            i.consume_key(
+                egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+                egui::Key::F,
+            )
+        })
+    {
+        find.open = true;
+    }
+    if !find.open {
+        return;
+    }
 
-    // Show search window if open
-    unsafe {
-        if let Some(state) = SEARCH_STATE.as_mut() {
-            if state.open {
-                egui::Window::new("Search")
-                    .fixed_size([300.0, 100.0])
-                    .collapsible(false)
-                    .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
-                    .show(ctx, |ui| {
-                        ui.horizontal(|ui| {
-                            let query_changed = ui.text_edit_singleline(&mut state.query).changed();
-                            if query_changed {
-                                state.find_matches(&text_ref);
-                                state.current_match = 0;
-                            }
+    let root = find.root.clone().or_else(|| default_search_root(tabs));
+    let mut open = true;
+    let mut start = false;
+    let mut replace = false;
+    let mut jump_to = None;
+    egui::Window::new("Find in Files")
+        .open(&mut open)
+        .default_size([500.0, 400.0])
+        .show(ctx, |ui| {
+            egui::Grid::new("find_in_files_grid")
+                .num_columns(2)
+                .show(ui, |ui| {
+                    ui.label("Find:");
+                    let response = ui.text_edit_singleline(&mut find.query);
+                    if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                        start = true;
+                    }
+                    ui.end_row();
 
-                            if ui.button("×").clicked() {
-                                state.open = false;
-                            }
-                        });
+                    ui.checkbox(&mut find.replace_mode, "Replace:");
+                    ui.add_enabled(
+                        find.replace_mode,
+                        egui::TextEdit::singleline(&mut find.replacement),
+                    );
+                    ui.end_row();
 
-                        ui.horizontal(|ui| {
-                            if ui
-                                .checkbox(&mut state.case_sensitive, "Case sensitive")
-                                .changed()
+                    ui.label("In:");
+                    ui.horizontal(|ui| {
+                        ui.label(
+                            root.as_ref()
+                                .map_or("(no folder)".to_string(), |r| r.display().to_string()),
+                        );
+                        if ui.button("Choose…").clicked() {
+                            if let Some(dir) = rfd::FileDialog::new()
+                                .set_title("Search in folder")
+                                .pick_folder()
                             {
-                                state.find_matches(&text_ref);
+                                find.root = Some(dir);
+                                start = true;
                             }
+                        }
+                        if find.root.is_some() && ui.button("Current file's folder").clicked() {
+                            find.root = None;
+                            start = true;
+                        }
+                    });
+                    ui.end_row();
+                });
 
-                            if ui.button("⬆ Previous").clicked()
-                                || ui.input(|i| i.key_pressed(egui::Key::N) && i.modifiers.shift)
-                            {
-                                state.prev_match();
+            ui.horizontal(|ui| {
+                start |= ui
+                    .checkbox(&mut find.case_sensitive, "Case sensitive")
+                    .changed();
+                start |= ui.checkbox(&mut find.whole_word, "Whole word").changed();
+                start |= ui.checkbox(&mut find.regex, "Regex").changed();
+            });
+
+            ui.horizontal(|ui| {
+                if find.is_running() {
+                    ui.spinner();
+                    if ui.button("Cancel").clicked() {
+                        find.cancel();
+                    }
+                } else if ui.button("Search").clicked() {
+                    start = true;
+                }
+                if let Some(error) = &find.error {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                } else if let Some(results) = find.results() {
+                    let hits: usize = results.iter().map(|f| f.hits.len()).sum();
+                    let status = if find.was_cancelled() {
+                        " (cancelled)"
+                    } else {
+                        ""
+                    };
+                    ui.label(format!(
+                        "{} results in {} files{}",
+                        hits,
+                        results.len(),
+                        status
+                    ));
+                    if find.replace_mode
+                        && !find.is_running()
+                        && ui
+                            .add_enabled(!results.is_empty(), egui::Button::new("Replace All"))
+                            .clicked()
+                    {
+                        replace = true;
+                    }
+                }
+            });
+            ui.separator();
+
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                let Some(results) = find.results() else {
+                    return;
+                };
+                for file in results.iter() {
+                    let name = match &root {
+                        Some(root) => file.path.strip_prefix(root).unwrap_or(&file.path),
+                        None => &file.path,
+                    };
+                    egui::CollapsingHeader::new(format!(
+                        "{} ({})",
+                        name.display(),
+                        file.hits.len()
+                    ))
+                    .id_salt(&file.path)
+                    .default_open(true)
+                    .show(ui, |ui| {
+                        for hit in &file.hits {
+                            let label = format!("{:>5}: {}", hit.line + 1, hit.preview());
+                            let response = ui.add(
+                                egui::Label::new(egui::RichText::new(label).monospace())
+                                    .sense(egui::Sense::click()),
+                            );
+                            if find.replace_mode {
+                                let replaced = find.replaced_line(&hit.text);
+                                ui.label(
+                                    egui::RichText::new(format!("    → {}", replaced.trim_start()))
+                                        .monospace()
+                                        .color(egui::Color32::from_rgb(120, 200, 120)),
+                                );
                             }
-                            if ui.button("⬇ Next").clicked()
-                                || ui.input(|i| i.key_pressed(egui::Key::N) && i.modifiers.command)
+                            if response
+                                .on_hover_cursor(egui::CursorIcon::PointingHand)
+                                .clicked()
                             {
-                                state.next_match();
-                            }
-                        });
-
-                        ui.label(format!(
-                            "{} matches found{}",
-                            state.matches.len(),
-                            if !state.matches.is_empty() {
-                                format!(
-                                    " (showing {}/{})",
-                                    state.current_match + 1,
-                                    state.matches.len()
-                                )
-                            } else {
-                                String::new()
+                                jump_to = Some((file.path.clone(), hit.line));
                             }
-                        ));
-
-                        if ui.input(|i| i.key_pressed(egui::Key::Escape)) {
-                            state.open = false;
                         }
                     });
-            }
-        }
-    }
-
-    egui::CentralPanel::default().show(ctx, |ui| {
-        let available_width = ui.available_width();
-        let available_height = ui.available_height()
-            - (unsafe {
-                if TERMINAL_OPEN {
-                    260.0
-                } else {
-                    20.0
                 }
             });
+        });
+    find.open = open;
 
-        egui::ScrollArea::vertical()
-            .max_height(available_height)
-            .show(ui, |ui| {
-                ui.horizontal(|ui| {
-                    let line_numbers = unsafe {
-                        let current_lines = text.split('\n').count().max(1);
-                        let text_layout = ui.available_size_before_wrap();
-                        let chars_per_line = (text_layout.x / 8.0).max(1.0) as usize;
-
-                        if let Some((cached, len)) = &CACHED_LINE_NUMBERS {
-                            if len == &current_lines {
-                                cached.clone()
-                            } else {
-                                let new_numbers = (1..=current_lines)
-                                    .flat_map(|i| {
-                                        let line = text.lines().nth(i - 1).unwrap_or("");
-                                        let wrap_count = if chars_per_line > 0 {
-                                            (line.len() + chars_per_line - 1) / chars_per_line
-                                        } else {
-                                            1
-                                        };
-                                        std::iter::once(i.to_string()).chain(
-                                            std::iter::repeat("-".to_string())
-                                                .take(wrap_count.saturating_sub(1)),
-                                        )
-                                    })
-                                    .collect::<Vec<_>>()
-                                    .join("\n");
-
-                                CACHED_LINE_NUMBERS = Some((new_numbers.clone(), current_lines));
-                                new_numbers
-                            }
-                        } else {
-                            let new_numbers = (1..=current_lines)
-                                .flat_map(|i| {
-                                    let line = text.lines().nth(i - 1).unwrap_or("");
-                                    let wrap_count = if chars_per_line > 0 {
-                                        (line.len() + chars_per_line - 1) / chars_per_line
-                                    } else {
-                                        1
-                                    };
-                                    std::iter::once(i.to_string()).chain(
-                                        std::iter::repeat("-".to_string())
-                                            .take(wrap_count.saturating_sub(1)),
-                                    )
-                                })
-                                .collect::<Vec<_>>()
-                                .join("\n");
-
-                            CACHED_LINE_NUMBERS = Some((new_numbers.clone(), current_lines));
-                            new_numbers
-                        }
-                    };
+    if let Some((path, line)) = jump_to {
+        open_at_line(ctx, tabs, current_view, &path, line);
+    }
+    if replace {
+        let skipped = replace_in_files(tabs, find);
+        if !skipped.is_empty() {
+            rfd::MessageDialog::new()
+                .set_title("Replace All")
+                .set_description(format!(
+                    "Some files were not changed:\n{}",
+                    skipped.join("\n")
+                ))
+                .set_level(rfd::MessageLevel::Warning)
+                .show();
+        }
+        start = true;
+    }
+    if start {
+        match &root {
+            Some(root) => find.start(ctx, root),
+            None => find.error = Some("No folder to search".to_string()),
+        }
+    }
+}
 
-                    ui.add(
-                        egui::TextEdit::multiline(&mut line_numbers.as_str())
-                            .desired_width(unsafe {
-                                SETTINGS
-                                    .as_ref()
-                                    .map_or(35.0, |s| 35.0 * (s.font_size / 12.0))
-                            })
-                            .min_size(egui::vec2(35.0, available_height))
-                            .interactive(false)
-                            .font(egui::FontId::monospace(unsafe {
-                                SETTINGS.as_ref().map_or(12.0, |s| s.font_size)
-                            }))
-                            .horizontal_align(egui::Align::RIGHT),
-                    );
+/// Replaces the problems from the last build, showing the Problems window
+/// when there are any.
+fn set_problems(problems: Vec<Problem>) {
+    unsafe {
+        PROBLEMS_OPEN |= !problems.is_empty();
+        *std::ptr::addr_of_mut!(PROBLEMS) = problems;
+        (*std::ptr::addr_of_mut!(PROBLEM_PATHS)).clear();
+    }
+}
 
-                    let text_edit = egui::TextEdit::multiline(text)
-                        .desired_width(available_width - 50.0)
-                        .min_size(egui::vec2(available_width - 50.0, available_height))
-                        .font(egui::TextStyle::Monospace)
-                        .lock_focus(true);
-
-                    let response = unsafe {
-                        let mut layouter = |ui: &egui::Ui, string: &str, wrap_width: f32| {
-                            let mut layout_job = egui::text::LayoutJob::default();
-
-                            if let Some(editor_state) = EDITOR_STATE.as_mut() {
-                                let highlights = editor_state.get_or_update_highlights(string);
-                                let mut last_end = 0;
-
-                                let (search_highlights, current_match) =
-                                    if let Some(search_state) = SEARCH_STATE.as_ref() {
-                                        if search_state.open {
-                                            (
-                                                &search_state.matches[..],
-                                                Some(search_state.current_match),
-                                            )
-                                        } else {
-                                            (&[][..], None)
-                                        }
-                                    } else {
-                                        (&[][..], None)
-                                    };
-
-                                for (format, text) in highlights {
-                                    let text_start = last_end;
-                                    let text_end = text_start + text.len();
-
-                                    let format = format.clone();
-
-                                    let mut processed = false;
-                                    // Apply search highlighting if needed
-                                    for (idx, &(s, e)) in search_highlights.iter().enumerate() {
-                                        if s >= text_start && e <= text_end {
-                                            let relative_start = s - text_start;
-                                            let relative_end = e - text_start;
-
-                                            // Add text before highlight
-                                            if relative_start > 0 {
-                                                layout_job.append(
-                                                    &text[..relative_start],
-                                                    0.0,
-                                                    format.clone(),
-                                                );
-                                            }
+/// `path` as Problems entries name it.
+fn problem_path(path: &std::path::Path) -> PathBuf {
+    let paths = unsafe { &mut *std::ptr::addr_of_mut!(PROBLEM_PATHS) };
+    paths
+        .entry(path.to_path_buf())
+        .or_insert_with(|| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()))
+        .clone()
+}
 
-                                            // Add highlighted text with different colors for current match
-                                            let mut highlight_format = format.clone();
-                                            highlight_format.background =
-                                                if Some(idx) == current_match {
-                                                    // Current match highlight - bright yellow
-                                                    egui::Color32::from_rgb(255, 255, 0)
-                                                } else {
-                                                    // Other matches highlight - darker yellow
-                                                    egui::Color32::from_rgb(180, 180, 0)
-                                                };
-                                            layout_job.append(
-                                                &text[relative_start..relative_end],
-                                                0.0,
-                                                highlight_format,
-                                            );
+/// Ctrl+Shift+B list of the tasks for the active file.
+struct TaskPicker {
+    tasks: Vec<Task>,
+    filter: String,
+    selected: usize,
+}
 
-                                            // Add text after highlight
-                                            if relative_end < text.len() {
-                                                layout_job.append(
-                                                    &text[relative_end..],
-                                                    0.0,
-                                                    format.clone(),
-                                                );
-                                            }
+impl TaskPicker {
+    fn visible(&self) -> Vec<&Task> {
+        let filter = self.filter.to_lowercase();
+        self.tasks
+            .iter()
+            .filter(|task| task.name.to_lowercase().contains(&filter))
+            .collect()
+    }
+}
 
-                                            processed = true;
-                                            break;
-                                        }
-                                    }
+/// What task variables stand for with the active document.
+fn task_variables(tabs: &Tabs) -> tasks::Variables {
+    let file = tabs.active().and_then(|doc| doc.path.clone());
+    let workspace_root = match &file {
+        Some(file) => working_dir(file),
+        None => project_root()
+            .or_else(|| std::env::current_dir().ok())
+            .unwrap_or_default(),
+    };
+    tasks::Variables {
+        file,
+        workspace_root,
+    }
+}
 
-                                    if !processed {
-                                        layout_job.append(&text, 0.0, format);
-                                    }
+fn run_task(ctx: &egui::Context, tabs: &Tabs, task: &Task) {
+    let task = task.resolve(&task_variables(tabs));
+    match task.output {
+        TaskOutput::Terminal => run_in_terminal(ctx, task.pty_command(), &task.name),
+        TaskOutput::Capture => match Job::start(ctx, &task) {
+            Ok(job) => unsafe {
+                // Replacing the job stops the one before
+                *std::ptr::addr_of_mut!(JOB) = Some(job);
+                (*std::ptr::addr_of_mut!(PROBLEMS)).clear();
+                OUTPUT_OPEN = true;
+            },
+            Err(e) => {
+                rfd::MessageDialog::new()
+                    .set_title("Error")
+                    .set_description(format!("Failed to run {}: {}", task.command_line(), e))
+                    .set_level(rfd::MessageLevel::Error)
+                    .show();
+            }
+        },
+    }
+}
 
-                                    last_end = text_end;
-                                }
-                            } else {
-                                layout_job.append(string, 0.0, egui::TextFormat::default());
-                            }
-                            layout_job.wrap.max_width = wrap_width;
-                            ui.fonts(|f| f.layout_job(layout_job))
-                        };
+/// How the task is doing, or how it ended.
+fn job_summary(job: &Job) -> String {
+    let elapsed = job.elapsed().as_secs_f32();
+    if job.is_running() {
+        return format!("{} {:.0}s", job.name, elapsed);
+    }
+    if job.was_cancelled() {
+        return format!("{} cancelled after {:.1}s", job.name, elapsed);
+    }
+    match job.status() {
+        Some(status) if status.success() => format!("{} ✔ {:.1}s", job.name, elapsed),
+        Some(status) => match status.code() {
+            Some(code) => format!("{} ✖ exit code {} after {:.1}s", job.name, code, elapsed),
+            None => format!("{} ✖ {} after {:.1}s", job.name, status, elapsed),
+        },
+        None => format!("{} ✖ after {:.1}s", job.name, elapsed),
+    }
+}
 
-                        let response = ui.add(text_edit.layouter(&mut layouter));
-                        if response.has_focus() && ui.input(|i| i.key_pressed(egui::Key::Tab)) {
-                            let cursor_pos = text.len();
-                            text.insert_str(cursor_pos, "    ");
-                            WAS_MODIFIED.store(true, Ordering::SeqCst);
-                            ctx.send_viewport_cmd(egui::ViewportCommand::Title(
-                                "Kokona | MODIFIED".into(),
-                            ));
-                            response.request_focus();
-                        }
-                        if response.changed() {
-                            WAS_MODIFIED.store(true, Ordering::SeqCst);
-                            ctx.send_viewport_cmd(egui::ViewportCommand::Title(
-                                "Kokona | MODIFIED".into(),
-                            ));
-                        }
-                        response
-                    };
+/// Spinner and elapsed time of the running task, or how the last one
+/// ended. Clicking it shows the output.
+fn job_status_ui(ui: &mut egui::Ui) {
+    let Some(job) = (unsafe { (*std::ptr::addr_of!(JOB)).as_ref() }) else {
+        return;
+    };
+    ui.label("|");
+    let response = ui.add(egui::Label::new(job_summary(job)).sense(egui::Sense::click()));
+    if job.is_running() {
+        ui.spinner();
+    }
+    if response
+        .on_hover_text("Show output")
+        .on_hover_cursor(egui::CursorIcon::PointingHand)
+        .clicked()
+    {
+        unsafe { OUTPUT_OPEN = true };
+    }
+}
 
-                    let (line, col) = if response.has_focus() {
-                        calculate_cursor_position(text)
-                    } else {
-                        (1, 1)
-                    };
+/// Streamed output of the running or last task, with the problems found
+/// in it passed on to the Problems window as they turn up.
+fn show_output(ctx: &egui::Context) {
+    let Some(job) = (unsafe { (*std::ptr::addr_of!(JOB)).as_ref() }) else {
+        return;
+    };
+    let output = job.output();
+    let problems = unsafe { &*std::ptr::addr_of!(PROBLEMS) };
+    if output.problems.len() != problems.len() {
+        set_problems(output.problems.clone());
+    }
 
-                    show_bottom_status_bar(ctx, line, col, text, filename);
-                });
+    let open = unsafe { &mut *std::ptr::addr_of_mut!(OUTPUT_OPEN) };
+    if !*open {
+        return;
+    }
+    egui::Window::new("Output")
+        .open(open)
+        .default_size([600.0, 360.0])
+        .show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if job.is_running() {
+                    ui.spinner();
+                }
+                ui.label(job_summary(job));
+                if job.is_running() && ui.button("Cancel").clicked() {
+                    job.cancel();
+                }
             });
-    });
-
-    was_modified = WAS_MODIFIED.load(Ordering::SeqCst);
-    was_modified
+            ui.label(
+                egui::RichText::new(format!("{}  (in {})", job.command_line, job.cwd.display()))
+                    .weak()
+                    .monospace(),
+            );
+            ui.separator();
+            let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+            egui::ScrollArea::both()
+                .stick_to_bottom(true)
+                .auto_shrink([false; 2])
+                .show_rows(ui, row_height, output.lines.len(), |ui, rows| {
+                    for line in &output.lines[rows] {
+                        ui.label(egui::RichText::new(line).monospace());
+                    }
+                });
+        });
 }
 
-fn calculate_cursor_position(text: &str) -> (usize, usize) {
-    let mut line = 1;
-    let mut col = 1;
-    // this still sucks, please someone help
-    let char_idx = text.len();
-    for (_i, c) in text[..char_idx].chars().enumerate() {
-        if c == '\n' {
-            line += 1;
-            col = 1;
-        } else {
-            col += 1;
-        }
+/// Runs `cmd` in the terminal panel, in place of whatever ran there
+/// before.
+fn run_in_terminal(ctx: &egui::Context, cmd: portable_pty::CommandBuilder, title: &str) {
+    match Terminal::spawn(ctx, cmd, title) {
+        Ok(terminal) => unsafe {
+            *std::ptr::addr_of_mut!(TERMINAL) = Some(terminal);
+            TERMINAL_OPEN = true;
+        },
+        Err(e) => println!("Failed to start {}: {}", title, e),
     }
+}
 
-    (line, col)
+/// Starts the user's shell in the project, or the current file's folder.
+fn start_shell(ctx: &egui::Context, filename: &str) -> Option<Terminal> {
+    let mut cmd = portable_pty::CommandBuilder::new_default_prog();
+    if let Some(dir) = project_root().or_else(|| {
+        std::path::Path::new(filename)
+            .parent()
+            .map(|p| p.to_path_buf())
+    }) {
+        cmd.cwd(dir);
+    }
+    Terminal::spawn(ctx, cmd, "Shell")
+        .inspect_err(|e| println!("Failed to start shell: {}", e))
+        .ok()
 }
 
-fn create_pty() -> Result<portable_pty::PtyPair, Box<dyn std::error::Error>> {
-    let pty_system = portable_pty::native_pty_system();
-    let pair = pty_system.openpty(portable_pty::PtySize {
-        rows: 24,
-        cols: 80,
-        pixel_width: 0,
-        pixel_height: 0,
-    })?;
-    Ok(pair)
+fn open_task_picker(tabs: &Tabs) {
+    let vars = task_variables(tabs);
+    let (found, errors) = tasks::load(project_root().as_deref());
+    for error in errors {
+        println!("Error reading tasks: {}", error);
+    }
+    let tasks = found.into_iter().filter(|t| t.applies_to(&vars)).collect();
+    unsafe {
+        *std::ptr::addr_of_mut!(TASK_PICKER) = Some(TaskPicker {
+            tasks,
+            filter: String::new(),
+            selected: 0,
+        });
+    }
 }
 
-fn show_bottom_status_bar(
+/// Opens a tasks file for editing, creating it first when needed. A new
+/// global file starts out with the default tasks.
+fn edit_tasks_file(
     ctx: &egui::Context,
-    line: usize,
-    col: usize,
-    text: &str,
-    filename: &str,
+    tabs: &mut Tabs,
+    current_view: &mut ViewType,
+    path: &std::path::Path,
 ) {
-    egui::TopBottomPanel::bottom("bottom_panel")
-        .min_height(unsafe {
-            if TERMINAL_OPEN {
-                220.0
-            } else {
-                20.0
+    let initial = if Some(path) == tasks::global_file().as_deref() {
+        tasks::defaults()
+    } else {
+        Vec::new()
+    };
+    if let Err(e) = tasks::create_file(path, initial) {
+        rfd::MessageDialog::new()
+            .set_title("Error")
+            .set_description(format!("Error creating {}: {}", path.display(), e))
+            .set_level(rfd::MessageLevel::Error)
+            .show();
+        return;
+    }
+    forget_listing(path);
+    open_path(ctx, tabs, current_view, path);
+}
+
+fn show_task_picker(ctx: &egui::Context, tabs: &Tabs) {
+    if ctx.input_mut(|i| {
+        i.consume_key(
+            egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+            egui::Key::B,
+        )
+    }) {
+        open_task_picker(tabs);
+    }
+    let picker = unsafe { &mut *std::ptr::addr_of_mut!(TASK_PICKER) };
+    let Some(state) = picker.as_mut() else {
+        return;
+    };
+    let mut open = true;
+    let mut close = false;
+    let mut run = None;
+    egui::Window::new("Run Task")
+        .open(&mut open)
+        .collapsible(false)
+        .default_size([360.0, 240.0])
+        .anchor(egui::Align2::CENTER_TOP, [0.0, 40.0])
+        .show(ctx, |ui| {
+            let response = ui.text_edit_singleline(&mut state.filter);
+            response.request_focus();
+            if response.changed() {
+                state.selected = 0;
             }
-        })
-        .max_height(unsafe {
-            if TERMINAL_OPEN {
-                220.0
-            } else {
-                20.0
+            let (up, down, enter, escape) = ui.input(|i| {
+                (
+                    i.key_pressed(egui::Key::ArrowUp),
+                    i.key_pressed(egui::Key::ArrowDown),
+                    i.key_pressed(egui::Key::Enter),
+                    i.key_pressed(egui::Key::Escape),
+                )
+            });
+            let mut selected = state.selected;
+            let visible = state.visible();
+            if down && selected + 1 < visible.len() {
+                selected += 1;
             }
-        })
-        .show(ctx, |ui| {
-            unsafe {
-                if TERMINAL_OPEN {
-                    // Initialize terminal state if needed
-                    if TERMINAL_PTY.is_none() {
-                        println!("Initializing PTY");
-                        if let Ok(pty_pair) = create_pty() {
-                            TERMINAL_PTY = Some(pty_pair);
-                            TERMINAL_OUTPUT = Some(String::new());
-                            TERMINAL_INPUT = Some(String::new());
-
-                            if let Some(pty_pair) = &TERMINAL_PTY {
-                                match pty_pair.master.take_writer() {
-                                    Ok(writer) => TERMINAL_WRITER = Some(writer),
-                                    Err(e) => println!("Failed to get writer: {}", e),
-                                };
-
-                                let mut cmd = portable_pty::CommandBuilder::new("/bin/sh");
-                                cmd.env("TERM", "dumb");
-                                cmd.env("LANG", "en_US.UTF-8");
-                                cmd.env("LC_ALL", "en_US.UTF-8");
-                                if let Some(parent) = std::path::Path::new(&*filename).parent() {
-                                    cmd.cwd(parent);
-                                }
+            if up {
+                selected = selected.saturating_sub(1);
+            }
+            if enter {
+                run = visible.get(selected).map(|task| (*task).clone());
+                close = true;
+            }
+            close |= escape;
 
-                                if let Ok(_child) = pty_pair.slave.spawn_command(cmd) {
-                                    println!("Shell started successfully");
-                                    let mut reader = pty_pair.master.try_clone_reader().unwrap();
-
-                                    // Read output in a separate thread
-                                    std::thread::spawn(move || {
-                                        let mut buffer = [0u8; 1024];
-                                        loop {
-                                            match reader.read(&mut buffer) {
-                                                Ok(0) => break,
-                                                Ok(n) => {
-                                                    let str = String::from_utf8_lossy(&buffer[..n])
-                                                        .into_owned();
-                                                    unsafe {
-                                                        if let Some(output) = &mut TERMINAL_OUTPUT {
-                                                            output.push_str(&str);
-                                                        }
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    println!("Read error: {}", e);
-                                                    break;
-                                                }
-                                            }
-                                        }
-                                    });
-                                } else {
-                                    println!("Failed to start shell");
-                                }
-                            }
-                        } else {
-                            println!("Failed to create PTY");
-                        }
+            ui.separator();
+            if visible.is_empty() {
+                ui.label("No tasks for this file");
+            }
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for (i, task) in visible.iter().enumerate() {
+                    let response = ui.selectable_label(i == selected, &task.name);
+                    if i == selected && (up || down) {
+                        response.scroll_to_me(None);
                     }
+                    if response.clicked() {
+                        run = Some((*task).clone());
+                        close = true;
+                    }
+                }
+            });
+            state.selected = selected;
+        });
+    if close || !open {
+        *picker = None;
+    }
+    if let Some(task) = run {
+        run_task(ctx, tabs, &task);
+    }
+}
 
-                    // Single interactive terminal field
-                    ui.vertical(|ui| {
-                        let available_width = ui.available_width();
-
-                        if let (Some(output), Some(input)) =
-                            (&mut TERMINAL_OUTPUT, &mut TERMINAL_INPUT)
+fn show_problems(ctx: &egui::Context, tabs: &mut Tabs, current_view: &mut ViewType) {
+    let open = unsafe { &mut *std::ptr::addr_of_mut!(PROBLEMS_OPEN) };
+    if !*open {
+        return;
+    }
+    let problems = unsafe { &*std::ptr::addr_of!(PROBLEMS) };
+    let root = project_root();
+    let mut jump_to = None;
+    egui::Window::new("Problems")
+        .open(open)
+        .default_size([500.0, 300.0])
+        .show(ctx, |ui| {
+            let count = |severity| problems.iter().filter(|p| p.severity == severity).count();
+            ui.label(format!(
+                "{} errors, {} warnings",
+                count(Severity::Error),
+                count(Severity::Warning)
+            ));
+            ui.separator();
+            egui::ScrollArea::vertical()
+                .auto_shrink([false; 2])
+                .show(ui, |ui| {
+                    if problems.is_empty() {
+                        ui.label("No problems from the last build");
+                    }
+                    for problem in problems {
+                        let color = severity_color(ui.visuals(), problem.severity);
+                        let response = ui
+                            .horizontal(|ui| {
+                                ui.colored_label(color, "●");
+                                ui.add(
+                                    egui::Label::new(&problem.message).sense(egui::Sense::click()),
+                                )
+                                .union(
+                                    ui.add(
+                                        egui::Label::new(
+                                            egui::RichText::new(problem.location(root.as_deref()))
+                                                .weak()
+                                                .monospace(),
+                                        )
+                                        .sense(egui::Sense::click()),
+                                    ),
+                                )
+                            })
+                            .inner;
+                        if response
+                            .on_hover_cursor(egui::CursorIcon::PointingHand)
+                            .clicked()
                         {
-                            // Combine output and current input line
-                            let mut terminal_content = output.clone();
-                            if !terminal_content.ends_with('\n') {
-                                terminal_content.push('\n');
-                            }
-                            terminal_content.push_str("$ ");
-                            terminal_content.push_str(input);
-
-                            egui::ScrollArea::vertical()
-                                .stick_to_bottom(true)
-                                .auto_shrink([false; 2])
-                                .show(ui, |ui| {
-                                    let response = ui.add(
-                                        egui::TextEdit::multiline(&mut terminal_content)
-                                            .min_size(egui::vec2(available_width, 180.0))
-                                            .font(egui::TextStyle::Monospace)
-                                            .cursor_at_end(true),
-                                    );
-
-                                    // Handle input
-                                    if ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-                                        if !input.trim().is_empty() {
-                                            if let Some(writer) = &mut TERMINAL_WRITER {
-                                                writeln!(writer, "{}", input).unwrap();
-                                            }
-                                            output.push_str("$ ");
-                                            output.push_str(input);
-                                            output.push('\n');
-                                            input.clear();
+                            jump_to = Some(problem.clone());
+                        }
+                    }
+                });
+        });
 
-                                            // Clear the last line from terminal_content
-                                            if let Some(last_line) = terminal_content.lines().last()
-                                            {
-                                                terminal_content.truncate(
-                                                    terminal_content.len() - last_line.len(),
-                                                );
-                                                if terminal_content.ends_with('\n') {
-                                                    terminal_content.push_str("$ ");
-                                                }
-                                            }
-                                        }
-                                        response.request_focus();
-                                    }
+    if let Some(problem) = jump_to {
+        if open_path(ctx, tabs, current_view, &problem.path) {
+            if let Some(doc) = tabs.active() {
+                set_cursor(ctx, doc.id, problem.char_range(doc.text.rope()).start);
+            }
+        }
+    }
+}
 
-                                    // Update input based on new content
-                                    if let Some(last_line) = terminal_content.lines().last() {
-                                        if last_line.starts_with("$ ") {
-                                            *input = last_line[2..].to_string();
-                                        }
-                                    }
-                                });
-                        }
+/// Saves `doc`, asking for a path when it is untitled or `save_as` is set.
+/// Returns false when the user cancelled or the write failed.
+pub fn save_document(ctx: &egui::Context, doc: &mut Document, save_as: bool) -> bool {
+    let path = match &doc.path {
+        Some(path) if !save_as => path.clone(),
+        _ => {
+            let Some(path) = rfd::FileDialog::new()
+                .set_title(if save_as { "Save As" } else { "Save" })
+                .set_file_name(doc.title())
+                .save_file()
+            else {
+                return false;
+            };
+            path
+        }
+    };
+
+    if let Err(e) = doc.save_to(&path) {
+        rfd::MessageDialog::new()
+            .set_title("Error")
+            .set_description(format!("Error saving {}: {}", path.display(), e))
+            .set_level(rfd::MessageLevel::Error)
+            .show();
+        return false;
+    }
+    println!("File saved successfully to: {}", path.display());
+    if save_as {
+        remember_recent(&path, RecentKind::File);
+    }
+    ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
+    true
+}
 
-                        // Status bar
-                        ui.with_layout(egui::Layout::right_to_left(egui::Align::BOTTOM), |ui| {
-                            ui.add_space(5.0);
-                            ui.label(format!(
-                                "{} lines, {} columns | Characters: {}",
-                                line,
-                                col,
-                                text.len()
-                            ));
-                        });
-                    });
-                } else {
-                    // Show status bar when terminal is closed
-                    ui.with_layout(egui::Layout::right_to_left(egui::Align::BOTTOM), |ui| {
-                        ui.add_space(5.0);
-                        ui.label(format!(
-                            "{} lines, {} columns | Characters: {}",
-                            line,
-                            col,
-                            text.len()
-                        ));
-                    });
+/// Lines of a diff between the file on disk and the buffer, with `None`
+/// where unchanged lines were left out.
+struct DiffView {
+    title: String,
+    lines: Vec<Option<(similar::ChangeTag, String)>>,
+}
 
-                    // Cleanup terminal when closed
-                    if TERMINAL_PTY.is_some() {
-                        println!("Cleaning up terminal");
-                        TERMINAL_PTY = None;
-                        TERMINAL_OUTPUT = None;
-                        TERMINAL_INPUT = None;
-                        TERMINAL_WRITER = None;
-                    }
+impl DiffView {
+    fn new(doc: &Document) -> std::io::Result<Self> {
+        let path = doc.path.as_deref().unwrap_or(std::path::Path::new(""));
+        let (disk, _) = doc.encoding.decode(&fs::read(path)?);
+        let disk = line_ending::normalize(&disk);
+        let text = doc.text.to_string();
+        let diff = similar::TextDiff::from_lines(disk.as_ref(), text.as_str());
+        let mut lines = Vec::new();
+        for (idx, group) in diff.grouped_ops(3).iter().enumerate() {
+            if idx > 0 {
+                lines.push(None);
+            }
+            for op in group {
+                for change in diff.iter_changes(op) {
+                    let line = change.value().trim_end_matches('\n').to_string();
+                    lines.push(Some((change.tag(), line)));
                 }
             }
-        });
+        }
+        Ok(Self {
+            title: doc.title(),
+            lines,
+        })
+    }
+}
+
+/// The bar above the editor when the active file changed or vanished on
+/// disk while it had unsaved changes, and the diff it can open.
+fn show_disk_change_bar(ctx: &egui::Context, tabs: &mut Tabs) {
+    let diff_view = unsafe { &mut *std::ptr::addr_of_mut!(DIFF_VIEW) };
+    if let Some(view) = diff_view {
+        let mut open = true;
+        egui::Window::new(format!("{}: on disk vs. unsaved", view.title))
+            .open(&mut open)
+            .default_size([700.0, 450.0])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.colored_label(ui.visuals().error_fg_color, "− on disk");
+                    ui.colored_label(egui::Color32::from_rgb(80, 200, 120), "+ in Kokona");
+                });
+                ui.separator();
+                egui::ScrollArea::both().show(ui, |ui| {
+                    for line in &view.lines {
+                        let Some((tag, text)) = line else {
+                            ui.weak("⋯");
+                            continue;
+                        };
+                        let (sign, color) = match tag {
+                            similar::ChangeTag::Delete => ("−", ui.visuals().error_fg_color),
+                            similar::ChangeTag::Insert => {
+                                ("+", egui::Color32::from_rgb(80, 200, 120))
+                            }
+                            similar::ChangeTag::Equal => (" ", ui.visuals().text_color()),
+                        };
+                        ui.label(
+                            egui::RichText::new(format!("{} {}", sign, text))
+                                .monospace()
+                                .color(color),
+                        );
+                    }
+                });
+            });
+        if !open {
+            *diff_view = None;
+        }
+    }
+
+    let Some(doc) = tabs.active_mut() else {
+        return;
+    };
+    let Some(change) = doc.disk_change else {
+        return;
+    };
+    let mut close = false;
+    egui::TopBottomPanel::top("disk_change_bar").show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            ui.colored_label(ui.visuals().warn_fg_color, "⚠");
+            match change {
+                DiskChange::Modified => {
+                    ui.label(format!("{} changed on disk.", doc.title()));
+                    if ui.button("Reload").clicked() {
+                        if let Err(e) = doc.reopen_with(doc.encoding) {
+                            rfd::MessageDialog::new()
+                                .set_title("Error")
+                                .set_description(format!("Error reloading {}: {}", doc.title(), e))
+                                .set_level(rfd::MessageLevel::Error)
+                                .show();
+                        }
+                        ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
+                    }
+                    if ui.button("Keep Mine").clicked() {
+                        doc.keep_mine();
+                    }
+                    if ui.button("Diff").clicked() {
+                        match DiffView::new(doc) {
+                            Ok(view) => *diff_view = Some(view),
+                            Err(e) => {
+                                rfd::MessageDialog::new()
+                                    .set_title("Error")
+                                    .set_description(format!(
+                                        "Error reading {} for the diff: {}",
+                                        doc.title(),
+                                        e
+                                    ))
+                                    .set_level(rfd::MessageLevel::Error)
+                                    .show();
+                            }
+                        }
+                    }
+                }
+                DiskChange::Deleted => {
+                    ui.label(format!("{} was deleted or moved on disk.", doc.title()));
+                    if ui.button("Save").clicked() {
+                        save_document(ctx, doc, false);
+                    }
+                    if ui.button("Keep Open").clicked() {
+                        doc.keep_mine();
+                        ctx.send_viewport_cmd(egui::ViewportCommand::Title(
+                            "Kokona | MODIFIED".into(),
+                        ));
+                    }
+                    if ui.button("Close").clicked() {
+                        close = true;
+                    }
+                }
+            }
+        });
+    });
+    if close {
+        tabs.request_close(tabs.active);
+    }
+}
+
+fn show_tab_bar(ctx: &egui::Context, tabs: &mut Tabs) {
+    let mut select = None;
+    let mut close = None;
+    egui::TopBottomPanel::top("tab_bar").show(ctx, |ui| {
+        egui::ScrollArea::horizontal().show(ui, |ui| {
+            ui.horizontal(|ui| {
+                for (idx, doc) in tabs.documents.iter().enumerate() {
+                    let label = if doc.modified {
+                        format!("● {}", doc.title())
+                    } else {
+                        doc.title()
+                    };
+                    let tab = ui
+                        .selectable_label(idx == tabs.active, label)
+                        .on_hover_text(doc.filename());
+                    if tab.clicked() {
+                        select = Some(idx);
+                    }
+                    if tab.middle_clicked() || ui.small_button("×").clicked() {
+                        close = Some(idx);
+                    }
+                    ui.separator();
+                }
+            });
+        });
+    });
+
+    if let Some(idx) = select {
+        tabs.active = idx;
+        let title = if tabs.documents[idx].modified {
+            "Kokona | MODIFIED"
+        } else {
+            "Kokona"
+        };
+        ctx.send_viewport_cmd(egui::ViewportCommand::Title(title.into()));
+    }
+    if let Some(idx) = close {
+        tabs.request_close(idx);
+    }
+}
+
+/// The project folder, if one is open.
+pub fn project_root() -> Option<PathBuf> {
+    unsafe { (*std::ptr::addr_of!(PROJECT)).as_ref() }.map(|project| project.root.clone())
+}
+
+fn set_project(project: Option<Project>) {
+    unsafe {
+        EXPLORER_OPEN = project.is_some();
+        PROJECT = project;
+    }
+}
+
+/// Opens `root` as the project and shows it in the explorer, reporting
+/// failures in a dialog.
+pub fn open_project(root: &std::path::Path) -> bool {
+    match Project::open(root) {
+        Ok(project) => {
+            remember_recent(&project.root, RecentKind::Folder);
+            set_project(Some(project));
+            true
+        }
+        Err(e) => {
+            rfd::MessageDialog::new()
+                .set_title("Error")
+                .set_description(format!("Error opening {}: {}", root.display(), e))
+                .set_level(rfd::MessageLevel::Error)
+                .show();
+            false
+        }
+    }
+}
+
+fn open_folder_dialog() {
+    if let Some(dir) = rfd::FileDialog::new()
+        .set_title("Open Folder")
+        .pick_folder()
+    {
+        open_project(&dir);
+    }
+}
+
+/// Where build commands for `file` run: the project root when the file is
+/// part of the project, otherwise the file's own folder.
+fn working_dir(file: &std::path::Path) -> PathBuf {
+    match project_root() {
+        Some(root) if file.starts_with(&root) => root,
+        _ => file
+            .parent()
+            .map(|dir| dir.to_path_buf())
+            .unwrap_or_default(),
+    }
+}
+
+/// Makes the explorer list the folder holding `path` again.
+fn forget_listing(path: &std::path::Path) {
+    if let (Some(project), Some(dir)) = (
+        unsafe { (*std::ptr::addr_of_mut!(PROJECT)).as_mut() },
+        path.parent(),
+    ) {
+        project.forget(dir);
+    }
+}
+
+enum ExplorerAction {
+    Open(PathBuf),
+    Prompt(PromptKind),
+    Delete(PathBuf),
+    CopyPath(PathBuf),
+    Reveal(PathBuf),
+}
+
+#[derive(Clone)]
+enum PromptKind {
+    NewFile(PathBuf),
+    NewFolder(PathBuf),
+    Rename(PathBuf),
+}
+
+/// The name asked for by New File, New Folder and Rename.
+struct ExplorerPrompt {
+    kind: PromptKind,
+    name: String,
+    error: Option<String>,
+    focus: bool,
+}
+
+fn explorer_context_menu(
+    response: &egui::Response,
+    entry: &TreeEntry,
+    is_root: bool,
+    actions: &mut Vec<ExplorerAction>,
+) {
+    response.context_menu(|ui| {
+        if entry.is_dir {
+            if ui.button("New File…").clicked() {
+                actions.push(ExplorerAction::Prompt(PromptKind::NewFile(
+                    entry.path.clone(),
+                )));
+                ui.close_menu();
+            }
+            if ui.button("New Folder…").clicked() {
+                actions.push(ExplorerAction::Prompt(PromptKind::NewFolder(
+                    entry.path.clone(),
+                )));
+                ui.close_menu();
+            }
+            ui.separator();
+        }
+        if !is_root {
+            if ui.button("Rename…").clicked() {
+                actions.push(ExplorerAction::Prompt(PromptKind::Rename(
+                    entry.path.clone(),
+                )));
+                ui.close_menu();
+            }
+            if ui.button("Move to Trash").clicked() {
+                actions.push(ExplorerAction::Delete(entry.path.clone()));
+                ui.close_menu();
+            }
+            ui.separator();
+        }
+        if ui.button("Copy Path").clicked() {
+            actions.push(ExplorerAction::CopyPath(entry.path.clone()));
+            ui.close_menu();
+        }
+        if ui.button("Reveal in File Manager").clicked() {
+            actions.push(ExplorerAction::Reveal(entry.path.clone()));
+            ui.close_menu();
+        }
+    });
+}
+
+/// One level of the explorer tree. Folders list their contents only once
+/// they are expanded.
+fn explorer_dir_ui(
+    ui: &mut egui::Ui,
+    project: &mut Project,
+    dir: &std::path::Path,
+    active: Option<&std::path::Path>,
+    actions: &mut Vec<ExplorerAction>,
+) {
+    let children = project.children(dir);
+    if children.is_empty() {
+        ui.weak("Empty");
+    }
+    for entry in children {
+        let label = format!(
+            "{} {}",
+            project::file_icon(&entry.path, entry.is_dir),
+            entry.name()
+        );
+        if entry.is_dir {
+            let response = egui::CollapsingHeader::new(label)
+                .id_salt(&entry.path)
+                .show(ui, |ui| {
+                    explorer_dir_ui(ui, project, &entry.path, active, actions);
+                });
+            explorer_context_menu(&response.header_response, &entry, false, actions);
+        } else {
+            let response = ui.selectable_label(active == Some(entry.path.as_path()), label);
+            if response.clicked() {
+                actions.push(ExplorerAction::Open(entry.path.clone()));
+            }
+            explorer_context_menu(&response, &entry, false, actions);
+        }
+    }
+}
+
+/// The project tree on the left, while a folder is open.
+fn show_explorer_panel(ctx: &egui::Context, tabs: &mut Tabs, current_view: &mut ViewType) {
+    let project = unsafe { &mut *std::ptr::addr_of_mut!(PROJECT) };
+    let Some(project) = project.as_mut() else {
+        return;
+    };
+    if !unsafe { EXPLORER_OPEN } {
+        return;
+    }
+
+    let mut actions = Vec::new();
+    let active = tabs.active().and_then(|doc| doc.path.clone());
+    egui::SidePanel::left("explorer")
+        .resizable(true)
+        .default_width(220.0)
+        .show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                let root = TreeEntry {
+                    path: project.root.clone(),
+                    is_dir: true,
+                };
+                let response = ui
+                    .strong(project.name())
+                    .on_hover_text(project.root.display().to_string());
+                explorer_context_menu(&response, &root, true, &mut actions);
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    if ui.small_button("✖").on_hover_text("Hide").clicked() {
+                        unsafe { EXPLORER_OPEN = false };
+                    }
+                    if ui.small_button("⟳").on_hover_text("Refresh").clicked() {
+                        project.refresh();
+                    }
+                    if ui.small_button("🗀").on_hover_text("New Folder").clicked() {
+                        actions.push(ExplorerAction::Prompt(PromptKind::NewFolder(
+                            project.root.clone(),
+                        )));
+                    }
+                    if ui.small_button("🗋").on_hover_text("New File").clicked() {
+                        actions.push(ExplorerAction::Prompt(PromptKind::NewFile(
+                            project.root.clone(),
+                        )));
+                    }
+                });
+            });
+            ui.separator();
+            egui::ScrollArea::both()
+                .auto_shrink([false, false])
+                .show(ui, |ui| {
+                    let root = project.root.clone();
+                    explorer_dir_ui(ui, project, &root, active.as_deref(), &mut actions);
+                });
+        });
+
+    for action in actions {
+        match action {
+            ExplorerAction::Open(path) => {
+                open_path(ctx, tabs, current_view, &path);
+            }
+            ExplorerAction::Prompt(kind) => {
+                let name = match &kind {
+                    PromptKind::Rename(path) => path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    _ => String::new(),
+                };
+                unsafe {
+                    EXPLORER_PROMPT = Some(ExplorerPrompt {
+                        kind,
+                        name,
+                        error: None,
+                        focus: true,
+                    });
+                }
+            }
+            ExplorerAction::Delete(path) => {
+                let confirmed = rfd::MessageDialog::new()
+                    .set_title("Move to Trash")
+                    .set_description(format!("Move {} to the trash?", path.display()))
+                    .set_buttons(rfd::MessageButtons::YesNo)
+                    .show()
+                    == rfd::MessageDialogResult::Yes;
+                if confirmed {
+                    if let Err(e) = project::move_to_trash(&path) {
+                        rfd::MessageDialog::new()
+                            .set_title("Error")
+                            .set_description(format!(
+                                "Error moving {} to the trash: {}",
+                                path.display(),
+                                e
+                            ))
+                            .set_level(rfd::MessageLevel::Error)
+                            .show();
+                    }
+                    forget_listing(&path);
+                }
+            }
+            ExplorerAction::CopyPath(path) => ctx.copy_text(path.display().to_string()),
+            ExplorerAction::Reveal(path) => {
+                if let Err(e) = project::reveal(&path) {
+                    println!("Failed to open the file manager: {}", e);
+                }
+            }
+        }
+    }
+    show_explorer_prompt(ctx, tabs, current_view);
+}
+
+fn show_explorer_prompt(ctx: &egui::Context, tabs: &mut Tabs, current_view: &mut ViewType) {
+    let prompt_slot = unsafe { &mut *std::ptr::addr_of_mut!(EXPLORER_PROMPT) };
+    let Some(prompt) = prompt_slot.as_mut() else {
+        return;
+    };
+    let title = match prompt.kind {
+        PromptKind::NewFile(_) => "New File",
+        PromptKind::NewFolder(_) => "New Folder",
+        PromptKind::Rename(_) => "Rename",
+    };
+
+    let mut submit = false;
+    let mut cancel = false;
+    egui::Window::new(title)
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+        .show(ctx, |ui| {
+            let response = ui.text_edit_singleline(&mut prompt.name);
+            if prompt.focus {
+                response.request_focus();
+                prompt.focus = false;
+            }
+            submit = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            if let Some(error) = &prompt.error {
+                ui.colored_label(ui.visuals().error_fg_color, error);
+            }
+            ui.add_space(8.0);
+            ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                cancel = ui.button("Cancel").clicked();
+                submit |= ui.button("OK").clicked();
+            });
+        });
+    if cancel {
+        *prompt_slot = None;
+        return;
+    }
+    if !submit {
+        return;
+    }
+
+    let name = prompt.name.trim();
+    let result = match &prompt.kind {
+        PromptKind::NewFile(dir) => project::create_file(dir, name).inspect(|path| {
+            open_path(ctx, tabs, current_view, path);
+        }),
+        PromptKind::NewFolder(dir) => project::create_folder(dir, name),
+        PromptKind::Rename(from) => project::rename(from, name).inspect(|to| {
+            // Open documents inside what was renamed move along with it
+            for doc in &mut tabs.documents {
+                let Some(rest) = doc.path.as_ref().and_then(|p| p.strip_prefix(from).ok()) else {
+                    continue;
+                };
+                let moved = if rest.as_os_str().is_empty() {
+                    to.clone()
+                } else {
+                    to.join(rest)
+                };
+                doc.path = Some(moved);
+                doc.syntax = None;
+            }
+            forget_listing(from);
+        }),
+    };
+    match result {
+        Ok(path) => {
+            forget_listing(&path);
+            *prompt_slot = None;
+        }
+        Err(e) => prompt.error = Some(e.to_string()),
+    }
+}
+
+fn recent_list() -> &'static mut RecentList {
+    unsafe { (*std::ptr::addr_of_mut!(RECENT)).get_or_insert_with(RecentList::load) }
+}
+
+/// Puts `path` at the top of the recent files or folders.
+pub fn remember_recent(path: &std::path::Path, kind: RecentKind) {
+    let recent = recent_list();
+    recent.add(path, kind);
+    if let Err(e) = recent.save() {
+        println!("Failed to save recent files: {}", e);
+    }
+}
+
+enum RecentAction {
+    Open(RecentEntry),
+    TogglePin(RecentEntry),
+    Remove(RecentEntry),
+}
+
+fn apply_recent_action(
+    ctx: &egui::Context,
+    tabs: &mut Tabs,
+    current_view: &mut ViewType,
+    action: RecentAction,
+) {
+    let recent = recent_list();
+    match action {
+        RecentAction::Open(entry) => match entry.kind {
+            RecentKind::File => {
+                open_path(ctx, tabs, current_view, &entry.path);
+            }
+            RecentKind::Folder => {
+                open_project(&entry.path);
+            }
+        },
+        RecentAction::TogglePin(entry) => recent.toggle_pin(&entry.path, entry.kind),
+        RecentAction::Remove(entry) => recent.remove(&entry.path, entry.kind),
+    }
+    if let Err(e) = recent.save() {
+        println!("Failed to save recent files: {}", e);
+    }
+}
+
+/// Lists recent entries of one kind for the home screen: pin, path, when it
+/// was opened, and a remove button. Entries that are gone are greyed out.
+fn recent_grid_ui(ui: &mut egui::Ui, kind: RecentKind) -> Option<RecentAction> {
+    let mut action = None;
+    let entries: Vec<RecentEntry> = recent_list().entries(kind).cloned().collect();
+    if entries.is_empty() {
+        ui.weak("Nothing yet");
+        return None;
+    }
+    egui::Grid::new(("recent", kind as u8))
+        .striped(true)
+        .show(ui, |ui| {
+            for entry in entries {
+                let pin = egui::RichText::new("📌");
+                let pin = if entry.pinned { pin } else { pin.weak() };
+                if ui
+                    .add(egui::Button::new(pin).frame(false))
+                    .on_hover_text(if entry.pinned { "Unpin" } else { "Pin" })
+                    .clicked()
+                {
+                    action = Some(RecentAction::TogglePin(entry.clone()));
+                }
+                let exists = entry.path.exists();
+                let label = entry.path.display().to_string();
+                let link = ui.add_enabled(exists, egui::Link::new(label));
+                if !exists {
+                    link.on_disabled_hover_text("No longer exists");
+                } else if link.clicked() {
+                    action = Some(RecentAction::Open(entry.clone()));
+                }
+                ui.weak(time_ago(entry.opened_at));
+                if ui
+                    .add(egui::Button::new("✖").frame(false))
+                    .on_hover_text("Remove from list")
+                    .clicked()
+                {
+                    action = Some(RecentAction::Remove(entry));
+                }
+                ui.end_row();
+            }
+        });
+    action
+}
+
+/// The entries of one kind as menu buttons.
+fn recent_menu_ui(ui: &mut egui::Ui, kind: RecentKind) -> Option<RecentAction> {
+    let mut action = None;
+    for entry in recent_list().entries(kind).cloned().collect::<Vec<_>>() {
+        ui.horizontal(|ui| {
+            let exists = entry.path.exists();
+            let mut label = entry.path.display().to_string();
+            if entry.pinned {
+                label = format!("📌 {}", label);
+            }
+            if ui.add_enabled(exists, egui::Button::new(label)).clicked() {
+                action = Some(RecentAction::Open(entry.clone()));
+            }
+            if !exists && ui.small_button("✖").on_hover_text("Remove").clicked() {
+                action = Some(RecentAction::Remove(entry));
+            }
+        });
+    }
+    action
+}
+
+/// How long ago a Unix timestamp was, roughly, e.g. "5 minutes ago".
+fn time_ago(timestamp: u64) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let secs = now.saturating_sub(timestamp);
+    let (count, unit) = match secs {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86_399 => (secs / 3600, "hour"),
+        _ => (secs / 86_400, "day"),
+    };
+    format!(
+        "{} {}{} ago",
+        count,
+        unit,
+        if count == 1 { "" } else { "s" }
+    )
+}
+
+/// Lists what an earlier session that didn't exit cleanly left unsaved.
+fn show_recovery_prompt(ctx: &egui::Context, tabs: &mut Tabs, current_view: &mut ViewType) {
+    let found = unsafe { &mut *std::ptr::addr_of_mut!(RECOVERY) };
+    let snapshots = found.get_or_insert_with(autosave::find_snapshots);
+    if snapshots.is_empty() {
+        return;
+    }
+
+    let mut recover = Vec::new();
+    let mut discard = Vec::new();
+    egui::Window::new("Recover unsaved work")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+        .show(ctx, |ui| {
+            ui.label("Kokona didn't exit cleanly last time. These had unsaved changes:");
+            ui.add_space(5.0);
+            egui::Grid::new("recovery_list")
+                .striped(true)
+                .show(ui, |ui| {
+                    for (idx, snapshot) in snapshots.iter().enumerate() {
+                        ui.label(snapshot.title());
+                        ui.weak(time_ago(snapshot.taken_at));
+                        if ui.button("Recover").clicked() {
+                            recover.push(idx);
+                        }
+                        if ui.button("Discard").clicked() {
+                            discard.push(idx);
+                        }
+                        ui.end_row();
+                    }
+                });
+            ui.add_space(8.0);
+            ui.horizontal(|ui| {
+                if ui.button("Recover All").clicked() {
+                    recover.extend(0..snapshots.len());
+                }
+                if ui.button("Discard All").clicked() {
+                    discard.extend(0..snapshots.len());
+                }
+            });
+        });
+
+    let mut remaining = Vec::new();
+    for (idx, snapshot) in std::mem::take(snapshots).into_iter().enumerate() {
+        if recover.contains(&idx) {
+            tabs.push(snapshot.into_document());
+            *current_view = ViewType::Editor;
+            ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona | MODIFIED".into()));
+        } else if discard.contains(&idx) {
+            snapshot.discard();
+        } else {
+            remaining.push(snapshot);
+        }
+    }
+    *snapshots = remaining;
+}
+
+pub fn home_view(ctx: &egui::Context, current_view: &mut ViewType, tabs: &mut Tabs) {
+    unsafe {
+        SETTINGS = Some(EditorSettings::load());
+        // Created here too so the home screen and Settings follow the theme
+        let editor_state = &mut *std::ptr::addr_of_mut!(EDITOR_STATE);
+        editor_state.get_or_insert_with(|| EditorState::new(ctx));
+    }
+    let mut should_create_new = false; // flag for new file
+    let mut recent_action = None;
+    if let Some((current_version, latest_version)) = SHOULD_SHOW_UPDATE.get() {
+        if !UPDATE_DIALOG_SHOWN.load(Ordering::SeqCst) {
+            egui::Window::new("Update Available")
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+                .show(ctx, |ui| {
+                    ui.vertical_centered(|ui| {
+                        ui.label(format!(
+                            "A new version of Kokona is available!\nCurrent: v{}\nLatest: v{}",
+                            current_version, latest_version
+                        ));
+                        ui.add_space(8.0);
+                        ui.horizontal(|ui| {
+                            ui.with_layout(
+                                egui::Layout::right_to_left(egui::Align::Center),
+                                |ui| {
+                                    if ui.button("Open Download Page").clicked() {
+                                        if let Err(e) = open::that(
+                                            "https://github.com/Noxyntious/kokona/releases/latest",
+                                        ) {
+                                            println!("Failed to open URL: {}", e);
+                                        }
+                                        UPDATE_DIALOG_SHOWN.store(true, Ordering::SeqCst);
+                                    }
+                                    if ui.button("Dismiss").clicked() {
+                                        UPDATE_DIALOG_SHOWN.store(true, Ordering::SeqCst);
+                                    }
+                                },
+                            );
+                        });
+                    });
+                });
+        }
+    }
+
+    if !UPDATE_CHECK_DONE.load(Ordering::SeqCst) {
+        UPDATE_CHECK_DONE.store(true, Ordering::SeqCst);
+
+        let ctx_clone = ctx.clone();
+        std::thread::spawn(move || {
+            // wait for 2 seconds to let the application load
+            std::thread::sleep(std::time::Duration::from_millis(500));
+
+            std::thread::Builder::new()
+                .name("update-checker".to_string())
+                .spawn(move || {
+                    let runtime = tokio::runtime::Runtime::new().unwrap();
+                    runtime.block_on(async {
+                        let client = reqwest::Client::new();
+                        match client
+                            .get("https://api.github.com/repos/Noxyntious/kokona/releases/latest")
+                            .header("User-Agent", "kokona-update-checker")
+                            .send()
+                            .await
+                        {
+                            Ok(response) => {
+                                match response.text().await {
+                                    Ok(text) => {
+                                        match serde_json::from_str::<GithubRelease>(&text) {
+                                            Ok(release) => {
+                                                let latest_version = release
+                                                    .tag_name
+                                                    .trim_start_matches('v')
+                                                    .to_string();
+                                                let current_version =
+                                                    crate::consts::versioninfo::VERSION.to_string();
+
+                                                if compare_versions(
+                                                    &current_version,
+                                                    &latest_version,
+                                                ) {
+                                                    let _ = SHOULD_SHOW_UPDATE
+                                                        .set((current_version, latest_version));
+                                                    ctx_clone.request_repaint();
+                                                    // Request UI update
+                                                }
+                                            }
+                                            Err(e) => println!("Failed to parse JSON: {}", e),
+                                        }
+                                    }
+                                    Err(e) => println!("Failed to get response text: {}", e),
+                                }
+                            }
+                            Err(e) => println!("Failed to get response from GitHub: {}", e),
+                        }
+                    });
+                })
+                .expect("Failed to spawn update checker thread");
+        });
+    }
+    show_recovery_prompt(ctx, tabs, current_view);
+    show_explorer_panel(ctx, tabs, current_view);
+    egui::CentralPanel::default().show(ctx, |ui| {
+        ui.add_space(15.0);
+        ui.horizontal(|ui| {
+            ui.heading(egui::RichText::new("Kokona").size(72.0));
+            ui.vertical(|ui| {
+                ui.add_space(55.5);
+                ui.label(format!("ver {}", crate::consts::versioninfo::VERSION));
+            });
+        });
+        ui.add_space(30.0);
+        ui.group(|ui| {
+            ui.vertical(|ui| {
+                ui.label("Start");
+                ui.add_space(5.0);
+                if ui.button("New File").clicked() {
+                    should_create_new = true;
+                    *current_view = ViewType::Editor;
+                }
+                if ui.button("Open File").clicked() {
+                    open_file_dialog(ctx, tabs, current_view);
+                }
+                if ui.button("Open Folder").clicked() {
+                    open_folder_dialog();
+                }
+            });
+        });
+        ui.add_space(10.0);
+        ui.group(|ui| {
+            egui::ScrollArea::vertical()
+                .id_salt("recent")
+                .max_height(ui.available_height() - 40.0)
+                .show(ui, |ui| {
+                    ui.label("Recent Files");
+                    ui.add_space(5.0);
+                    let files = recent_grid_ui(ui, RecentKind::File);
+                    ui.add_space(10.0);
+                    ui.label("Recent Folders");
+                    ui.add_space(5.0);
+                    let folders = recent_grid_ui(ui, RecentKind::Folder);
+                    recent_action = files.or(folders);
+                });
+        });
+        ui.with_layout(egui::Layout::bottom_up(egui::Align::RIGHT), |ui| {
+            if ui.link("kokona.nijika.dev").clicked() {
+                if let Err(e) = open::that("https://kokona.nijika.dev") {
+                    println!("Failed to open URL: {}", e);
+                }
+            }
+        });
+    });
+
+    // Handle filename change after UI
+    if should_create_new {
+        tabs.new_document();
+        ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
+    }
+    if let Some(action) = recent_action {
+        apply_recent_action(ctx, tabs, current_view, action);
+    }
+    // Check for Ctrl+O, leaving Ctrl+Shift+O alone as it only means
+    // something in the editor
+    let open_file = ctx.input_mut(|i| {
+        !i.consume_key(
+            egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+            egui::Key::O,
+        ) && i.consume_key(egui::Modifiers::COMMAND, egui::Key::O)
+    });
+    if open_file {
+        open_file_dialog(ctx, tabs, current_view);
+    }
+    show_top_panel(ctx, tabs, current_view);
+}
+
+pub fn editor_view(ctx: &egui::Context, tabs: &mut Tabs, current_view: &mut ViewType) {
+    unsafe {
+        if SEARCH_STATE.is_none() {
+            SEARCH_STATE = Some(SearchState::default());
+        }
+        if EDITOR_STATE.is_none() {
+            EDITOR_STATE = Some(EditorState::new(ctx));
+        }
+    }
+
+    // Tab shortcuts are consumed before the TextEdit sees them, otherwise
+    // Ctrl+Tab would also insert a tab character. The Shift variants go
+    // first as the plain ones match them too. In the terminal they are
+    // left for the shell
+    let shortcuts = !terminal_focused(ctx);
+    let (prev_tab, next_tab, close_tab, goto_symbol, open_file) = if shortcuts {
+        ctx.input_mut(|i| {
+            (
+                i.consume_key(
+                    egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+                    egui::Key::Tab,
+                ),
+                i.consume_key(egui::Modifiers::COMMAND, egui::Key::Tab),
+                i.consume_key(egui::Modifiers::COMMAND, egui::Key::W),
+                i.consume_key(
+                    egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+                    egui::Key::O,
+                ),
+                i.consume_key(egui::Modifiers::COMMAND, egui::Key::O),
+            )
+        })
+    } else {
+        Default::default()
+    };
+    if next_tab {
+        tabs.select_next();
+    }
+    if prev_tab {
+        tabs.select_prev();
+    }
+    if close_tab {
+        tabs.request_close(tabs.active);
+    }
+
+    show_top_panel(ctx, tabs, current_view);
+    show_explorer_panel(ctx, tabs, current_view);
+    show_tab_bar(ctx, tabs);
+    show_disk_change_bar(ctx, tabs);
+
+    if tabs.is_empty() {
+        *current_view = ViewType::Home;
+        ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
+        return;
+    }
+
+    if open_file {
+        open_file_dialog(ctx, tabs, current_view);
+    }
+
+    unsafe {
+        if let Some(editor_state) = EDITOR_STATE.as_ref() {
+            editor_state
+                .highlighter
+                .retain(|id| tabs.index_of(id).is_some());
+        }
+    }
+
+    handle_lsp_replies(ctx, tabs, current_view);
+    show_rename_prompt(ctx, tabs);
+
+    let Some(doc) = tabs.active_mut() else {
+        return;
+    };
+
+    // Check for Ctrl+S
+    if shortcuts && ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::S)) {
+        save_document(ctx, doc, false);
+    }
+
+    unsafe {
+        if let Some(editor_state) = EDITOR_STATE.as_mut() {
+            if doc.syntax.is_none() {
+                doc.syntax = Some(editor_state.syntax_name_for(&doc.filename()));
+            }
+        }
+    }
+
+    // Undo/redo are ours, not the TextEdit's built-in undoer. Other text
+    // fields and the terminal keep the keys while they have focus
+    let editor_focused = ctx.memory(|m| m.has_focus(editor_id(doc.id)));
+    let (redo, undo) = if editor_focused {
+        ctx.input_mut(|i| {
+            (
+                i.consume_key(
+                    egui::Modifiers::COMMAND | egui::Modifiers::SHIFT,
+                    egui::Key::Z,
+                ) || i.consume_key(egui::Modifiers::COMMAND, egui::Key::Y),
+                i.consume_key(egui::Modifiers::COMMAND, egui::Key::Z),
+            )
+        })
+    } else {
+        (false, false)
+    };
+    let cursor_after = if undo {
+        doc.undo()
+    } else if redo {
+        doc.redo()
+    } else {
+        None
+    };
+    if let Some(cursor) = cursor_after {
+        set_cursor(ctx, doc.id, cursor);
+        let title = if doc.modified {
+            "Kokona | MODIFIED"
+        } else {
+            "Kokona"
+        };
+        ctx.send_viewport_cmd(egui::ViewportCommand::Title(title.into()));
+    }
+
+    // The completion list and the language server shortcuts come before
+    // indentation, which would take Tab and Enter otherwise
+    let cursor_index = egui::TextEdit::load_state(ctx, editor_id(doc.id))
+        .and_then(|state| state.cursor.char_range())
+        .or(doc.cursor)
+        .map_or(0, |range| range.primary.index.min(doc.text.len_chars()));
+    let completion = unsafe { &mut *std::ptr::addr_of_mut!(COMPLETION) };
+    // The list goes once the cursor leaves the word it was opened for
+    if completion.as_ref().is_some_and(|popup| {
+        popup.doc_id != doc.id
+            || cursor_index < popup.at
+            || doc
+                .text
+                .char_slice(popup.at..cursor_index)
+                .contains(|c| !is_word_char(c))
+            || popup.matching(&doc.text, cursor_index).is_empty()
+    }) {
+        *completion = None;
+    }
+    if editor_focused {
+        if let Some(popup) = completion.as_mut() {
+            let (down, up, accept, escape) = ctx.input_mut(|i| {
+                (
+                    i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown),
+                    i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp),
+                    i.consume_key(egui::Modifiers::NONE, egui::Key::Enter)
+                        || i.consume_key(egui::Modifiers::NONE, egui::Key::Tab),
+                    i.consume_key(egui::Modifiers::NONE, egui::Key::Escape),
+                )
+            });
+            let count = popup.matching(&doc.text, cursor_index).len();
+            if down {
+                popup.selected = (popup.selected + 1) % count;
+            }
+            if up {
+                popup.selected = (popup.selected + count - 1) % count;
+            }
+            popup.selected = popup.selected.min(count - 1);
+            if accept {
+                let insert = popup.matching(&doc.text, cursor_index)[popup.selected]
+                    .insert
+                    .clone();
+                accept_completion(
+                    ctx,
+                    doc.id,
+                    (&mut doc.text, &mut doc.history),
+                    popup.at..cursor_index,
+                    &insert,
+                );
+                doc.modified = true;
+            }
+            if accept || escape {
+                *completion = None;
+            }
+        }
+        let (complete, definition, rename) = ctx.input_mut(|i| {
+            (
+                i.consume_key(egui::Modifiers::COMMAND, egui::Key::Space),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::F12),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::F2),
+            )
+        });
+        if complete {
+            let at = word_at(&doc.text, cursor_index).start;
+            lsp_client().completion(doc, at, cursor_index);
+        }
+        if definition {
+            lsp_client().definition(doc, cursor_index);
+        }
+        if rename && lsp_client().has_server(doc) {
+            let word = word_at(&doc.text, cursor_index);
+            unsafe {
+                RENAME_PROMPT = Some(RenamePrompt {
+                    doc_id: doc.id,
+                    at: word.start,
+                    name: doc.text.char_slice(word).to_string(),
+                    focus: true,
+                });
+            }
+        }
+    }
+
+    // Tab, Shift+Tab and Enter indent the way the language wants, so they
+    // are taken from the editor before it sees them
+    let language = doc.syntax.clone().unwrap_or_default();
+    let (indent_settings, indent_source) = doc.indent(current_settings().indent_for(&language));
+    if ctx.memory(|m| m.has_focus(editor_id(doc.id))) {
+        // Shift+Tab first: plain Tab would match it too
+        let (shift_tab, tab, enter) = ctx.input_mut(|i| {
+            (
+                i.consume_key(egui::Modifiers::SHIFT, egui::Key::Tab),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::Tab),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::Enter),
+            )
+        });
+        if tab || shift_tab || enter {
+            let len = doc.text.len_chars();
+            let selection = egui::TextEdit::load_state(ctx, editor_id(doc.id))
+                .and_then(|state| state.cursor.char_range())
+                .or(doc.cursor)
+                .map(|range| egui::text::CCursorRange {
+                    primary: egui::text::CCursor::new(range.primary.index.min(len)),
+                    secondary: egui::text::CCursor::new(range.secondary.index.min(len)),
+                })
+                .unwrap_or_default();
+            let result = if tab {
+                indent::indent(&doc.text, &selection, indent_settings)
+            } else if shift_tab {
+                indent::outdent(&doc.text, &selection, indent_settings)
+            } else {
+                indent::newline(&doc.text, &selection, indent_settings, &language)
+            };
+            if !result.edits.is_empty() {
+                doc.edit(result.edits);
+                ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona | MODIFIED".into()));
+            }
+            doc.cursor = Some(result.selection);
+            set_selection(ctx, doc.id, result.selection);
+        }
+    }
+
+    let filename = doc.filename();
+    let doc_id = doc.id;
+    let syntax = doc.syntax.clone().unwrap_or_default();
+    let mut diagnostics = doc
+        .path
+        .as_deref()
+        .map(|path| lsp_client().diagnostics(path).to_vec())
+        .unwrap_or_default();
+    let problems = unsafe { &*std::ptr::addr_of!(PROBLEMS) };
+    if let Some(path) = doc.path.as_deref().filter(|_| !problems.is_empty()) {
+        let path = problem_path(path);
+        diagnostics.extend(
+            problems
+                .iter()
+                .filter(|problem| problem.path == path)
+                .map(|problem| problem.diagnostic(doc.text.rope())),
+        );
+    }
+    let trigger_characters = lsp_client().trigger_characters(doc).to_vec();
+    let mut lsp_request = None;
+    unsafe {
+        if let Some(editor_state) = EDITOR_STATE.as_ref() {
+            editor_state.highlighter.update(doc_id, &doc.text, &syntax);
+        }
+    }
+    let Document {
+        text,
+        modified,
+        scroll_offset,
+        scroll_to,
+        cursor,
+        history,
+        encoding,
+        line_ending,
+        mixed_line_endings,
+        ..
+    } = doc;
+
+    let goto_line =
+        shortcuts && ctx.input_mut(|i| i.consume_key(egui::Modifiers::COMMAND, egui::Key::G));
+    unsafe {
+        if goto_line {
+            GOTO_LINE_OPEN = true;
+            (*std::ptr::addr_of_mut!(GOTO_LINE_INPUT)).clear();
+        }
+        let picker = &mut *std::ptr::addr_of_mut!(SYMBOL_PICKER);
+        if goto_symbol {
+            if let Some(editor_state) = (*std::ptr::addr_of!(EDITOR_STATE)).as_ref() {
+                *picker = Some(SymbolPicker {
+                    doc_id,
+                    revision: text.revision(),
+                    symbols: symbols::extract(&editor_state.ps, &syntax, text),
+                    filter: String::new(),
+                    selected: 0,
+                });
+            }
+        }
+        // The list is only good for the text it was made from
+        if picker
+            .as_ref()
+            .is_some_and(|p| p.doc_id != doc_id || p.revision != text.revision())
+        {
+            *picker = None;
+        }
+    }
+    show_goto_windows(ctx, doc_id, text, indent_settings.tab_width);
+
+    // Check for Ctrl+F
+    if shortcuts && ctx.input(|i| i.key_pressed(egui::Key::F) && i.modifiers.command) {
+        unsafe {
+            if let Some(state) = SEARCH_STATE.as_mut() {
+                state.open = true;
+                state.find_matches(text, doc_id);
+            }
+        }
+    }
+
+    let text_ref: &Buffer = text;
+    let selection = cursor.map(|range| {
+        let [start, end] = range.sorted();
+        text_ref.char_to_byte(start.index)..text_ref.char_to_byte(end.index)
+    });
+    let mut replace_edits = Vec::new();
+
+    // Show search window if open
+    unsafe {
+        if let Some(state) = SEARCH_STATE.as_mut() {
+            if state.open {
+                egui::Window::new("Search")
+                    .default_width(340.0)
+                    .resizable(false)
+                    .collapsible(false)
+                    .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+                    .show(ctx, |ui| {
+                        egui::Grid::new("search_grid")
+                            .num_columns(2)
+                            .show(ui, |ui| {
+                                ui.label("Find:");
+                                ui.horizontal(|ui| {
+                                    let query_changed =
+                                        ui.text_edit_singleline(&mut state.query).changed();
+                                    if query_changed {
+                                        state.find_matches(text_ref, doc_id);
+                                        state.current_match = 0;
+                                    }
+
+                                    if ui.button("×").clicked() {
+                                        state.open = false;
+                                    }
+                                });
+                                ui.end_row();
+
+                                ui.label("Replace:");
+                                ui.horizontal(|ui| {
+                                    ui.text_edit_singleline(&mut state.replacement);
+                                    if ui
+                                        .add_enabled(
+                                            !state.matches.is_empty(),
+                                            egui::Button::new("Replace"),
+                                        )
+                                        .clicked()
+                                    {
+                                        replace_edits.extend(state.replace_current(text_ref));
+                                    }
+                                    if ui
+                                        .add_enabled(
+                                            !state.matches.is_empty(),
+                                            egui::Button::new("All"),
+                                        )
+                                        .on_hover_text("Replace all")
+                                        .clicked()
+                                    {
+                                        replace_edits = state.replace_all(text_ref);
+                                    }
+                                });
+                                ui.end_row();
+                            });
+
+                        ui.horizontal(|ui| {
+                            let mut changed = ui
+                                .checkbox(&mut state.case_sensitive, "Case sensitive")
+                                .changed();
+                            changed |= ui.checkbox(&mut state.whole_word, "Whole word").changed();
+                            changed |= ui.checkbox(&mut state.regex, "Regex").changed();
+                            let mut in_selection = state.in_selection;
+                            if ui
+                                .add_enabled(
+                                    state.in_selection
+                                        || selection.as_ref().is_some_and(|r| !r.is_empty()),
+                                    egui::Checkbox::new(&mut in_selection, "In selection"),
+                                )
+                                .changed()
+                            {
+                                state.set_scope(if in_selection {
+                                    selection.clone()
+                                } else {
+                                    None
+                                });
+                                changed = true;
+                            }
+                            if changed {
+                                state.find_matches(text_ref, doc_id);
+                            }
+                        });
+
+                        ui.horizontal(|ui| {
+                            if ui.button("⬆ Previous").clicked()
+                                || ui.input(|i| i.key_pressed(egui::Key::N) && i.modifiers.shift)
+                            {
+                                state.prev_match();
+                            }
+                            if ui.button("⬇ Next").clicked()
+                                || ui.input(|i| i.key_pressed(egui::Key::N) && i.modifiers.command)
+                            {
+                                state.next_match();
+                            }
+                        });
+
+                        if let Some(error) = &state.error {
+                            ui.colored_label(ui.visuals().error_fg_color, error);
+                        } else {
+                            ui.label(format!(
+                                "{} matches found{}",
+                                state.matches.len(),
+                                if !state.matches.is_empty() {
+                                    format!(
+                                        " (showing {}/{})",
+                                        state.current_match + 1,
+                                        state.matches.len()
+                                    )
+                                } else {
+                                    String::new()
+                                }
+                            ));
+                        }
+
+                        if ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                            state.open = false;
+                        }
+                    });
+            }
+        }
+    }
+
+    // Replacing is one undo step, however many matches it touches
+    if !replace_edits.is_empty() {
+        let cursor_after = replace_edits
+            .iter()
+            .map(|edit| edit.apply(text))
+            .last()
+            .unwrap_or_default();
+        history.record_step(replace_edits);
+        *modified = true;
+        set_cursor(ctx, doc_id, cursor_after);
+        ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona | MODIFIED".into()));
+    }
+    unsafe {
+        let search_state = &mut *std::ptr::addr_of_mut!(SEARCH_STATE);
+        if let Some(state) = search_state.as_mut().filter(|s| s.open) {
+            state.update(text, doc_id);
+        }
+    }
+
+    egui::CentralPanel::default().show(ctx, |ui| {
+        let available_width = ui.available_width();
+        let available_height = ui.available_height()
+            - (unsafe {
+                if TERMINAL_OPEN {
+                    TERMINAL_HEIGHT + 40.0
+                } else {
+                    20.0
+                }
+            });
+
+        let font_id = egui::FontId::monospace(current_settings().font_size);
+        let row_height = ui.fonts(|f| f.row_height(&font_id));
+        let total_lines = text.len_lines();
+        // Short documents go to the TextEdit whole. Long ones only around
+        // the scroll position, so layout doesn't grow with the file
+        let windowed =
+            total_lines > 2 * EditorWindow::MARGIN_PAGES * page_lines(available_height, row_height);
+        let focused = ctx.memory(|m| m.has_focus(editor_id(doc_id)));
+        if windowed {
+            if focused {
+                jump_across_window(ctx, doc_id, text.len_chars());
+            }
+            // Typing at, or jumping to, a cursor that was scrolled away
+            // brings its lines back first, or the TextEdit would edit the
+            // lines it has instead
+            let offset = scroll_to.map_or(scroll_offset.y, |offset| offset.y);
+            let lines = EditorWindow::new(offset, available_height, row_height, total_lines).lines;
+            let cursor_line = egui::TextEdit::load_state(ctx, editor_id(doc_id))
+                .and_then(|state| state.cursor.char_range())
+                .or(*cursor)
+                .map(|range| {
+                    text.rope()
+                        .char_to_line(range.primary.index.min(text.len_chars()))
+                });
+            let typing = focused && ui.input(|i| i.events.iter().any(edits_at_cursor));
+            let reveal = unsafe { REVEAL_CURSOR == Some(doc_id) };
+            if let Some(line) = cursor_line.filter(|line| !lines.contains(line)) {
+                if typing || reveal {
+                    let offset =
+                        EditorWindow::offset_for(line, available_height, row_height, total_lines);
+                    *scroll_to = Some(egui::vec2(0.0, offset));
+                    unsafe {
+                        REVEAL_CURSOR = Some(doc_id);
+                    }
+                }
+            }
+        }
+
+        let mut scroll_area = egui::ScrollArea::vertical()
+            .id_salt(doc_id)
+            .max_height(available_height);
+        if let Some(offset) = scroll_to.take() {
+            scroll_area = scroll_area.scroll_offset(offset);
+        }
+        let scroll_output = scroll_area.show_viewport(ui, |ui, viewport| {
+            let origin = ui.max_rect().min;
+            let window = if windowed {
+                EditorWindow::new(viewport.min.y, available_height, row_height, total_lines)
+            } else {
+                EditorWindow::whole(total_lines)
+            };
+
+            // The TextEdit draws no frame so the background and the
+            // current line highlight can go under its text
+            let background = ui.painter().add(egui::Shape::Noop);
+            let gutter_width = gutter_width(ui, &font_id, total_lines);
+            let gutter_rect = egui::Rect::from_min_size(
+                origin + egui::vec2(0.0, viewport.min.y),
+                egui::vec2(gutter_width, available_height),
+            );
+            let editor_left = origin.x + gutter_width + ui.spacing().item_spacing.x;
+            let editor_width = available_width - gutter_width - ui.spacing().item_spacing.x;
+
+            // The TextEdit only sees the part of a selection inside its
+            // window, so it can't be left to edit one reaching past it
+            let inside =
+                text.line_to_char(window.lines.start)..=text.line_to_char(window.lines.end);
+            let load_selection = || {
+                egui::TextEdit::load_state(ctx, editor_id(doc_id))
+                    .and_then(|state| state.cursor.char_range())
+            };
+            let mut selection = load_selection();
+            if let Some(range) = selection.filter(|_| focused) {
+                let [start, end] = range.sorted();
+                if (!inside.contains(&start.index) || !inside.contains(&end.index))
+                    && edit_selection(ctx, doc_id, (text, history), range)
+                {
+                    *modified = true;
+                    selection = load_selection();
+                }
+            }
+
+            // The layouter can't borrow the buffer while the TextEdit
+            // holds it, so it learns about edits through this
+            let revision = Cell::new(text.revision());
+            let window_bytes =
+                text.line_to_byte(window.lines.start)..text.line_to_byte(window.lines.end);
+            let first_line = window.lines.start;
+            let mut buffer = RecordingBuffer::new(text, window.lines.clone(), &revision);
+            let window_chars = buffer.window();
+            let window_start = window_chars.start;
+            let margin = egui::Margin::symmetric(4.0, 2.0);
+
+            unsafe {
+                let mut layouter = |ui: &egui::Ui, string: &str, wrap_width: f32| {
+                    let revision = revision.get();
+                    let search_state = (*std::ptr::addr_of!(SEARCH_STATE))
+                        .as_ref()
+                        // Offsets from before this frame's edit would be off
+                        .filter(|s| s.open && s.doc_id == doc_id && s.revision == revision);
+
+                    if let Some(editor_state) = EDITOR_STATE.as_mut() {
+                        let key = GalleyKey {
+                            doc_id,
+                            revision,
+                            lines: window.lines.clone(),
+                            highlights: editor_state.highlighter.generation(doc_id),
+                            search: search_state.map(|s| s.generation),
+                            wrap_width: wrap_width.to_bits(),
+                        };
+                        if let Some((cached_key, galley, _)) = &editor_state.galley_cache {
+                            if *cached_key == key {
+                                return galley.clone();
+                            }
+                        }
+                        let (matches, current_match) = match search_state {
+                            Some(state) => window_matches(
+                                &state.matches,
+                                state.current_match,
+                                window_bytes.clone(),
+                            ),
+                            None => (Vec::new(), None),
+                        };
+                        let font_id = egui::FontId::monospace(
+                            SETTINGS.as_ref().map_or(12.0, |s| s.font_size),
+                        );
+                        let mut layout_job = editor_state.highlighter.with_lines(doc_id, |lines| {
+                            editor_layout_job(
+                                string,
+                                lines.get(first_line..).unwrap_or_default(),
+                                &matches,
+                                current_match,
+                                &font_id,
+                            )
+                        });
+                        layout_job.wrap.max_width = wrap_width;
+                        let galley = ui.fonts(|f| f.layout_job(layout_job));
+                        editor_state.galley_cache =
+                            Some((key, galley.clone(), row_lines(&galley).into()));
+                        return galley;
+                    }
+                    let mut layout_job = egui::text::LayoutJob::default();
+                    layout_job.append(string, 0.0, egui::TextFormat::default());
+                    layout_job.wrap.max_width = wrap_width;
+                    ui.fonts(|f| f.layout_job(layout_job))
+                };
+
+                // Pins the anchor line where the window wants it, which
+                // takes its place in the galley, so lay it out up front.
+                // The TextEdit gets the same galley from the cache
+                let top = if windowed {
+                    let wrap_width = editor_width - margin.sum().x;
+                    let galley = layouter(ui, egui::TextBuffer::as_str(&buffer), wrap_width);
+                    let (line_top, line_height) =
+                        line_extent(&galley, window.anchor_line - first_line);
+                    origin.y + window.anchor
+                        - line_top
+                        - window.anchor_fraction * line_height
+                        - margin.top
+                } else {
+                    origin.y
+                };
+
+                // The TextEdit works in window offsets, everything else in
+                // document offsets
+                let to_window = |cursor: egui::text::CCursor| egui::text::CCursor {
+                    index: cursor.index.clamp(window_chars.start, window_chars.end)
+                        - window_chars.start,
+                    ..cursor
+                };
+                let window_selection = selection.map(|range| egui::text::CCursorRange {
+                    primary: to_window(range.primary),
+                    secondary: to_window(range.secondary),
+                });
+                if let Some(range) = window_selection {
+                    store_selection(ctx, doc_id, range);
+                }
+
+                let text_edit = egui::TextEdit::multiline(&mut buffer)
+                    .id(editor_id(doc_id))
+                    .frame(false)
+                    .margin(margin)
+                    .desired_width(editor_width)
+                    .min_size(egui::vec2(editor_width, available_height))
+                    .font(egui::TextStyle::Monospace)
+                    .lock_focus(true);
+                let editor_rect = egui::Rect::from_min_size(
+                    egui::pos2(editor_left, top),
+                    egui::vec2(editor_width, available_height),
+                );
+                // Not allocated: a window's galley says nothing about how
+                // tall the document is
+                let mut editor_ui = ui.new_child(egui::UiBuilder::new().max_rect(editor_rect));
+                let output = text_edit.layouter(&mut layouter).show(&mut editor_ui);
+                let content_height = if windowed {
+                    total_lines as f32 * row_height
+                } else {
+                    editor_ui.min_rect().bottom() - origin.y
+                };
+                ui.set_min_size(egui::vec2(available_width, content_height));
+
+                let edited = !buffer.edits.is_empty();
+                let typed = buffer
+                    .edits
+                    .last()
+                    .filter(|edit| edit.removed.is_empty())
+                    .map(|edit| edit.inserted.clone());
+                history.record_typing(buffer.edits, selection.map(|range| range.primary.index));
+                if REVEAL_CURSOR == Some(doc_id) {
+                    REVEAL_CURSOR = None;
+                    if let Some(range) = &output.cursor_range {
+                        let rect = output
+                            .galley
+                            .pos_from_cursor(&range.primary)
+                            .translate(output.galley_pos.to_vec2());
+                        ui.scroll_to_rect(rect, Some(egui::Align::Center));
+                    }
+                    output.response.request_focus();
+                }
+                if let Some(editor_state) = EDITOR_STATE.as_ref() {
+                    editor_state.highlighter.update(doc_id, text, &syntax);
+                }
+                let response = output.response;
+                if response.changed() {
+                    *modified = true;
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona | MODIFIED".into()));
+                }
+
+                // Ends the TextEdit left alone keep their document offset,
+                // which may lie outside the window
+                let to_document = |after: egui::text::CCursor,
+                                   before: Option<(egui::text::CCursor, egui::text::CCursor)>| {
+                    match before {
+                        Some((window, document)) if !edited && after.index == window.index => {
+                            document
+                        }
+                        _ => egui::text::CCursor {
+                            index: after.index + window_start,
+                            ..after
+                        },
+                    }
+                };
+                let before = selection.zip(window_selection);
+                *cursor = output.cursor_range.map(|range| {
+                    let range = range.as_ccursor_range();
+                    egui::text::CCursorRange {
+                        primary: to_document(
+                            range.primary,
+                            before.map(|(doc, window)| (window.primary, doc.primary)),
+                        ),
+                        secondary: to_document(
+                            range.secondary,
+                            before.map(|(doc, window)| (window.secondary, doc.secondary)),
+                        ),
+                    }
+                });
+                if let Some(range) = *cursor {
+                    store_selection(ctx, doc_id, range);
+                }
+                let cursor_index = cursor.map_or(0, |range| range.primary.index);
+
+                // Typing a trigger character like `.` opens the completion list
+                if let Some(typed) = typed.filter(|_| response.changed()) {
+                    let before = text.char_slice(cursor_index.saturating_sub(3)..cursor_index);
+                    if trigger_characters.iter().any(|trigger| {
+                        before.ends_with(trigger.as_str()) && typed.ends_with(trigger.as_str())
+                    }) {
+                        lsp_request = Some(LspRequest::Completion(cursor_index));
+                    }
+                }
+                if response.clicked() && ui.input(|i| i.modifiers.command) {
+                    lsp_request = Some(LspRequest::Definition(cursor_index));
+                }
+
+                let window_chars =
+                    window_start..window_start + output.galley.text().chars().count();
+                paint_diagnostics(
+                    ui,
+                    &output.galley,
+                    output.galley_pos,
+                    text,
+                    window_chars,
+                    &diagnostics,
+                    &font_id,
+                );
+                if let Some(pointer) = response.hover_pos() {
+                    let at = output
+                        .galley
+                        .cursor_from_pos(pointer - output.galley_pos)
+                        .ccursor
+                        .index
+                        + window_start;
+                    if let Some(request) = show_hover(ui, doc_id, text, &diagnostics, at) {
+                        lsp_request = Some(request);
+                    }
+                }
+                let completion = &mut *std::ptr::addr_of_mut!(COMPLETION);
+                if let Some(popup) = completion.as_mut().filter(|p| p.doc_id == doc_id) {
+                    let anchor = output
+                        .galley
+                        .pos_from_ccursor(egui::text::CCursor::new(
+                            cursor_index.saturating_sub(window_start),
+                        ))
+                        .left_bottom()
+                        + output.galley_pos.to_vec2();
+                    let items = popup.matching(text, cursor_index);
+                    let picked = show_completion_popup(ctx, &items, popup.selected, anchor)
+                        .map(|idx| items[idx].insert.clone());
+                    if let Some(insert) = picked {
+                        accept_completion(
+                            ctx,
+                            doc_id,
+                            (text, history),
+                            popup.at..cursor_index,
+                            &insert,
+                        );
+                        *modified = true;
+                        *completion = None;
+                    }
+                }
+
+                let mut marks = BTreeMap::new();
+                for diagnostic in &diagnostics {
+                    if diagnostic.severity == Severity::Hint {
+                        continue;
+                    }
+                    let worst = marks
+                        .entry(diagnostic.start.line as usize)
+                        .or_insert(diagnostic.severity);
+                    *worst = (*worst).min(diagnostic.severity);
+                }
+                let marks: BTreeMap<usize, egui::Color32> = marks
+                    .into_iter()
+                    .map(|(line, severity)| (line, severity_color(ui.visuals(), severity)))
+                    .collect();
+
+                let cursor_line = cursor.map(|range| {
+                    text.rope()
+                        .char_to_line(range.primary.index.min(text.len_chars()))
+                });
+                let row_lines = (*std::ptr::addr_of!(EDITOR_STATE))
+                    .as_ref()
+                    .and_then(|state| state.galley_cache.as_ref())
+                    .filter(|(_, galley, _)| Arc::ptr_eq(galley, &output.galley))
+                    .map_or_else(
+                        || row_lines(&output.galley).into(),
+                        |(_, _, lines)| lines.clone(),
+                    );
+                paint_gutter(
+                    ui,
+                    &Gutter {
+                        rect: gutter_rect,
+                        editor: response.rect,
+                        background,
+                        first_line,
+                        row_lines: &row_lines,
+                    },
+                    &output.galley,
+                    output.galley_pos,
+                    cursor_line,
+                    &marks,
+                    &font_id,
+                );
+            }
+
+            let status = StatusInfo::new(
+                text,
+                *cursor,
+                &syntax,
+                (indent_settings, indent_source),
+                *encoding,
+                (*line_ending, *mixed_line_endings),
+            );
+            if let Some(picked) = show_bottom_status_bar(ctx, &status, &filename) {
+                // The text is the same, but the file on disk no longer matches
+                *line_ending = picked;
+                *modified = true;
+                ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona | MODIFIED".into()));
+            }
+        });
+        *scroll_offset = scroll_output.state.offset;
+    });
+
+    match lsp_request {
+        Some(LspRequest::Completion(cursor)) => lsp_client().completion(doc, cursor, cursor),
+        Some(LspRequest::Hover(at)) => lsp_client().hover(doc, at),
+        Some(LspRequest::Definition(at)) => lsp_client().definition(doc, at),
+        None => {}
+    }
+}
+
+/// Space left of the line numbers, for git change and diagnostic markers.
+const GUTTER_MARKS_WIDTH: f32 = 14.0;
+/// Space right of the line numbers, for fold markers.
+const GUTTER_FOLDS_WIDTH: f32 = 12.0;
+
+fn gutter_width(ui: &egui::Ui, font_id: &egui::FontId, total_lines: usize) -> f32 {
+    let digits = total_lines.to_string().len().max(3) as f32;
+    let digit_width = ui.fonts(|f| f.glyph_width(font_id, '0'));
+    GUTTER_MARKS_WIDTH + digits * digit_width + GUTTER_FOLDS_WIDTH
+}
+
+/// Lines of a long document handed to the editor for one scroll offset. The
+/// scroll area is sized as if every line took one row. The anchor is the
+/// point of the text pinned to the viewport: it moves from the viewport's
+/// top to its bottom as the offset goes from start to end, so both ends of
+/// the document can be reached however its lines wrap.
+struct EditorWindow {
+    lines: std::ops::Range<usize>,
+    /// Line at the anchor, and how far into it the anchor is.
+    anchor_line: usize,
+    anchor_fraction: f32,
+    /// Where the anchor goes in the scroll area's content.
+    anchor: f32,
+}
+
+impl EditorWindow {
+    /// Pages laid out on either side of the anchor.
+    const MARGIN_PAGES: usize = 2;
+
+    fn new(offset: f32, viewport_height: f32, row_height: f32, total_lines: usize) -> Self {
+        let max_offset = (total_lines as f32 * row_height - viewport_height).max(0.0);
+        let offset = offset.clamp(0.0, max_offset);
+        let anchor = if max_offset > 0.0 {
+            offset + viewport_height * offset / max_offset
+        } else {
+            offset
+        };
+        let anchor_line = ((anchor / row_height) as usize).min(total_lines);
+        let margin = Self::MARGIN_PAGES * page_lines(viewport_height, row_height);
+        Self {
+            lines: anchor_line.saturating_sub(margin)..(anchor_line + margin).min(total_lines),
+            anchor_line,
+            anchor_fraction: (anchor / row_height - anchor_line as f32).clamp(0.0, 1.0),
+            anchor,
+        }
+    }
+
+    /// The whole document, for one short enough to lay out in full.
+    fn whole(total_lines: usize) -> Self {
+        Self {
+            lines: 0..total_lines,
+            anchor_line: 0,
+            anchor_fraction: 0.0,
+            anchor: 0.0,
+        }
+    }
+
+    /// Scroll offset that puts the middle of `line` at the anchor.
+    fn offset_for(line: usize, viewport_height: f32, row_height: f32, total_lines: usize) -> f32 {
+        let content = total_lines as f32 * row_height;
+        let max_offset = (content - viewport_height).max(0.0);
+        ((line as f32 + 0.5) * row_height * max_offset / content).clamp(0.0, max_offset)
+    }
+}
+
+fn page_lines(viewport_height: f32, row_height: f32) -> usize {
+    (viewport_height / row_height).ceil().max(1.0) as usize
+}
+
+/// Top and height of the `line`th line of a galley. Past its last line, the
+/// galley's bottom.
+fn line_extent(galley: &egui::Galley, line: usize) -> (f32, f32) {
+    let mut current = 0;
+    let mut extent: Option<(f32, f32)> = None;
+    for row in &galley.rows {
+        if current == line {
+            let top = extent.map_or(row.rect.top(), |(top, _)| top);
+            extent = Some((top, row.rect.bottom()));
+        } else if current > line {
+            break;
+        }
+        if row.ends_with_newline {
+            current += 1;
+        }
+    }
+    extent.map_or((galley.rect.bottom(), 0.0), |(top, bottom)| {
+        (top, bottom - top)
+    })
+}
+
+/// Search matches in `window` (bytes), as offsets into it, and where the
+/// current match is among them.
+fn window_matches(
+    matches: &[(usize, usize)],
+    current: usize,
+    window: std::ops::Range<usize>,
+) -> (Vec<(usize, usize)>, Option<usize>) {
+    let first = matches.partition_point(|&(_, end)| end <= window.start);
+    let last = matches
+        .partition_point(|&(start, _)| start < window.end)
+        .max(first);
+    let inside = matches[first..last]
+        .iter()
+        .map(|&(start, end)| {
+            (
+                start.max(window.start) - window.start,
+                end.min(window.end) - window.start,
+            )
+        })
+        .collect();
+    (
+        inside,
+        current.checked_sub(first).filter(|&i| i < last - first),
+    )
+}
+
+/// Input the TextEdit acts on at the cursor.
+fn edits_at_cursor(event: &egui::Event) -> bool {
+    use egui::Key;
+    match event {
+        egui::Event::Text(_) | egui::Event::Paste(_) | egui::Event::Cut => true,
+        egui::Event::Key {
+            key, pressed: true, ..
+        } => matches!(
+            key,
+            Key::ArrowLeft
+                | Key::ArrowRight
+                | Key::ArrowUp
+                | Key::ArrowDown
+                | Key::Home
+                | Key::End
+                | Key::Backspace
+                | Key::Delete
+        ),
+        _ => false,
+    }
+}
+
+/// Select all and the jumps to the start or end of the text, which the
+/// TextEdit would stop at the ends of its window. The Shift variants go
+/// first as the plain ones match them too.
+fn jump_across_window(ctx: &egui::Context, doc_id: u64, len_chars: usize) {
+    use egui::{Key, Modifiers};
+    let (select_all, select_to_start, select_to_end, to_start, to_end) = ctx.input_mut(|i| {
+        (
+            i.consume_key(Modifiers::COMMAND, Key::A),
+            i.consume_key(Modifiers::CTRL | Modifiers::SHIFT, Key::Home)
+                || i.consume_key(Modifiers::COMMAND | Modifiers::SHIFT, Key::ArrowUp),
+            i.consume_key(Modifiers::CTRL | Modifiers::SHIFT, Key::End)
+                || i.consume_key(Modifiers::COMMAND | Modifiers::SHIFT, Key::ArrowDown),
+            i.consume_key(Modifiers::CTRL, Key::Home)
+                || i.consume_key(Modifiers::COMMAND, Key::ArrowUp),
+            i.consume_key(Modifiers::CTRL, Key::End)
+                || i.consume_key(Modifiers::COMMAND, Key::ArrowDown),
+        )
+    });
+    let (start, end) = (
+        egui::text::CCursor::new(0),
+        egui::text::CCursor::new(len_chars),
+    );
+    if select_all {
+        store_selection(
+            ctx,
+            doc_id,
+            egui::text::CCursorRange {
+                primary: end,
+                secondary: start,
+            },
+        );
+        return;
+    }
+    let primary = if to_start || select_to_start {
+        start
+    } else if to_end || select_to_end {
+        end
+    } else {
+        return;
+    };
+    let secondary = if select_to_start || select_to_end {
+        egui::TextEdit::load_state(ctx, editor_id(doc_id))
+            .and_then(|state| state.cursor.char_range())
+            .map_or(primary, |range| range.secondary)
+    } else {
+        primary
+    };
+    set_selection(ctx, doc_id, egui::text::CCursorRange { primary, secondary });
+}
+
+/// Copies, cuts or replaces a selection reaching past the editor's window
+/// the way the TextEdit would if it had the whole text. Returns true if the
+/// text changed.
+fn edit_selection(
+    ctx: &egui::Context,
+    doc_id: u64,
+    (text, history): (&mut Buffer, &mut History),
+    selection: egui::text::CCursorRange,
+) -> bool {
+    let len = text.len_chars();
+    let [start, end] = selection.sorted();
+    let (start, end) = (start.index.min(len), end.index.min(len));
+    let mut copy = false;
+    let mut replacement: Option<String> = None;
+    ctx.input_mut(|i| {
+        i.events.retain(|event| {
+            let inserted = match event {
+                egui::Event::Copy => {
+                    copy = true;
+                    return false;
+                }
+                egui::Event::Cut => {
+                    copy = true;
+                    String::new()
+                }
+                // Enter is taken for indentation before the editor sees it
+                egui::Event::Text(typed) if typed != "\n" && typed != "\r" => typed.clone(),
+                egui::Event::Paste(pasted) => line_ending::normalize(pasted).into_owned(),
+                egui::Event::Key {
+                    key: egui::Key::Backspace | egui::Key::Delete,
+                    pressed: true,
+                    ..
+                } => String::new(),
+                _ => return true,
+            };
+            replacement.get_or_insert(inserted);
+            false
+        })
+    });
+    if copy {
+        ctx.copy_text(text.char_slice(start..end).into_owned());
+    }
+    let Some(inserted) = replacement else {
+        return false;
+    };
+    let edit = Edit {
+        at: start,
+        removed: text.char_slice(start..end).into_owned(),
+        inserted,
+    };
+    let cursor = edit.apply(text);
+    history.record_step(vec![edit]);
+    set_cursor(ctx, doc_id, cursor);
+    ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona | MODIFIED".into()));
+    true
+}
+
+/// Where the gutter goes, next to which editor.
+struct Gutter<'a> {
+    rect: egui::Rect,
+    editor: egui::Rect,
+    /// Shape slot reserved before the editor was drawn, so what goes in it
+    /// ends up under the text.
+    background: egui::layers::ShapeIdx,
+    /// Document line the editor's galley starts at.
+    first_line: usize,
+    /// `row_lines` of the galley.
+    row_lines: &'a [usize],
+}
+
+/// Line of each galley row, counted from the galley's first line. Worked
+/// out once per layout rather than every frame.
+fn row_lines(galley: &egui::Galley) -> Vec<usize> {
+    let mut line = 0;
+    galley
+        .rows
+        .iter()
+        .map(|row| {
+            let row_line = line;
+            if row.ends_with_newline {
+                line += 1;
+            }
+            row_line
+        })
+        .collect()
+}
+
+/// Numbers the galley rows that start a line, so wrapped lines and any font
+/// size line up, marks lines with diagnostics, and highlights the cursor's
+/// line. Only rows in view are painted.
+fn paint_gutter(
+    ui: &egui::Ui,
+    gutter: &Gutter,
+    galley: &egui::Galley,
+    galley_pos: egui::Pos2,
+    cursor_line: Option<usize>,
+    marks: &BTreeMap<usize, egui::Color32>,
+    font_id: &egui::FontId,
+) {
+    let painter = ui.painter();
+    let clip = ui.clip_rect();
+    let visuals = ui.visuals();
+    let numbers_right = gutter.rect.right() - GUTTER_FOLDS_WIDTH;
+
+    // Rows are sorted top to bottom, so the first one in view can be
+    // searched for
+    let first = galley
+        .rows
+        .partition_point(|row| row.rect.max.y + galley_pos.y < clip.min.y);
+    let mut line = gutter.first_line + gutter.row_lines.get(first).copied().unwrap_or_default();
+    let mut starts_line = galley.rows[..first]
+        .last()
+        .is_none_or(|row| row.ends_with_newline);
+    let mut current: Option<egui::Rect> = None;
+    for row in &galley.rows[first..] {
+        let rect = row.rect.translate(galley_pos.to_vec2());
+        if rect.min.y > clip.max.y {
+            break;
+        }
+        if Some(line) == cursor_line {
+            current = Some(current.map_or(rect, |r| r.union(rect)));
+        }
+        if starts_line {
+            if let Some(&color) = marks.get(&line) {
+                let center = egui::pos2(
+                    gutter.rect.left() + GUTTER_MARKS_WIDTH / 2.0,
+                    rect.min.y + font_id.size / 2.0 + 1.0,
+                );
+                painter.circle_filled(center, 3.5, color);
+            }
+            let color = if Some(line) == cursor_line {
+                visuals.strong_text_color()
+            } else {
+                visuals.weak_text_color()
+            };
+            painter.text(
+                egui::pos2(numbers_right, rect.min.y),
+                egui::Align2::RIGHT_TOP,
+                (line + 1).to_string(),
+                font_id.clone(),
+                color,
+            );
+        }
+        starts_line = row.ends_with_newline;
+        if starts_line {
+            line += 1;
+        }
+    }
+
+    let full = gutter.rect.union(gutter.editor);
+    let mut shapes = vec![egui::Shape::rect_filled(
+        full,
+        0.0,
+        visuals.extreme_bg_color,
+    )];
+    if let Some(current) = current {
+        shapes.push(egui::Shape::rect_filled(
+            egui::Rect::from_x_y_ranges(full.x_range(), current.y_range()),
+            0.0,
+            visuals.faint_bg_color,
+        ));
+    }
+    painter.set(gutter.background, egui::Shape::Vec(shapes));
+}
+
+fn lsp_client() -> &'static mut LspClient {
+    unsafe { (*std::ptr::addr_of_mut!(LSP)).get_or_insert_with(LspClient::default) }
+}
+
+/// Keeps the language servers in step with the open documents.
+pub fn update_language_servers(ctx: &egui::Context, tabs: &Tabs) {
+    let root = project_root();
+    lsp_client().update(
+        ctx,
+        tabs,
+        &current_settings().language_servers,
+        root.as_deref(),
+    );
+}
+
+pub fn shutdown_language_servers() {
+    lsp_client().shutdown();
+}
+
+/// Completions for the word starting at `at`, filtered as the user types.
+struct CompletionPopup {
+    doc_id: u64,
+    at: usize,
+    items: Vec<lsp::CompletionItem>,
+    selected: usize,
+}
+
+impl CompletionPopup {
+    /// Items matching what has been typed since `at`.
+    fn matching(&self, text: &Buffer, cursor: usize) -> Vec<&lsp::CompletionItem> {
+        let prefix = text.char_slice(self.at..cursor).to_lowercase();
+        self.items
+            .iter()
+            .filter(|item| item.filter.to_lowercase().starts_with(&prefix))
+            .collect()
+    }
+}
+
+/// Hover info asked for at a char offset; `text` is `None` until the server
+/// answers.
+struct HoverState {
+    doc_id: u64,
+    at: usize,
+    text: Option<String>,
+}
+
+struct RenamePrompt {
+    doc_id: u64,
+    at: usize,
+    name: String,
+    focus: bool,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Char range of the word around `at`.
+fn word_at(text: &Buffer, at: usize) -> std::ops::Range<usize> {
+    let rope = text.rope();
+    let at = at.min(rope.len_chars());
+    let mut start = at;
+    while start > 0 && is_word_char(rope.char(start - 1)) {
+        start -= 1;
+    }
+    let mut end = at;
+    while end < rope.len_chars() && is_word_char(rope.char(end)) {
+        end += 1;
+    }
+    start..end
+}
+
+/// Acts on what the language servers answered since the last frame.
+fn handle_lsp_replies(ctx: &egui::Context, tabs: &mut Tabs, current_view: &mut ViewType) {
+    for reply in lsp_client().take_replies() {
+        match reply {
+            Reply::Completion { doc_id, at, items } => unsafe {
+                *std::ptr::addr_of_mut!(COMPLETION) =
+                    (!items.is_empty()).then_some(CompletionPopup {
+                        doc_id,
+                        at,
+                        items,
+                        selected: 0,
+                    });
+            },
+            Reply::Hover { doc_id, at, text } => {
+                let hover = unsafe { &mut *std::ptr::addr_of_mut!(HOVER) };
+                if let Some(hover) = hover.as_mut().filter(|h| h.doc_id == doc_id && h.at == at) {
+                    hover.text = Some(text);
+                }
+            }
+            Reply::Definition(locations) => {
+                let Some(location) = locations.first() else {
+                    println!("No definition found");
+                    continue;
+                };
+                if open_path(ctx, tabs, current_view, &location.path) {
+                    if let Some(doc) = tabs.active() {
+                        set_cursor(ctx, doc.id, location.position.to_char(doc.text.rope()));
+                    }
+                }
+            }
+            Reply::Rename(files) => {
+                let active = tabs.active;
+                // The server may name a file differently than it was opened,
+                // through a symlink or a relative path
+                let canonical = |path: &std::path::Path| {
+                    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+                };
+                for (path, edits) in files {
+                    let target = canonical(&path);
+                    let idx = match tabs
+                        .documents
+                        .iter()
+                        .position(|d| d.path.as_deref().is_some_and(|p| canonical(p) == target))
+                    {
+                        Some(idx) => idx,
+                        None => match tabs.open(&path) {
+                            Ok(()) => tabs.active,
+                            Err(e) => {
+                                println!("Error opening {}: {}", path.display(), e);
+                                continue;
+                            }
+                        },
+                    };
+                    let doc = &mut tabs.documents[idx];
+                    let edits = lsp::buffer_edits(&doc.text, &edits);
+                    if !edits.is_empty() {
+                        doc.edit(edits);
+                    }
+                }
+                // Files opened for the rename stay open, but the one the
+                // rename started from stays in front
+                tabs.active = active;
+                ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona | MODIFIED".into()));
+            }
+            Reply::Failed(message) => println!("Language server error: {}", message),
+        }
+    }
+}
+
+/// Language server requests made while the document is borrowed for
+/// drawing, sent once it isn't.
+enum LspRequest {
+    Completion(usize),
+    Hover(usize),
+    Definition(usize),
+}
+
+/// Replaces `range` with a picked completion as one undo step.
+fn accept_completion(
+    ctx: &egui::Context,
+    doc_id: u64,
+    (text, history): (&mut Buffer, &mut History),
+    range: std::ops::Range<usize>,
+    insert: &str,
+) {
+    let edit = Edit {
+        at: range.start,
+        removed: text.char_slice(range).to_string(),
+        inserted: insert.to_string(),
+    };
+    let cursor_after = edit.apply(text);
+    history.record_step(vec![edit]);
+    set_cursor(ctx, doc_id, cursor_after);
+    ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona | MODIFIED".into()));
+}
+
+/// Tooltip for the text under a resting pointer: diagnostics there, and
+/// what the language server says about the word. Returns a hover request
+/// when the word is new.
+fn show_hover(
+    ui: &egui::Ui,
+    doc_id: u64,
+    text: &Buffer,
+    diagnostics: &[Diagnostic],
+    at: usize,
+) -> Option<LspRequest> {
+    const HOVER_DELAY: f32 = 0.4;
+    let resting = ui.input(|i| i.pointer.time_since_last_movement());
+    if resting < HOVER_DELAY {
+        ui.ctx()
+            .request_repaint_after(std::time::Duration::from_secs_f32(HOVER_DELAY - resting));
+        return None;
+    }
+
+    let word = word_at(text, at);
+    let mut request = None;
+    let hover = unsafe { &mut *std::ptr::addr_of_mut!(HOVER) };
+    if !word.is_empty()
+        && !hover
+            .as_ref()
+            .is_some_and(|h| h.doc_id == doc_id && h.at == word.start)
+    {
+        *hover = Some(HoverState {
+            doc_id,
+            at: word.start,
+            text: None,
+        });
+        request = Some(LspRequest::Hover(word.start));
+    }
+    let info = hover
+        .as_ref()
+        .filter(|h| h.doc_id == doc_id && h.at == word.start && !word.is_empty())
+        .and_then(|h| h.text.as_deref())
+        .map(str::trim)
+        .filter(|text| !text.is_empty());
+
+    let here: Vec<&Diagnostic> = diagnostics
+        .iter()
+        .filter(|d| {
+            let range = d.char_range(text.rope());
+            range.contains(&at) || (range.is_empty() && range.start == at)
+        })
+        .collect();
+    if here.is_empty() && info.is_none() {
+        return request;
+    }
+    egui::show_tooltip_at_pointer(ui.ctx(), ui.layer_id(), egui::Id::new("lsp_hover"), |ui| {
+        ui.set_max_width(500.0);
+        for diagnostic in here {
+            ui.colored_label(
+                severity_color(ui.visuals(), diagnostic.severity),
+                &diagnostic.message,
+            );
+        }
+        if let Some(info) = info {
+            ui.label(egui::RichText::new(info).monospace());
+        }
+    });
+    request
+}
+
+/// Wavy underline from `from` to `to_x`.
+fn paint_squiggle(painter: &egui::Painter, from: egui::Pos2, to_x: f32, color: egui::Color32) {
+    let mut points = Vec::new();
+    let mut x = from.x;
+    let mut up = false;
+    while x < to_x {
+        points.push(egui::pos2(x, from.y - if up { 1.5 } else { 0.0 }));
+        x += 2.0;
+        up = !up;
+    }
+    points.push(egui::pos2(to_x, from.y));
+    painter.add(egui::Shape::line(points, egui::Stroke::new(1.0, color)));
+}
+
+fn severity_color(visuals: &egui::Visuals, severity: Severity) -> egui::Color32 {
+    match severity {
+        Severity::Error => visuals.error_fg_color,
+        Severity::Warning => visuals.warn_fg_color,
+        Severity::Information => visuals.hyperlink_color,
+        Severity::Hint => visuals.weak_text_color(),
+    }
+}
+
+/// Underlines each diagnostic's span in the editor, a row at a time.
+fn paint_diagnostics(
+    ui: &egui::Ui,
+    galley: &egui::Galley,
+    galley_pos: egui::Pos2,
+    text: &Buffer,
+    window: std::ops::Range<usize>,
+    diagnostics: &[Diagnostic],
+    font_id: &egui::FontId,
+) {
+    let painter = ui.painter();
+    let clip = ui.clip_rect();
+    let min_width = ui.fonts(|f| f.glyph_width(font_id, 'x'));
+    for diagnostic in diagnostics {
+        let range = diagnostic.char_range(text.rope());
+        if range.end < window.start || range.start > window.end {
+            continue;
+        }
+        let in_window = |index: usize| index.clamp(window.start, window.end) - window.start;
+        let start = galley.from_ccursor(egui::text::CCursor::new(in_window(range.start)));
+        let end = galley.from_ccursor(egui::text::CCursor::new(in_window(range.end)));
+        let color = severity_color(ui.visuals(), diagnostic.severity);
+        for row in start.rcursor.row..=end.rcursor.row.min(galley.rows.len() - 1) {
+            let rect = galley.rows[row].rect.translate(galley_pos.to_vec2());
+            if rect.max.y < clip.min.y || rect.min.y > clip.max.y {
+                continue;
+            }
+            let x0 = if row == start.rcursor.row {
+                galley.pos_from_cursor(&start).min.x + galley_pos.x
+            } else {
+                rect.min.x
+            };
+            let mut x1 = if row == end.rcursor.row {
+                galley.pos_from_cursor(&end).min.x + galley_pos.x
+            } else {
+                rect.max.x
+            };
+            // Empty spans (like a missing semicolon) still get a mark
+            if x1 - x0 < min_width {
+                x1 = x0 + min_width;
+            }
+            paint_squiggle(painter, egui::pos2(x0, rect.max.y - 1.0), x1, color);
+        }
+    }
+}
+
+/// The completion list under the cursor. Returns the item picked with the
+/// mouse.
+fn show_completion_popup(
+    ctx: &egui::Context,
+    items: &[&lsp::CompletionItem],
+    selected: usize,
+    anchor: egui::Pos2,
+) -> Option<usize> {
+    let mut picked = None;
+    egui::Area::new(egui::Id::new("completion_popup"))
+        .order(egui::Order::Foreground)
+        .fixed_pos(anchor)
+        .show(ctx, |ui| {
+            egui::Frame::popup(ui.style()).show(ui, |ui| {
+                ui.set_max_width(400.0);
+                egui::ScrollArea::vertical()
+                    .max_height(220.0)
+                    .show(ui, |ui| {
+                        for (idx, item) in items.iter().enumerate() {
+                            let selected = idx == selected;
+                            let response = ui.horizontal(|ui| {
+                                let response = ui.selectable_label(selected, &item.label);
+                                if let Some(detail) = &item.detail {
+                                    ui.weak(detail);
+                                }
+                                response
+                            });
+                            if selected {
+                                response.response.scroll_to_me(None);
+                            }
+                            if response.inner.clicked() {
+                                picked = Some(idx);
+                            }
+                        }
+                    });
+            });
+        });
+    picked
+}
+
+/// F2's prompt for the new name of the symbol under the cursor.
+fn show_rename_prompt(ctx: &egui::Context, tabs: &Tabs) {
+    let prompt_slot = unsafe { &mut *std::ptr::addr_of_mut!(RENAME_PROMPT) };
+    let Some(prompt) = prompt_slot.as_mut() else {
+        return;
+    };
+    let mut submit = false;
+    let mut cancel = false;
+    egui::Window::new("Rename Symbol")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_TOP, [0.0, 40.0])
+        .show(ctx, |ui| {
+            let response = ui.text_edit_singleline(&mut prompt.name);
+            if prompt.focus {
+                response.request_focus();
+                prompt.focus = false;
+            }
+            submit = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            cancel = ui.input(|i| i.key_pressed(egui::Key::Escape));
+            ui.add_space(8.0);
+            ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                cancel |= ui.button("Cancel").clicked();
+                submit |= ui.button("Rename").clicked();
+            });
+        });
+    if submit && !prompt.name.trim().is_empty() {
+        if let Some(idx) = tabs.index_of(prompt.doc_id) {
+            lsp_client().rename(&tabs.documents[idx], prompt.at, prompt.name.trim());
+        }
+        *prompt_slot = None;
+    } else if cancel {
+        *prompt_slot = None;
+    }
+}
+
+/// Ctrl+G and Ctrl+Shift+O prompts for the active document.
+fn show_goto_windows(ctx: &egui::Context, doc_id: u64, text: &Buffer, tab_width: usize) {
+    let mut jump_to = None;
+    unsafe {
+        if GOTO_LINE_OPEN {
+            let mut open = true;
+            egui::Window::new("Go to Line")
+                .open(&mut open)
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_TOP, [0.0, 40.0])
+                .show(ctx, |ui| {
+                    ui.label(format!("Line[:column], 1 to {}", text.len_lines()));
+                    let input = &mut *std::ptr::addr_of_mut!(GOTO_LINE_INPUT);
+                    let response = ui.text_edit_singleline(input);
+                    response.request_focus();
+                    if ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                        let mut parts = input.trim().splitn(2, ':');
+                        let line = parts.next().and_then(|l| l.trim().parse::<usize>().ok());
+                        let column = parts.next().and_then(|c| c.trim().parse::<usize>().ok());
+                        if let Some(line) = line {
+                            jump_to = Some(char_at(text, line, column.unwrap_or(1), tab_width));
+                            GOTO_LINE_OPEN = false;
+                        }
+                    }
+                    if ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                        GOTO_LINE_OPEN = false;
+                    }
+                });
+            GOTO_LINE_OPEN &= open;
+        }
+
+        let picker = &mut *std::ptr::addr_of_mut!(SYMBOL_PICKER);
+        if let Some(state) = picker.as_mut() {
+            let mut open = true;
+            let mut close = false;
+            egui::Window::new("Go to Symbol")
+                .open(&mut open)
+                .collapsible(false)
+                .default_size([360.0, 320.0])
+                .anchor(egui::Align2::CENTER_TOP, [0.0, 40.0])
+                .show(ctx, |ui| {
+                    let response = ui.text_edit_singleline(&mut state.filter);
+                    response.request_focus();
+                    if response.changed() {
+                        state.selected = 0;
+                    }
+                    let (up, down, enter, escape) = ui.input(|i| {
+                        (
+                            i.key_pressed(egui::Key::ArrowUp),
+                            i.key_pressed(egui::Key::ArrowDown),
+                            i.key_pressed(egui::Key::Enter),
+                            i.key_pressed(egui::Key::Escape),
+                        )
+                    });
+                    let mut selected = state.selected;
+                    let visible = state.visible();
+                    if down && selected + 1 < visible.len() {
+                        selected += 1;
+                    }
+                    if up {
+                        selected = selected.saturating_sub(1);
+                    }
+                    if enter {
+                        if let Some(symbol) = visible.get(selected) {
+                            jump_to = Some(symbol.char_index);
+                        }
+                        close = true;
+                    }
+                    close |= escape;
+
+                    ui.separator();
+                    if visible.is_empty() {
+                        ui.label("No symbols");
+                    }
+                    egui::ScrollArea::vertical().show(ui, |ui| {
+                        for (i, symbol) in visible.iter().enumerate() {
+                            let label =
+                                format!("{:<8} {}  :{}", symbol.kind, symbol.name, symbol.line + 1);
+                            let response = ui.selectable_label(
+                                i == selected,
+                                egui::RichText::new(label).monospace(),
+                            );
+                            if i == selected && (up || down) {
+                                response.scroll_to_me(None);
+                            }
+                            if response.clicked() {
+                                jump_to = Some(symbol.char_index);
+                                close = true;
+                            }
+                        }
+                    });
+                    state.selected = selected;
+                });
+            if close || !open {
+                *picker = None;
+            }
+        }
+    }
+    if let Some(at) = jump_to {
+        set_cursor(ctx, doc_id, at);
+    }
+}
+
+/// Tab width and tabs-vs-spaces controls, on one row.
+fn indent_settings_ui(ui: &mut egui::Ui, indent: &mut IndentSettings) -> bool {
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        ui.label("Tab width:");
+        changed |= ui
+            .add(egui::DragValue::new(&mut indent.tab_width).range(1..=16))
+            .changed();
+        changed |= ui
+            .checkbox(&mut indent.use_tabs, "Indent with tabs")
+            .changed();
+    });
+    changed
+}
+
+/// What the status bar shows about the active document.
+struct StatusInfo {
+    /// One-based, like every editor shows them.
+    line: usize,
+    column: usize,
+    selected_chars: usize,
+    selected_lines: usize,
+    total_lines: usize,
+    line_ending: LineEnding,
+    mixed_line_endings: bool,
+    encoding: String,
+    /// E.g. "Spaces: 4 (.editorconfig)".
+    indent: String,
+    language: String,
+}
+
+impl StatusInfo {
+    fn new(
+        text: &Buffer,
+        cursor: Option<egui::text::CCursorRange>,
+        language: &str,
+        (indent, indent_source): (IndentSettings, Option<&str>),
+        encoding: TextEncoding,
+        (line_ending, mixed_line_endings): (LineEnding, bool),
+    ) -> Self {
+        let tab_width = indent.tab_width;
+        let rope = text.rope();
+        let (primary, secondary) =
+            cursor.map_or((0, 0), |range| (range.primary.index, range.secondary.index));
+        let primary = primary.min(rope.len_chars());
+        let secondary = secondary.min(rope.len_chars());
+
+        let line = rope.char_to_line(primary);
+        // Tabs count up to the next tab stop, so the column matches what is on screen
+        let column = indent::visual_column(
+            &text.char_slice(rope.line_to_char(line)..primary),
+            tab_width,
+        );
+
+        let (start, end) = (primary.min(secondary), primary.max(secondary));
+        let (selected_chars, selected_lines) = if start == end {
+            (0, 0)
+        } else {
+            (
+                end - start,
+                rope.char_to_line(end) - rope.char_to_line(start) + 1,
+            )
+        };
+
+        Self {
+            line: line + 1,
+            column: column + 1,
+            selected_chars,
+            selected_lines,
+            total_lines: text.len_lines(),
+            line_ending,
+            mixed_line_endings,
+            encoding: encoding.name(),
+            indent: format!(
+                "{}: {}{}",
+                if indent.use_tabs { "Tabs" } else { "Spaces" },
+                indent.tab_width,
+                indent_source.map_or(String::new(), |source| format!(" ({})", source)),
+            ),
+            language: language.to_string(),
+        }
+    }
+
+    fn label(&self) -> String {
+        let selection = match (self.selected_chars, self.selected_lines) {
+            (0, _) => String::new(),
+            (chars, 1) => format!(" ({} selected)", chars),
+            (chars, lines) => format!(" ({} selected, {} lines)", chars, lines),
+        };
+        format!(
+            "Ln {}, Col {}{} | {} lines | {} | {} |",
+            self.line, self.column, selection, self.total_lines, self.indent, self.encoding
+        )
+    }
+}
+
+/// The status bar's contents, right-aligned. Returns the line ending picked
+/// from its dropdown, if one was.
+fn show_status(ui: &mut egui::Ui, status: &StatusInfo) -> Option<LineEnding> {
+    let mut picked = None;
+    ui.with_layout(egui::Layout::right_to_left(egui::Align::BOTTOM), |ui| {
+        ui.add_space(5.0);
+        ui.label(&status.language);
+        ui.label("|");
+        let mut selected = status.line_ending;
+        egui::ComboBox::from_id_salt("line_ending")
+            .selected_text(selected.name())
+            .width(60.0)
+            .show_ui(ui, |ui| {
+                for ending in LineEnding::ALL {
+                    ui.selectable_value(&mut selected, ending, ending.name());
+                }
+            });
+        if selected != status.line_ending {
+            picked = Some(selected);
+        }
+        if status.mixed_line_endings {
+            ui.colored_label(ui.visuals().warn_fg_color, "⚠ Mixed")
+                .on_hover_text(format!(
+                    "This file mixes line endings. Saving writes them all as {}.",
+                    status.line_ending.name()
+                ));
+        }
+        ui.label(status.label());
+        job_status_ui(ui);
+        status_error_ui(ui);
+    });
+    picked
+}
+
+fn status_error_ui(ui: &mut egui::Ui) {
+    let error = unsafe { &mut *std::ptr::addr_of_mut!(STATUS_ERROR) };
+    let Some(message) = error.as_ref() else {
+        return;
+    };
+    ui.label("|");
+    let text = egui::RichText::new(format!("⚠ {}", message)).color(ui.visuals().error_fg_color);
+    let response = ui.add(
+        egui::Label::new(text)
+            .truncate()
+            .sense(egui::Sense::click()),
+    );
+    if response
+        .on_hover_text(format!("{}\n\nClick to dismiss", message))
+        .clicked()
+    {
+        *error = None;
+    }
+}
+
+fn show_bottom_status_bar(
+    ctx: &egui::Context,
+    status: &StatusInfo,
+    filename: &str,
+) -> Option<LineEnding> {
+    let mut picked = None;
+    let terminal = unsafe { &mut *std::ptr::addr_of_mut!(TERMINAL) };
+    if !terminal_open() {
+        // Closing the panel ends whatever ran in it
+        *terminal = None;
+        egui::TopBottomPanel::bottom("bottom_panel")
+            .exact_height(20.0)
+            .show(ctx, |ui| {
+                picked = show_status(ui, status);
+            });
+        return picked;
+    }
+
+    if terminal.is_none() {
+        *terminal = start_shell(ctx, filename);
+    }
+    let mut restart = false;
+    let panel = egui::TopBottomPanel::bottom("terminal_panel")
+        .resizable(true)
+        .default_height(220.0)
+        .height_range(80.0..=ctx.screen_rect().height() * 0.8)
+        .show(ctx, |ui| {
+            match terminal.as_mut() {
+                Some(terminal) => {
+                    if terminal.has_exited() {
+                        ui.horizontal(|ui| {
+                            ui.weak(format!("{} exited", terminal.title));
+                            restart = ui.button("Restart shell").clicked();
+                        });
+                    }
+                    // Leaves room for the status bar
+                    let size = egui::vec2(
+                        ui.available_width(),
+                        (ui.available_height() - 24.0).max(0.0),
+                    );
+                    terminal_ui(ui, terminal, size);
+                }
+                None => {
+                    ui.horizontal(|ui| {
+                        ui.colored_label(ui.visuals().error_fg_color, "Failed to start the shell");
+                        restart = ui.button("Retry").clicked();
+                    });
+                }
+            }
+            picked = show_status(ui, status);
+        });
+    unsafe {
+        TERMINAL_HEIGHT = panel.response.rect.height();
+    }
+    if restart {
+        *terminal = start_shell(ctx, filename);
+    }
+    picked
+}
+
+/// Draws the terminal's screen to fill `size`, resizing it to match, and
+/// sends it what is typed while it has focus.
+fn terminal_ui(ui: &mut egui::Ui, terminal: &mut Terminal, size: egui::Vec2) {
+    let font_id = egui::TextStyle::Monospace.resolve(ui.style());
+    let (cell_width, row_height) =
+        ui.fonts(|f| (f.glyph_width(&font_id, 'M'), f.row_height(&font_id)));
+    let (rect, _) = ui.allocate_exact_size(size, egui::Sense::hover());
+    let response = ui.interact(rect, terminal_id(), egui::Sense::click());
+    let rows = (rect.height() / row_height).floor().max(1.0) as u16;
+    let cols = (rect.width() / cell_width).floor().max(2.0) as u16;
+    terminal.resize(rows, cols);
+
+    if response.clicked() {
+        response.request_focus();
+    }
+    let focused = response.has_focus();
+    if focused {
+        // Keep Tab, arrows and Escape for the program rather than egui
+        ui.memory_mut(|m| {
+            m.set_focus_lock_filter(
+                response.id,
+                egui::EventFilter {
+                    tab: true,
+                    horizontal_arrows: true,
+                    vertical_arrows: true,
+                    escape: true,
+                },
+            )
+        });
+        let (application_cursor, bracketed_paste) = {
+            let parser = terminal.parser();
+            let screen = parser.screen();
+            (screen.application_cursor(), screen.bracketed_paste())
+        };
+        let mut input = Vec::new();
+        for event in ui.input(|i| i.events.clone()) {
+            match event {
+                egui::Event::Text(text) => input.extend(text.into_bytes()),
+                egui::Event::Key {
+                    key,
+                    pressed: true,
+                    modifiers,
+                    ..
+                } => {
+                    if let Some(bytes) = terminal::key_bytes(key, modifiers, application_cursor) {
+                        input.extend(bytes);
+                    }
+                }
+                // egui turns Ctrl+C, Ctrl+X and Ctrl+V into clipboard events
+                egui::Event::Copy if !cfg!(target_os = "macos") => input.push(0x03),
+                egui::Event::Cut if !cfg!(target_os = "macos") => input.push(0x18),
+                egui::Event::Copy => ui.ctx().copy_text(terminal.parser().screen().contents()),
+                egui::Event::Paste(text) => {
+                    if bracketed_paste {
+                        input.extend(b"\x1b[200~");
+                        input.extend(text.into_bytes());
+                        input.extend(b"\x1b[201~");
+                    } else {
+                        input.extend(text.replace('\n', "\r").into_bytes());
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !input.is_empty() {
+            terminal.scroll(isize::MIN);
+            terminal.write(&input);
+        }
+    }
+
+    let scroll = ui.input(|i| i.smooth_scroll_delta.y);
+    if response.hovered() && scroll != 0.0 {
+        let lines = (scroll / row_height).round() as isize;
+        if lines != 0 && !terminal.parser().screen().alternate_screen() {
+            terminal.scroll(lines);
+        }
+    }
+
+    let visuals = ui.visuals();
+    let default_fg = visuals.text_color();
+    let default_bg = visuals.extreme_bg_color;
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, default_bg);
+    let parser = terminal.parser();
+    let screen = parser.screen();
+    for row in 0..rows {
+        let mut job = egui::text::LayoutJob::default();
+        let mut run = String::new();
+        let mut run_format = egui::TextFormat::default();
+        for col in 0..cols {
+            let Some(cell) = screen.cell(row, col) else {
+                continue;
+            };
+            if cell.is_wide_continuation() {
+                continue;
+            }
+            let mut fg = match cell.fgcolor() {
+                // Bold brightens the eight basic colours, as xterm does
+                vt100::Color::Idx(idx) if cell.bold() && idx < 8 => {
+                    terminal::color(vt100::Color::Idx(idx + 8), default_fg)
+                }
+                color => terminal::color(color, default_fg),
+            };
+            let mut bg = terminal::color(cell.bgcolor(), default_bg);
+            if cell.inverse() {
+                std::mem::swap(&mut fg, &mut bg);
+            }
+            if cell.dim() {
+                fg = fg.gamma_multiply(0.6);
+            }
+            let format = egui::TextFormat {
+                font_id: font_id.clone(),
+                color: fg,
+                background: if bg == default_bg {
+                    egui::Color32::TRANSPARENT
+                } else {
+                    bg
+                },
+                italics: cell.italic(),
+                underline: if cell.underline() {
+                    egui::Stroke::new(1.0, fg)
+                } else {
+                    egui::Stroke::NONE
+                },
+                ..Default::default()
+            };
+            if format != run_format && !run.is_empty() {
+                job.append(&std::mem::take(&mut run), 0.0, run_format.clone());
+            }
+            run_format = format;
+            if cell.has_contents() {
+                run.push_str(cell.contents());
+            } else {
+                run.push(' ');
+            }
+        }
+        if !run.is_empty() {
+            job.append(&run, 0.0, run_format);
+        }
+        let galley = ui.fonts(|f| f.layout_job(job));
+        let pos = rect.min + egui::vec2(0.0, row as f32 * row_height);
+        painter.galley(pos, galley, default_fg);
+    }
+
+    if !screen.hide_cursor() && screen.scrollback() == 0 {
+        let (row, col) = screen.cursor_position();
+        let cursor = egui::Rect::from_min_size(
+            rect.min + egui::vec2(col as f32 * cell_width, row as f32 * row_height),
+            egui::vec2(cell_width, row_height),
+        );
+        if focused {
+            painter.rect_filled(cursor, 0.0, default_fg.gamma_multiply(0.5));
+        } else {
+            painter.rect_stroke(cursor, 0.0, egui::Stroke::new(1.0, default_fg));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn search(query: &str, whole_word: bool, text: &Buffer) -> SearchState {
+        let mut state = SearchState {
+            query: query.to_string(),
+            whole_word,
+            ..SearchState::default()
+        };
+        state.find_matches(text, 1);
+        state
+    }
+
+    /// Makes an edit and brings the search up to date with it.
+    fn edit(state: &mut SearchState, text: &mut Buffer, chars: std::ops::Range<usize>, with: &str) {
+        text.replace(chars, with);
+        state.update(text, 1);
+    }
+
+    /// What searching the whole text from scratch would find.
+    fn fresh(state: &SearchState, text: &Buffer) -> Vec<(usize, usize)> {
+        let mut again = SearchState {
+            query: state.query.clone(),
+            whole_word: state.whole_word,
+            regex: state.regex,
+            in_selection: state.in_selection,
+            scope: state.scope.clone(),
+            ..SearchState::default()
+        };
+        again.compile();
+        again.find_in(&text.to_string(), 0)
+    }
+
+    #[test]
+    fn edits_inside_a_match_drop_it_and_shift_the_rest() {
+        let mut text = Buffer::from("abc abc\nabc".to_string());
+        let mut state = search("abc", false, &text);
+        edit(&mut state, &mut text, 5..6, "xx");
+        assert_eq!(text.to_string(), "abc axxc\nabc");
+        assert_eq!(state.matches, [(0, 3), (9, 12)]);
+
+        // Lines added above move every match down
+        edit(&mut state, &mut text, 0..0, "\n\n");
+        assert_eq!(state.matches, [(2, 5), (11, 14)]);
+        assert_eq!(state.matches, fresh(&state, &text));
+    }
+
+    #[test]
+    fn edits_can_join_text_into_a_new_match() {
+        let mut text = Buffer::from("x\nab c\nabc".to_string());
+        let mut state = search("abc", false, &text);
+        assert_eq!(state.matches, [(7, 10)]);
+        edit(&mut state, &mut text, 4..5, "");
+        assert_eq!(state.matches, [(2, 5), (6, 9)]);
+
+        // And split a match over two lines
+        edit(&mut state, &mut text, 3..3, "\n");
+        assert_eq!(text.to_string(), "x\na\nbc\nabc");
+        assert_eq!(state.matches, [(7, 10)]);
+        assert_eq!(state.matches, fresh(&state, &text));
+    }
+
+    #[test]
+    fn whole_words_next_to_an_edit() {
+        let mut text = Buffer::from("foo foo".to_string());
+        let mut state = search("foo", true, &text);
+        edit(&mut state, &mut text, 3..3, "d");
+        assert_eq!(state.matches, [(5, 8)]);
+        edit(&mut state, &mut text, 3..4, "");
+        assert_eq!(state.matches, [(0, 3), (4, 7)]);
+        edit(&mut state, &mut text, 4..4, "_");
+        assert_eq!(state.matches, [(0, 3)]);
+        assert_eq!(state.matches, fresh(&state, &text));
+    }
+
+    #[test]
+    fn the_selection_scope_follows_edits() {
+        let mut text = Buffer::from("foo foo foo".to_string());
+        let mut state = search("foo", false, &text);
+        state.set_scope(Some(4..11));
+        state.find_matches(&text, 1);
+        assert_eq!(state.matches, [(4, 7), (8, 11)]);
+
+        // Typing before the selection moves it along
+        edit(&mut state, &mut text, 0..0, "xx ");
+        assert_eq!(state.scope, Some(7..14));
+        assert_eq!(state.matches, [(7, 10), (11, 14)]);
+
+        // Typing inside it grows it
+        edit(&mut state, &mut text, 7..7, "foo ");
+        assert_eq!(state.scope, Some(7..18));
+        assert_eq!(state.matches, [(7, 10), (11, 14), (15, 18)]);
+
+        // Deleting across its start cuts it back to the edit
+        edit(&mut state, &mut text, 5..9, "");
+        assert_eq!(text.to_string(), "xx foo foo foo");
+        assert_eq!(state.scope, Some(5..14));
+        assert_eq!(state.matches, [(7, 10), (11, 14)]);
+        assert_eq!(state.matches, fresh(&state, &text));
+    }
+
+    #[test]
+    fn regex_searches_start_over_on_edits() {
+        let mut text = Buffer::from("a\nb a".to_string());
+        let mut state = SearchState {
+            query: r"a\nb".to_string(),
+            regex: true,
+            ..SearchState::default()
+        };
+        state.find_matches(&text, 1);
+        assert_eq!(state.matches, [(0, 3)]);
+        edit(&mut state, &mut text, 5..5, "\nb");
+        assert_eq!(state.matches, [(0, 3), (4, 7)]);
+    }
 }
@@ -0,0 +1,124 @@
+use crate::document::Tabs;
+use eframe::egui;
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Size and modification time of a file, to tell whether it is still the
+/// file that was last read or written.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DiskStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl DiskStamp {
+    pub fn of(path: &Path) -> Option<Self> {
+        let meta = std::fs::metadata(path).ok()?;
+        Some(Self {
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+}
+
+/// What happened on disk to a document that couldn't just be reloaded.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DiskChange {
+    /// Changed while the buffer had unsaved changes of its own.
+    Modified,
+    Deleted,
+}
+
+#[derive(Default)]
+struct Pending {
+    changed: HashSet<PathBuf>,
+    /// `(from, to)`, when the platform reports both ends of a rename.
+    renamed: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Watches the folders of all open documents. Folders rather than the files
+/// themselves, since tools that save by writing a new file and renaming it
+/// over the old one (like Kokona itself) would end a watch on the file.
+pub struct FileWatcher {
+    watcher: RecommendedWatcher,
+    folders: HashSet<PathBuf>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl FileWatcher {
+    pub fn new(ctx: &egui::Context) -> notify::Result<Self> {
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let events = pending.clone();
+        let ctx = ctx.clone();
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let Ok(event) = event else {
+                return;
+            };
+            if matches!(event.kind, EventKind::Access(_)) {
+                return;
+            }
+            let mut pending = events.lock().unwrap();
+            if let (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) =
+                (event.kind, event.paths.as_slice())
+            {
+                pending.renamed.push((from.clone(), to.clone()));
+            }
+            pending.changed.extend(event.paths);
+            ctx.request_repaint();
+        })?;
+        Ok(Self {
+            watcher,
+            folders: HashSet::new(),
+            pending,
+        })
+    }
+
+    /// Follows the open documents and applies what happened on disk since
+    /// the last frame: clean documents reload, the rest get a `DiskChange`.
+    pub fn update(&mut self, ctx: &egui::Context, tabs: &mut Tabs) {
+        let folders: HashSet<PathBuf> = tabs
+            .documents
+            .iter()
+            .filter_map(|doc| Some(doc.path.as_ref()?.parent()?.to_path_buf()))
+            .collect();
+        for folder in self.folders.difference(&folders) {
+            let _ = self.watcher.unwatch(folder);
+        }
+        for folder in folders.difference(&self.folders) {
+            if let Err(e) = self.watcher.watch(folder, RecursiveMode::NonRecursive) {
+                crate::views::report_error(format!(
+                    "Failed to watch {} for changes: {}",
+                    folder.display(),
+                    e
+                ));
+            }
+        }
+        self.folders = folders;
+
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for (from, to) in &pending.renamed {
+            for doc in &mut tabs.documents {
+                if doc.path.as_ref() == Some(from) && to.exists() {
+                    doc.path = Some(to.clone());
+                    doc.syntax = None;
+                }
+            }
+        }
+        for idx in 0..tabs.documents.len() {
+            let doc = &mut tabs.documents[idx];
+            if doc
+                .path
+                .as_ref()
+                .is_some_and(|path| pending.changed.contains(path))
+                && doc.check_disk()
+                && idx == tabs.active
+            {
+                ctx.send_viewport_cmd(egui::ViewportCommand::Title("Kokona".into()));
+            }
+        }
+    }
+}